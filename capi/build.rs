@@ -0,0 +1,33 @@
+// Generates the C header into OUT_DIR. The checked in include/nsc_a1.h is only rewritten when
+// asked for, with NSC_A1_UPDATE_HEADER=1, and a warning says when it has fallen behind the
+// exports.
+
+fn main() {
+    let crate_dir = std::env::var("CARGO_MANIFEST_DIR").unwrap();
+    let out_dir = std::env::var("OUT_DIR").unwrap();
+    println!("cargo:rerun-if-changed=src");
+    println!("cargo:rerun-if-changed=cbindgen.toml");
+    println!("cargo:rerun-if-changed=include/nsc_a1.h");
+    println!("cargo:rerun-if-env-changed=NSC_A1_UPDATE_HEADER");
+
+    let config = cbindgen::Config::from_file(format!("{}/cbindgen.toml", crate_dir)).unwrap();
+    let generated = format!("{}/nsc_a1.h", out_dir);
+    cbindgen::Builder::new()
+        .with_crate(&crate_dir)
+        .with_config(config)
+        .generate()
+        .expect("Unable to generate the C header")
+        .write_to_file(&generated);
+
+    let checked_in = format!("{}/include/nsc_a1.h", crate_dir);
+    let new_header = std::fs::read(&generated).unwrap();
+    if std::fs::read(&checked_in).ok().as_ref() == Some(&new_header) {
+        return;
+    }
+    match std::env::var("NSC_A1_UPDATE_HEADER").as_deref() {
+        Ok("1") => std::fs::write(&checked_in, new_header).unwrap(),
+        _ => println!(
+            "cargo:warning=include/nsc_a1.h is out of date, rebuild with NSC_A1_UPDATE_HEADER=1 to regenerate it"
+        ),
+    }
+}
@@ -0,0 +1,178 @@
+/* C API for the NSC-A1 driver. See the README for building and linking. */
+
+#ifndef NSC_A1_H
+#define NSC_A1_H
+
+/* Generated by cbindgen from capi/src, don't edit by hand. */
+
+#include <stddef.h>
+#include <stdint.h>
+
+// What every function returns. The errors match libusb's error codes where there is one.
+typedef enum NscStatus {
+  NSC_STATUS_OK = 0,
+  NSC_STATUS_IO = -1,
+  // Also returned for NULL pointers, bad strings and out of range values.
+  NSC_STATUS_INVALID_PARAM = -2,
+  NSC_STATUS_ACCESS = -3,
+  NSC_STATUS_NO_DEVICE = -4,
+  NSC_STATUS_NOT_FOUND = -5,
+  NSC_STATUS_BUSY = -6,
+  NSC_STATUS_TIMEOUT = -7,
+  // The response didn't fit in the buffer, what fit was still written.
+  NSC_STATUS_OVERFLOW = -8,
+  NSC_STATUS_PIPE = -9,
+  // Aborted, see the Stopping section of the README.
+  NSC_STATUS_INTERRUPTED = -10,
+  NSC_STATUS_NO_MEM = -11,
+  NSC_STATUS_NOT_SUPPORTED = -12,
+  NSC_STATUS_BAD_DESCRIPTOR = -13,
+  // Includes the device giving a response that couldn't be understood.
+  NSC_STATUS_OTHER = -99,
+} NscStatus;
+
+// An open controller. Only ever handled through a pointer.
+typedef struct NscA1 NscA1;
+
+#ifdef __cplusplus
+extern "C" {
+#endif // __cplusplus
+
+// A short description of a status, never NULL. The string is static, don't free it.
+const char *nsc_status_string(int32_t status);
+
+// Opens the first NSC-A1 found. `*out` is NULL on failure.
+enum NscStatus nsc_open(struct NscA1 **out);
+
+// Opens the NSC-A1 with USB serial number `serial`. `*out` is NULL on failure.
+enum NscStatus nsc_open_by_serial(const char *serial, struct NscA1 **out);
+
+// Opens the `number`th (from 0) NSC-A1 plugged in. `*out` is NULL on failure.
+enum NscStatus nsc_open_by_number(uint32_t number, struct NscA1 **out);
+
+// Closes communication and frees the handle, even if closing fails. NULL is ignored.
+enum NscStatus nsc_close(struct NscA1 *nsc);
+
+// Sends a raw command, e.g. "HSPD=1000", and copies the reply into `response`. Moves and
+// jogs are held to the soft limits like in interactive mode, NSC_STATUS_INVALID_PARAM if refused.
+enum NscStatus nsc_send_command(const struct NscA1 *nsc,
+                                const char *command,
+                                char *response,
+                                size_t response_len);
+
+// Moves to (ABS) or by (INC) `position` pulses. Doesn't wait, see nsc_wait_idle.
+enum NscStatus nsc_move(const struct NscA1 *nsc, int32_t position);
+
+// Jogs in the positive direction if `positive` is non-zero, negative otherwise, until
+// nsc_stop (or to the soft limit if one is set).
+enum NscStatus nsc_jog(const struct NscA1 *nsc, int32_t positive);
+
+// Decelerates to a stop.
+enum NscStatus nsc_stop(const struct NscA1 *nsc);
+
+// Blocks until the motor is idle.
+enum NscStatus nsc_wait_idle(const struct NscA1 *nsc);
+
+// Reads the pulse position, PX.
+enum NscStatus nsc_get_pulse_position(const struct NscA1 *nsc, int32_t *out);
+
+// Sets the pulse position, PX.
+enum NscStatus nsc_set_pulse_position(const struct NscA1 *nsc, int32_t position);
+
+// Reads the encoder position, EX.
+enum NscStatus nsc_get_encoder_position(const struct NscA1 *nsc, int32_t *out);
+
+// Sets the encoder position, EX.
+enum NscStatus nsc_set_encoder_position(const struct NscA1 *nsc, int32_t position);
+
+// Reads the raw MST status word, 0 when idle.
+enum NscStatus nsc_get_motor_status(const struct NscA1 *nsc, int32_t *out);
+
+// `*out` is 1 if the motor is energized (EO), 0 if not.
+enum NscStatus nsc_get_motor_enabled(const struct NscA1 *nsc, int32_t *out);
+
+// Energizes the motor if `enabled` is non-zero (waits 3 s for it to settle), turns it off
+// otherwise.
+enum NscStatus nsc_set_motor_enabled(const struct NscA1 *nsc, int32_t enabled);
+
+// Reads the high speed, HSPD, in pulses/s.
+enum NscStatus nsc_get_high_speed(const struct NscA1 *nsc, uint32_t *out);
+
+// Sets the high speed, HSPD, in pulses/s.
+enum NscStatus nsc_set_high_speed(const struct NscA1 *nsc, uint32_t speed);
+
+// Sets the low speed, LSPD, in pulses/s.
+enum NscStatus nsc_set_low_speed(const struct NscA1 *nsc, uint32_t speed);
+
+// Sets the acceleration time, ACC, in ms.
+enum NscStatus nsc_set_acceleration_time(const struct NscA1 *nsc, uint32_t time);
+
+// Sets the deceleration time, DEC, in ms.
+enum NscStatus nsc_set_deceleration_time(const struct NscA1 *nsc, uint32_t time);
+
+// "abs" or "inc".
+enum NscStatus nsc_set_movement_type(const struct NscA1 *nsc, const char *abs_inc);
+
+// Sets the microsteps per full step, DRVMS, 2-500. Takes effect after
+// nsc_write_driver_settings.
+enum NscStatus nsc_set_microstepping(const struct NscA1 *nsc, uint32_t microsteps);
+
+// Sets the idle current, DRVIC, 100-2800 mA. Takes effect after nsc_write_driver_settings.
+enum NscStatus nsc_set_idle_current(const struct NscA1 *nsc, uint32_t current);
+
+// Sets the run current, DRVRC, 100-3000 mA. Takes effect after nsc_write_driver_settings.
+enum NscStatus nsc_set_run_current(const struct NscA1 *nsc, uint32_t current);
+
+// Sets the idle time, DRVIT, 1-100 cs. Takes effect after nsc_write_driver_settings.
+enum NscStatus nsc_set_idle_time(const struct NscA1 *nsc, uint32_t time);
+
+// Writes the driver parameters to the driver (RW) and checks it worked. Takes about 3 s and
+// turns the motor off.
+enum NscStatus nsc_write_driver_settings(const struct NscA1 *nsc);
+
+// Reads the driver parameters back from the driver (RR) so the nsc_get_ driver functions
+// report what it actually has. Takes about 3 s.
+enum NscStatus nsc_read_driver_settings(const struct NscA1 *nsc);
+
+// Reads the microsteps per full step, DRVMS.
+enum NscStatus nsc_get_microstepping(const struct NscA1 *nsc, uint32_t *out);
+
+// Reads the idle current, DRVIC, in mA.
+enum NscStatus nsc_get_idle_current(const struct NscA1 *nsc, uint32_t *out);
+
+// Reads the run current, DRVRC, in mA.
+enum NscStatus nsc_get_run_current(const struct NscA1 *nsc, uint32_t *out);
+
+// Reads the idle time, DRVIT, in cs.
+enum NscStatus nsc_get_idle_time(const struct NscA1 *nsc, uint32_t *out);
+
+// Counts the NSC-A1s plugged in.
+int32_t fnPerformaxComGetNumDevices(uint32_t *num_devices);
+
+// Opens the `device_number`th (from 0) NSC-A1. The handle is also usable with the nsc_
+// functions.
+int32_t fnPerformaxComOpen(uint32_t device_number, void **handle);
+
+// Closes and frees the handle.
+int32_t fnPerformaxComClose(void *handle);
+
+// Sets the USB timeouts in ms. The driver has one timeout for both directions, so the
+// longer of the two is used. 0 (wait forever) is refused.
+int32_t fnPerformaxComSetTimeouts(uint32_t read_timeout, uint32_t write_timeout);
+
+// Sends the command in `write_buffer` (up to the first NUL or `bytes_to_write` bytes) and
+// copies the NUL terminated reply into `read_buffer`, cut short to `bytes_to_read` bytes.
+int32_t fnPerformaxComSendRecv(void *handle,
+                               const void *write_buffer,
+                               uint32_t bytes_to_write,
+                               uint32_t bytes_to_read,
+                               void *read_buffer);
+
+// Throws away anything left unread from the controller.
+int32_t fnPerformaxComFlush(void *handle);
+
+#ifdef __cplusplus
+}  // extern "C"
+#endif  // __cplusplus
+
+#endif  /* NSC_A1_H */
@@ -0,0 +1,397 @@
+//! C API for the NSC-A1 driver, for LabVIEW, MATLAB or anything else that can load a
+//! DLL/.so. build.rs generates the header into include/nsc_a1.h.
+//!
+//! Every function returns an NscStatus and hands results back through out pointers. Handles
+//! come from one of the nsc_open functions and must be given back to nsc_close exactly once.
+//! Strings are NUL terminated. A handle can be used from several threads, commands are
+//! serialized the same way they are in the library.
+
+// The pointer rules are the same for every function and are spelled out above and in the
+// header, a Safety section on each one would just repeat them
+#![allow(clippy::missing_safety_doc)]
+
+mod performax;
+
+use rusb::{DeviceHandle, GlobalContext};
+use rust_mechanical_loader::stage_control::{
+    commands::{
+        close, get_encoder_position, get_high_speed, get_idle_current, get_idle_time,
+        get_microstepping, get_motor_enabled, get_motor_status, get_pulse_position,
+        get_run_current, jog, move_stage, open, open_by_number, open_by_serial,
+        read_driver_settings, send_command_within_limits, set_acceleration_time,
+        set_deceleration_time, set_encoder_position, set_high_speed, set_idle_current,
+        set_idle_time, set_low_speed, set_microstepping, set_movement_type, set_pulse_position,
+        set_run_current, stop, turn_motor_off, turn_motor_on, wait_for_motor_idle,
+        write_driver_settings,
+    },
+    driver::{NSC_A1_PRODUCT_ID, NSC_A1_VENDOR_ID},
+};
+
+use std::{
+    ffi::{c_char, CStr},
+    panic::{catch_unwind, AssertUnwindSafe},
+    ptr,
+};
+
+/// An open controller. Only ever handled through a pointer.
+pub struct NscA1 {
+    handle: DeviceHandle<GlobalContext>,
+}
+
+/// What every function returns. The errors match libusb's error codes where there is one.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NscStatus {
+    Ok = 0,
+    Io = -1,
+    /// Also returned for NULL pointers, bad strings and out of range values.
+    InvalidParam = -2,
+    Access = -3,
+    NoDevice = -4,
+    NotFound = -5,
+    Busy = -6,
+    Timeout = -7,
+    /// The response didn't fit in the buffer, what fit was still written.
+    Overflow = -8,
+    Pipe = -9,
+    /// Aborted, see the Stopping section of the README.
+    Interrupted = -10,
+    NoMem = -11,
+    NotSupported = -12,
+    BadDescriptor = -13,
+    /// Includes the device giving a response that couldn't be understood.
+    Other = -99,
+}
+
+impl From<rusb::Error> for NscStatus {
+    fn from(e: rusb::Error) -> Self {
+        match e {
+            rusb::Error::Io => NscStatus::Io,
+            rusb::Error::InvalidParam => NscStatus::InvalidParam,
+            rusb::Error::Access => NscStatus::Access,
+            rusb::Error::NoDevice => NscStatus::NoDevice,
+            rusb::Error::NotFound => NscStatus::NotFound,
+            rusb::Error::Busy => NscStatus::Busy,
+            rusb::Error::Timeout => NscStatus::Timeout,
+            rusb::Error::Overflow => NscStatus::Overflow,
+            rusb::Error::Pipe => NscStatus::Pipe,
+            rusb::Error::Interrupted => NscStatus::Interrupted,
+            rusb::Error::NoMem => NscStatus::NoMem,
+            rusb::Error::NotSupported => NscStatus::NotSupported,
+            rusb::Error::BadDescriptor => NscStatus::BadDescriptor,
+            rusb::Error::Other => NscStatus::Other,
+        }
+    }
+}
+
+// Unwinding into C is undefined, so a panic anywhere below becomes Other. The panic message
+// still goes to stderr.
+fn guard(f: impl FnOnce() -> rusb::Result<()>) -> NscStatus {
+    match catch_unwind(AssertUnwindSafe(f)) {
+        Ok(Ok(())) => NscStatus::Ok,
+        Ok(Err(e)) => e.into(),
+        Err(_) => NscStatus::Other,
+    }
+}
+
+unsafe fn controller<'a>(nsc: *const NscA1) -> rusb::Result<&'a DeviceHandle<GlobalContext>> {
+    match nsc.as_ref() {
+        None => Err(rusb::Error::InvalidParam),
+        Some(nsc) => Ok(&nsc.handle),
+    }
+}
+
+unsafe fn c_str<'a>(string: *const c_char) -> rusb::Result<&'a str> {
+    if string.is_null() {
+        return Err(rusb::Error::InvalidParam);
+    }
+    CStr::from_ptr(string)
+        .to_str()
+        .map_err(|_| rusb::Error::InvalidParam)
+}
+
+unsafe fn write_out<T>(out: *mut T, value: T) -> rusb::Result<()> {
+    if out.is_null() {
+        return Err(rusb::Error::InvalidParam);
+    }
+    out.write(value);
+    Ok(())
+}
+
+// Copies `string` into `buffer` with a NUL, cutting it short (and returning Overflow) if it
+// doesn't fit
+unsafe fn write_string(buffer: *mut c_char, buffer_len: usize, string: &str) -> rusb::Result<()> {
+    if buffer.is_null() || buffer_len == 0 {
+        return Err(rusb::Error::InvalidParam);
+    }
+    let len = string.len().min(buffer_len - 1);
+    ptr::copy_nonoverlapping(string.as_ptr(), buffer as *mut u8, len);
+    buffer.add(len).write(0);
+    match len < string.len() {
+        true => Err(rusb::Error::Overflow),
+        false => Ok(()),
+    }
+}
+
+unsafe fn open_with(
+    out: *mut *mut NscA1,
+    open: impl FnOnce() -> rusb::Result<DeviceHandle<GlobalContext>>,
+) -> NscStatus {
+    guard(|| {
+        if out.is_null() {
+            return Err(rusb::Error::InvalidParam);
+        }
+        out.write(ptr::null_mut());
+        let handle = open()?;
+        out.write(Box::into_raw(Box::new(NscA1 { handle })));
+        Ok(())
+    })
+}
+
+/// A short description of a status, never NULL. The string is static, don't free it.
+// Takes an int rather than NscStatus so a bad value from C is just an unknown status
+#[no_mangle]
+pub extern "C" fn nsc_status_string(status: i32) -> *const c_char {
+    let description = match status {
+        0 => c"ok",
+        -1 => c"input/output error",
+        -2 => c"invalid parameter",
+        -3 => c"access denied (insufficient permissions)",
+        -4 => c"no such device (it may have been disconnected)",
+        -5 => c"entity not found",
+        -6 => c"resource busy",
+        -7 => c"operation timed out",
+        -8 => c"overflow",
+        -9 => c"pipe error",
+        -10 => c"aborted",
+        -11 => c"insufficient memory",
+        -12 => c"operation not supported or unimplemented on this platform",
+        -13 => c"malformed descriptor",
+        -99 => c"other error",
+        _ => c"unknown status",
+    };
+    description.as_ptr()
+}
+
+/// Opens the first NSC-A1 found. `*out` is NULL on failure.
+#[no_mangle]
+pub unsafe extern "C" fn nsc_open(out: *mut *mut NscA1) -> NscStatus {
+    open_with(out, || open(NSC_A1_VENDOR_ID, NSC_A1_PRODUCT_ID))
+}
+
+/// Opens the NSC-A1 with USB serial number `serial`. `*out` is NULL on failure.
+#[no_mangle]
+pub unsafe extern "C" fn nsc_open_by_serial(
+    serial: *const c_char,
+    out: *mut *mut NscA1,
+) -> NscStatus {
+    open_with(out, || {
+        open_by_serial(NSC_A1_VENDOR_ID, NSC_A1_PRODUCT_ID, c_str(serial)?)
+    })
+}
+
+/// Opens the `number`th (from 0) NSC-A1 plugged in. `*out` is NULL on failure.
+#[no_mangle]
+pub unsafe extern "C" fn nsc_open_by_number(number: u32, out: *mut *mut NscA1) -> NscStatus {
+    open_with(out, || {
+        open_by_number(NSC_A1_VENDOR_ID, NSC_A1_PRODUCT_ID, number as usize)
+    })
+}
+
+/// Closes communication and frees the handle, even if closing fails. NULL is ignored.
+#[no_mangle]
+pub unsafe extern "C" fn nsc_close(nsc: *mut NscA1) -> NscStatus {
+    if nsc.is_null() {
+        return NscStatus::Ok;
+    }
+    let nsc = Box::from_raw(nsc);
+    guard(|| close(&nsc.handle))
+}
+
+/// Sends a raw command, e.g. "HSPD=1000", and copies the reply into `response`. Moves and
+/// jogs are held to the soft limits like in interactive mode, NSC_STATUS_INVALID_PARAM if refused.
+#[no_mangle]
+pub unsafe extern "C" fn nsc_send_command(
+    nsc: *const NscA1,
+    command: *const c_char,
+    response: *mut c_char,
+    response_len: usize,
+) -> NscStatus {
+    guard(|| {
+        let reply = send_command_within_limits(controller(nsc)?, c_str(command)?)?;
+        write_string(response, response_len, &reply)
+    })
+}
+
+/// Moves to (ABS) or by (INC) `position` pulses. Doesn't wait, see nsc_wait_idle.
+#[no_mangle]
+pub unsafe extern "C" fn nsc_move(nsc: *const NscA1, position: i32) -> NscStatus {
+    guard(|| move_stage(controller(nsc)?, position))
+}
+
+/// Jogs in the positive direction if `positive` is non-zero, negative otherwise, until
+/// nsc_stop (or to the soft limit if one is set).
+#[no_mangle]
+pub unsafe extern "C" fn nsc_jog(nsc: *const NscA1, positive: i32) -> NscStatus {
+    guard(|| jog(controller(nsc)?, positive != 0))
+}
+
+/// Decelerates to a stop.
+#[no_mangle]
+pub unsafe extern "C" fn nsc_stop(nsc: *const NscA1) -> NscStatus {
+    guard(|| stop(controller(nsc)?))
+}
+
+/// Blocks until the motor is idle.
+#[no_mangle]
+pub unsafe extern "C" fn nsc_wait_idle(nsc: *const NscA1) -> NscStatus {
+    guard(|| wait_for_motor_idle(controller(nsc)?, &mut None))
+}
+
+/// Reads the pulse position, PX.
+#[no_mangle]
+pub unsafe extern "C" fn nsc_get_pulse_position(nsc: *const NscA1, out: *mut i32) -> NscStatus {
+    guard(|| write_out(out, get_pulse_position(controller(nsc)?)?))
+}
+
+/// Sets the pulse position, PX.
+#[no_mangle]
+pub unsafe extern "C" fn nsc_set_pulse_position(nsc: *const NscA1, position: i32) -> NscStatus {
+    guard(|| set_pulse_position(controller(nsc)?, position))
+}
+
+/// Reads the encoder position, EX.
+#[no_mangle]
+pub unsafe extern "C" fn nsc_get_encoder_position(nsc: *const NscA1, out: *mut i32) -> NscStatus {
+    guard(|| write_out(out, get_encoder_position(controller(nsc)?)?))
+}
+
+/// Sets the encoder position, EX.
+#[no_mangle]
+pub unsafe extern "C" fn nsc_set_encoder_position(nsc: *const NscA1, position: i32) -> NscStatus {
+    guard(|| set_encoder_position(controller(nsc)?, position))
+}
+
+/// Reads the raw MST status word, 0 when idle.
+#[no_mangle]
+pub unsafe extern "C" fn nsc_get_motor_status(nsc: *const NscA1, out: *mut i32) -> NscStatus {
+    guard(|| write_out(out, get_motor_status(controller(nsc)?)?.bits()))
+}
+
+/// `*out` is 1 if the motor is energized (EO), 0 if not.
+#[no_mangle]
+pub unsafe extern "C" fn nsc_get_motor_enabled(nsc: *const NscA1, out: *mut i32) -> NscStatus {
+    guard(|| write_out(out, get_motor_enabled(controller(nsc)?)? as i32))
+}
+
+/// Energizes the motor if `enabled` is non-zero (waits 3 s for it to settle), turns it off
+/// otherwise.
+#[no_mangle]
+pub unsafe extern "C" fn nsc_set_motor_enabled(nsc: *const NscA1, enabled: i32) -> NscStatus {
+    guard(|| match enabled != 0 {
+        true => turn_motor_on(controller(nsc)?),
+        false => turn_motor_off(controller(nsc)?),
+    })
+}
+
+/// Reads the high speed, HSPD, in pulses/s.
+#[no_mangle]
+pub unsafe extern "C" fn nsc_get_high_speed(nsc: *const NscA1, out: *mut u32) -> NscStatus {
+    guard(|| write_out(out, get_high_speed(controller(nsc)?)?))
+}
+
+/// Sets the high speed, HSPD, in pulses/s.
+#[no_mangle]
+pub unsafe extern "C" fn nsc_set_high_speed(nsc: *const NscA1, speed: u32) -> NscStatus {
+    guard(|| set_high_speed(controller(nsc)?, speed))
+}
+
+/// Sets the low speed, LSPD, in pulses/s.
+#[no_mangle]
+pub unsafe extern "C" fn nsc_set_low_speed(nsc: *const NscA1, speed: u32) -> NscStatus {
+    guard(|| set_low_speed(controller(nsc)?, speed))
+}
+
+/// Sets the acceleration time, ACC, in ms.
+#[no_mangle]
+pub unsafe extern "C" fn nsc_set_acceleration_time(nsc: *const NscA1, time: u32) -> NscStatus {
+    guard(|| set_acceleration_time(controller(nsc)?, time))
+}
+
+/// Sets the deceleration time, DEC, in ms.
+#[no_mangle]
+pub unsafe extern "C" fn nsc_set_deceleration_time(nsc: *const NscA1, time: u32) -> NscStatus {
+    guard(|| set_deceleration_time(controller(nsc)?, time))
+}
+
+/// "abs" or "inc".
+#[no_mangle]
+pub unsafe extern "C" fn nsc_set_movement_type(
+    nsc: *const NscA1,
+    abs_inc: *const c_char,
+) -> NscStatus {
+    guard(|| set_movement_type(controller(nsc)?, c_str(abs_inc)?))
+}
+
+/// Sets the microsteps per full step, DRVMS, 2-500. Takes effect after
+/// nsc_write_driver_settings.
+#[no_mangle]
+pub unsafe extern "C" fn nsc_set_microstepping(nsc: *const NscA1, microsteps: u32) -> NscStatus {
+    guard(|| set_microstepping(controller(nsc)?, microsteps))
+}
+
+/// Sets the idle current, DRVIC, 100-2800 mA. Takes effect after nsc_write_driver_settings.
+#[no_mangle]
+pub unsafe extern "C" fn nsc_set_idle_current(nsc: *const NscA1, current: u32) -> NscStatus {
+    guard(|| set_idle_current(controller(nsc)?, current))
+}
+
+/// Sets the run current, DRVRC, 100-3000 mA. Takes effect after nsc_write_driver_settings.
+#[no_mangle]
+pub unsafe extern "C" fn nsc_set_run_current(nsc: *const NscA1, current: u32) -> NscStatus {
+    guard(|| set_run_current(controller(nsc)?, current))
+}
+
+/// Sets the idle time, DRVIT, 1-100 cs. Takes effect after nsc_write_driver_settings.
+#[no_mangle]
+pub unsafe extern "C" fn nsc_set_idle_time(nsc: *const NscA1, time: u32) -> NscStatus {
+    guard(|| set_idle_time(controller(nsc)?, time))
+}
+
+/// Writes the driver parameters to the driver (RW) and checks it worked. Takes about 3 s and
+/// turns the motor off.
+#[no_mangle]
+pub unsafe extern "C" fn nsc_write_driver_settings(nsc: *const NscA1) -> NscStatus {
+    guard(|| write_driver_settings(controller(nsc)?))
+}
+
+/// Reads the driver parameters back from the driver (RR) so the nsc_get_ driver functions
+/// report what it actually has. Takes about 3 s.
+#[no_mangle]
+pub unsafe extern "C" fn nsc_read_driver_settings(nsc: *const NscA1) -> NscStatus {
+    guard(|| read_driver_settings(controller(nsc)?))
+}
+
+/// Reads the microsteps per full step, DRVMS.
+#[no_mangle]
+pub unsafe extern "C" fn nsc_get_microstepping(nsc: *const NscA1, out: *mut u32) -> NscStatus {
+    guard(|| write_out(out, get_microstepping(controller(nsc)?)?))
+}
+
+/// Reads the idle current, DRVIC, in mA.
+#[no_mangle]
+pub unsafe extern "C" fn nsc_get_idle_current(nsc: *const NscA1, out: *mut u32) -> NscStatus {
+    guard(|| write_out(out, get_idle_current(controller(nsc)?)?))
+}
+
+/// Reads the run current, DRVRC, in mA.
+#[no_mangle]
+pub unsafe extern "C" fn nsc_get_run_current(nsc: *const NscA1, out: *mut u32) -> NscStatus {
+    guard(|| write_out(out, get_run_current(controller(nsc)?)?))
+}
+
+/// Reads the idle time, DRVIT, in cs.
+#[no_mangle]
+pub unsafe extern "C" fn nsc_get_idle_time(nsc: *const NscA1, out: *mut u32) -> NscStatus {
+    guard(|| write_out(out, get_idle_time(controller(nsc)?)?))
+}
@@ -0,0 +1,112 @@
+//! The functions from Arcus's PerformaxCom C driver, with the same names and arguments, so
+//! programs written against the vendor library can load this one instead. BOOL is an int32
+//! (non-zero on success), DWORD a uint32 and HANDLE a void pointer from fnPerformaxComOpen.
+//! fnPerformaxComGetProductString isn't provided.
+
+#![allow(non_snake_case)]
+
+use super::{controller, guard, write_out, write_string, NscA1, NscStatus};
+
+use rust_mechanical_loader::stage_control::{
+    commands::{close, open_by_number, send_command_within_limits},
+    driver::{count_devices, discard_stale, NSC_A1_PRODUCT_ID, NSC_A1_VENDOR_ID},
+    profile::{get_device_profile, set_device_profile},
+};
+
+use std::{ffi::c_void, ptr, time::Duration};
+
+const TRUE: i32 = 1;
+const FALSE: i32 = 0;
+
+fn to_bool(status: NscStatus) -> i32 {
+    match status {
+        NscStatus::Ok => TRUE,
+        _ => FALSE,
+    }
+}
+
+/// Counts the NSC-A1s plugged in.
+#[no_mangle]
+pub unsafe extern "C" fn fnPerformaxComGetNumDevices(num_devices: *mut u32) -> i32 {
+    to_bool(guard(|| {
+        write_out(
+            num_devices,
+            count_devices(NSC_A1_VENDOR_ID, NSC_A1_PRODUCT_ID)? as u32,
+        )
+    }))
+}
+
+/// Opens the `device_number`th (from 0) NSC-A1. The handle is also usable with the nsc_
+/// functions.
+#[no_mangle]
+pub unsafe extern "C" fn fnPerformaxComOpen(device_number: u32, handle: *mut *mut c_void) -> i32 {
+    to_bool(guard(|| {
+        if handle.is_null() {
+            return Err(rusb::Error::InvalidParam);
+        }
+        handle.write(ptr::null_mut());
+        let nsc = open_by_number(NSC_A1_VENDOR_ID, NSC_A1_PRODUCT_ID, device_number as usize)?;
+        handle.write(Box::into_raw(Box::new(NscA1 { handle: nsc })) as *mut c_void);
+        Ok(())
+    }))
+}
+
+/// Closes and frees the handle.
+#[no_mangle]
+pub unsafe extern "C" fn fnPerformaxComClose(handle: *mut c_void) -> i32 {
+    if handle.is_null() {
+        return FALSE;
+    }
+    let nsc = Box::from_raw(handle as *mut NscA1);
+    to_bool(guard(|| close(&nsc.handle)))
+}
+
+/// Sets the USB timeouts in ms. The driver has one timeout for both directions, so the
+/// longer of the two is used. 0 (wait forever) is refused.
+#[no_mangle]
+pub extern "C" fn fnPerformaxComSetTimeouts(read_timeout: u32, write_timeout: u32) -> i32 {
+    match read_timeout.max(write_timeout) {
+        0 => FALSE,
+        timeout => {
+            let mut profile = get_device_profile();
+            profile.timeout = Duration::from_millis(timeout as u64);
+            set_device_profile(profile);
+            TRUE
+        }
+    }
+}
+
+/// Sends the command in `write_buffer` (up to the first NUL or `bytes_to_write` bytes) and
+/// copies the NUL terminated reply into `read_buffer`, cut short to `bytes_to_read` bytes.
+#[no_mangle]
+pub unsafe extern "C" fn fnPerformaxComSendRecv(
+    handle: *mut c_void,
+    write_buffer: *const c_void,
+    bytes_to_write: u32,
+    bytes_to_read: u32,
+    read_buffer: *mut c_void,
+) -> i32 {
+    to_bool(guard(|| {
+        if write_buffer.is_null() {
+            return Err(rusb::Error::InvalidParam);
+        }
+        let written =
+            std::slice::from_raw_parts(write_buffer as *const u8, bytes_to_write as usize);
+        let command = match written.iter().position(|&b| b == 0) {
+            Some(end) => &written[..end],
+            None => written,
+        };
+        // Held to the soft limits like nsc_send_command
+        let command = std::str::from_utf8(command).map_err(|_| rusb::Error::InvalidParam)?;
+        let reply = send_command_within_limits(controller(handle as *const NscA1)?, command)?;
+        write_string(read_buffer as *mut _, bytes_to_read as usize, &reply)
+    }))
+}
+
+/// Throws away anything left unread from the controller.
+#[no_mangle]
+pub unsafe extern "C" fn fnPerformaxComFlush(handle: *mut c_void) -> i32 {
+    to_bool(guard(|| {
+        discard_stale(controller(handle as *const NscA1)?).map(|_| ())
+    }))
+}
@@ -0,0 +1,301 @@
+//! Python bindings for the NSC-A1 driver. Built into a wheel with maturin, see pyproject.toml.
+
+use pyo3::{
+    create_exception,
+    exceptions::{PyIOError, PyKeyboardInterrupt},
+    prelude::*,
+    types::PyDict,
+};
+use rusb::{DeviceHandle, GlobalContext};
+use rust_mechanical_loader::stage_control::{
+    abort::request_abort,
+    calibrate::{calibrate_with_progress, CalibrateProgress},
+    commands::{
+        close, get_encoder_position, get_high_speed, get_motor_enabled, get_motor_status,
+        get_pulse_position, jog, move_stage, open, open_by_serial, send_command_within_limits,
+        set_acceleration_time, set_deceleration_time, set_encoder_position, set_high_speed,
+        set_idle_current, set_idle_time, set_low_speed, set_microstepping, set_movement_type,
+        set_pulse_position, set_run_current, stop, turn_motor_off, turn_motor_on,
+        wait_for_motor_idle, write_driver_settings,
+    },
+    driver::{NSC_A1_PRODUCT_ID, NSC_A1_VENDOR_ID},
+    run::{run_with_progress, RunProgress},
+};
+
+create_exception!(mechanical_loader, ControllerError, PyIOError);
+
+fn to_py_err(e: rusb::Error) -> PyErr {
+    match e {
+        rusb::Error::Interrupted => PyKeyboardInterrupt::new_err("aborted"),
+        e => ControllerError::new_err(e.to_string()),
+    }
+}
+
+/// One NSC-A1 controller. Closed when used as a context manager or with close().
+#[pyclass]
+struct Controller {
+    handle: Option<DeviceHandle<GlobalContext>>,
+}
+
+impl Controller {
+    fn handle(&self) -> PyResult<&DeviceHandle<GlobalContext>> {
+        self.handle
+            .as_ref()
+            .ok_or_else(|| ControllerError::new_err("controller is closed"))
+    }
+}
+
+// Runs a long procedure without holding the GIL. `report` is called with the GIL after every
+// step, and Ctrl-C or an exception from the callback aborts the procedure (the motor is
+// stopped the same way as Ctrl-C in the loader) and is raised once it has unwound.
+fn run_procedure<P>(
+    py: Python<'_>,
+    progress: Option<PyObject>,
+    procedure: impl FnOnce(&mut dyn FnMut(&P)) -> rusb::Result<()> + Send,
+    to_dict: impl for<'py> Fn(Python<'py>, &P) -> PyResult<Bound<'py, PyDict>> + Send + Sync,
+) -> PyResult<()> {
+    let mut callback_error: Option<PyErr> = None;
+    let result = py.allow_threads(|| {
+        procedure(&mut |step: &P| {
+            Python::with_gil(|py| {
+                let reported = py.check_signals().and_then(|_| match &progress {
+                    None => Ok(()),
+                    Some(progress) => progress.call1(py, (to_dict(py, step)?,)).map(|_| ()),
+                });
+                if let Err(e) = reported {
+                    callback_error.get_or_insert(e);
+                    request_abort();
+                }
+            })
+        })
+    });
+    match (callback_error, result) {
+        (Some(e), _) => Err(e),
+        (None, result) => result.map_err(to_py_err),
+    }
+}
+
+#[pymethods]
+impl Controller {
+    /// Opens the first NSC-A1 found, or the one with USB serial number `serial`.
+    #[new]
+    #[pyo3(signature = (serial=None, vendor_id=NSC_A1_VENDOR_ID, product_id=NSC_A1_PRODUCT_ID))]
+    fn new(serial: Option<&str>, vendor_id: u16, product_id: u16) -> PyResult<Self> {
+        let handle = match serial {
+            None => open(vendor_id, product_id),
+            Some(serial) => open_by_serial(vendor_id, product_id, serial),
+        }
+        .map_err(to_py_err)?;
+        Ok(Controller {
+            handle: Some(handle),
+        })
+    }
+
+    /// Closes communication. Does nothing if already closed.
+    fn close(&mut self) -> PyResult<()> {
+        if let Some(handle) = self.handle.take() {
+            close(&handle).map_err(to_py_err)?;
+        }
+        Ok(())
+    }
+
+    fn __enter__(slf: PyRef<'_, Self>) -> PyRef<'_, Self> {
+        slf
+    }
+
+    #[pyo3(signature = (*_args))]
+    fn __exit__(&mut self, _args: &Bound<'_, pyo3::types::PyTuple>) -> PyResult<bool> {
+        self.close()?;
+        Ok(false)
+    }
+
+    /// Sends a raw command, e.g. "HSPD=1000", and returns the reply. Moves and jogs are held
+    /// to the soft limits like in interactive mode.
+    fn send_command(&self, command: &str) -> PyResult<String> {
+        send_command_within_limits(self.handle()?, command).map_err(to_py_err)
+    }
+
+    /// Moves to (ABS) or by (INC) `position` pulses. Doesn't wait, see wait_idle.
+    #[pyo3(name = "move")]
+    fn move_to(&self, position: i32) -> PyResult<()> {
+        move_stage(self.handle()?, position).map_err(to_py_err)
+    }
+
+    /// Jogs "+" or "-" until stop() (or to the soft limit if one is set).
+    fn jog(&self, direction: &str) -> PyResult<()> {
+        let positive = match direction {
+            "+" => true,
+            "-" => false,
+            _ => return Err(ControllerError::new_err("direction must be '+' or '-'")),
+        };
+        jog(self.handle()?, positive).map_err(to_py_err)
+    }
+
+    /// Decelerates to a stop.
+    fn stop(&self) -> PyResult<()> {
+        stop(self.handle()?).map_err(to_py_err)
+    }
+
+    /// Blocks until the motor is idle.
+    fn wait_idle(&self, py: Python<'_>) -> PyResult<()> {
+        let handle = self.handle()?;
+        py.allow_threads(|| wait_for_motor_idle(handle, &mut None))
+            .map_err(to_py_err)
+    }
+
+    #[getter]
+    fn pulse_position(&self) -> PyResult<i32> {
+        get_pulse_position(self.handle()?).map_err(to_py_err)
+    }
+
+    #[setter]
+    fn set_pulse_position(&self, position: i32) -> PyResult<()> {
+        set_pulse_position(self.handle()?, position).map_err(to_py_err)
+    }
+
+    #[getter]
+    fn encoder_position(&self) -> PyResult<i32> {
+        get_encoder_position(self.handle()?).map_err(to_py_err)
+    }
+
+    #[setter]
+    fn set_encoder_position(&self, position: i32) -> PyResult<()> {
+        set_encoder_position(self.handle()?, position).map_err(to_py_err)
+    }
+
+    /// The raw MST status word, 0 when idle.
+    #[getter]
+    fn motor_status(&self) -> PyResult<i32> {
+        get_motor_status(self.handle()?)
+            .map(|status| status.bits())
+            .map_err(to_py_err)
+    }
+
+    /// The names of the MST bits that are set, e.g. ["CONSTANT_SPEED", "HOME_INPUT"].
+    #[getter]
+    fn motor_flags(&self) -> PyResult<Vec<&'static str>> {
+        let status = get_motor_status(self.handle()?).map_err(to_py_err)?;
+        Ok(status.iter_names().map(|(name, _)| name).collect())
+    }
+
+    #[getter]
+    fn motor_enabled(&self) -> PyResult<bool> {
+        get_motor_enabled(self.handle()?).map_err(to_py_err)
+    }
+
+    #[setter]
+    fn set_motor_enabled(&self, enabled: bool) -> PyResult<()> {
+        let handle = self.handle()?;
+        match enabled {
+            true => turn_motor_on(handle),
+            false => turn_motor_off(handle),
+        }
+        .map_err(to_py_err)
+    }
+
+    #[getter]
+    fn high_speed(&self) -> PyResult<u32> {
+        get_high_speed(self.handle()?).map_err(to_py_err)
+    }
+
+    #[setter]
+    fn set_high_speed(&self, speed: u32) -> PyResult<()> {
+        set_high_speed(self.handle()?, speed).map_err(to_py_err)
+    }
+
+    /// LSPD in pulses/s.
+    fn set_low_speed(&self, speed: u32) -> PyResult<()> {
+        set_low_speed(self.handle()?, speed).map_err(to_py_err)
+    }
+
+    /// ACC in ms.
+    fn set_acceleration_time(&self, time: u32) -> PyResult<()> {
+        set_acceleration_time(self.handle()?, time).map_err(to_py_err)
+    }
+
+    /// DEC in ms.
+    fn set_deceleration_time(&self, time: u32) -> PyResult<()> {
+        set_deceleration_time(self.handle()?, time).map_err(to_py_err)
+    }
+
+    /// "abs" or "inc".
+    fn set_movement_type(&self, abs_inc: &str) -> PyResult<()> {
+        set_movement_type(self.handle()?, abs_inc).map_err(to_py_err)
+    }
+
+    /// Sets the driver parameters that are given and writes them with RW. The motor is
+    /// turned off by the write, turn it back on with motor_enabled = True.
+    #[pyo3(signature = (microsteps=None, idle_current=None, run_current=None, idle_time=None))]
+    fn write_driver_settings(
+        &self,
+        microsteps: Option<u32>,
+        idle_current: Option<u32>,
+        run_current: Option<u32>,
+        idle_time: Option<u32>,
+    ) -> PyResult<()> {
+        let handle = self.handle()?;
+        if let Some(microsteps) = microsteps {
+            set_microstepping(handle, microsteps).map_err(to_py_err)?;
+        }
+        if let Some(current) = idle_current {
+            set_idle_current(handle, current).map_err(to_py_err)?;
+        }
+        if let Some(current) = run_current {
+            set_run_current(handle, current).map_err(to_py_err)?;
+        }
+        if let Some(time) = idle_time {
+            set_idle_time(handle, time).map_err(to_py_err)?;
+        }
+        write_driver_settings(handle).map_err(to_py_err)
+    }
+
+    /// Runs the test in input_output_files/RunInput.txt (relative to the working directory).
+    /// `progress` is called with a dict after every cycle.
+    #[pyo3(signature = (progress=None))]
+    fn run(&self, py: Python<'_>, progress: Option<PyObject>) -> PyResult<()> {
+        let handle = self.handle()?;
+        run_procedure(
+            py,
+            progress,
+            |report| run_with_progress(handle, report),
+            |py, step: &RunProgress| {
+                let dict = PyDict::new(py);
+                dict.set_item("cycle", step.cycle)?;
+                dict.set_item("load_cycles", step.load_cycles)?;
+                dict.set_item("elapsed", step.elapsed)?;
+                dict.set_item("period_error", step.period_error)?;
+                dict.set_item("hspd", step.hspd)?;
+                Ok(dict)
+            },
+        )
+    }
+
+    /// Calibrates from input_output_files/CalibrateInput.txt (relative to the working
+    /// directory). `progress` is called with a dict after every speed adjustment.
+    #[pyo3(signature = (progress=None))]
+    fn calibrate(&self, py: Python<'_>, progress: Option<PyObject>) -> PyResult<()> {
+        let handle = self.handle()?;
+        run_procedure(
+            py,
+            progress,
+            |report| calibrate_with_progress(handle, report),
+            |py, step: &CalibrateProgress| {
+                let dict = PyDict::new(py);
+                dict.set_item("iteration", step.iteration)?;
+                dict.set_item("average_period", step.average_period)?;
+                dict.set_item("target_period", step.target_period)?;
+                dict.set_item("hspd", step.hspd)?;
+                Ok(dict)
+            },
+        )
+    }
+}
+
+#[pymodule]
+fn mechanical_loader(m: &Bound<'_, PyModule>) -> PyResult<()> {
+    m.add_class::<Controller>()?;
+    m.add("ControllerError", m.py().get_type::<ControllerError>())?;
+    m.add("VENDOR_ID", NSC_A1_VENDOR_ID)?;
+    m.add("PRODUCT_ID", NSC_A1_PRODUCT_ID)?;
+    Ok(())
+}
@@ -1 +1,6 @@
-pub mod cli;
\ No newline at end of file
+#[allow(clippy::module_inception)]
+pub mod cli;
+#[cfg(all(feature = "tui", unix))]
+pub mod dashboard;
+#[cfg(feature = "repl")]
+pub mod repl;
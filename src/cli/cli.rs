@@ -1,44 +1,272 @@
-use crate::stage_control::{
+use rust_mechanical_loader::stage_control::{
+    abort::{install_abort_handler, AbortScope},
     calibrate::calibrate,
-    commands::{close, interactive_mode, open},
-    run::run,
+    commands::{close, open, open_replay},
+    driver::Transport,
+    homing::{home, read_home_setting, HomeSettings},
+    metrics::{serve_metrics, DEFAULT_METRICS_ADDRESS},
+    profile::{get_device_profile, read_device_profile, set_device_profile},
+    recording::{is_replaying, set_replaying, start_recording, stop_recording, ReplayTransport},
+    run::{run, run_standalone, standalone_protocol},
+    script::run_script,
+    server::{serve, DEFAULT_ADDRESS},
+    standalone::{download_program, get_program_status, get_variable, stop_program, write_program},
 };
 
-use std::io::{stdin, Write};
+use std::{io::stdin, sync::Arc, thread};
+
+// Serving only ends with an error, the device is closed on the way out like any other failure
+fn serve_until_error(handle: &Arc<dyn Transport>, address: &str) -> rusb::Result<()> {
+    // Every client and stream is its own thread, a replay can't tell them apart
+    if is_replaying() {
+        eprintln!("serve can't be used with --replay");
+        return Err(rusb::Error::InvalidParam);
+    }
+    match serve(Arc::clone(handle), address) {
+        Ok(()) => Ok(()),
+        Err(e) => {
+            eprintln!("Server on {} stopped: {}", address, e);
+            Err(rusb::Error::Io)
+        }
+    }
+}
+
+// The dashboard when there's a terminal to show it on, otherwise the usual line per cycle
+#[cfg(all(feature = "tui", unix))]
+fn run_in_terminal(handle: &dyn Transport) -> rusb::Result<()> {
+    use std::io::IsTerminal;
+
+    // The dashboard's sampler can't be replayed, see recording
+    match std::io::stdout().is_terminal() && stdin().is_terminal() && !is_replaying() {
+        true => crate::cli::dashboard::run_dashboard(handle),
+        false => run(handle),
+    }
+}
+
+#[cfg(not(all(feature = "tui", unix)))]
+fn run_in_terminal(handle: &dyn Transport) -> rusb::Result<()> {
+    run(handle)
+}
+
+// `home [switch|limit|index] [+|-] [offset]`, anything left out is the default
+fn home_from_words(handle: &dyn Transport, words: &[&str]) -> rusb::Result<()> {
+    let mut settings = HomeSettings::default();
+    let keys = ["homemethod", "homedirection", "homeoffset"];
+    if words.len() > keys.len() {
+        eprintln!("Usage: home [switch|limit|index] [+|-] [offset]");
+        return Ok(());
+    }
+    for (key, value) in keys.iter().zip(words) {
+        if read_home_setting(&mut settings, key, value).is_err() {
+            return Ok(()); // Already said what was wrong
+        }
+    }
+
+    let _abort_scope = AbortScope::enter();
+    home(handle, &settings)
+}
+
+// `standalone compile|download|start|stop|status`, see stage_control::standalone
+fn standalone_from_words(handle: &dyn Transport, words: &[&str]) -> rusb::Result<()> {
+    let word = words.first().map(|word| word.to_ascii_lowercase());
+    match (word.as_deref(), words.len()) {
+        (Some("compile"), 1) => {
+            let protocol = standalone_protocol()?;
+            if let Err(e) = write_program(&protocol) {
+                eprintln!("Couldn't write the standalone program: {}", e);
+            }
+            Ok(())
+        }
+        (Some("download"), 1) => match download_program(handle, &standalone_protocol()?) {
+            // Not implemented, it has said so
+            Err(rusb::Error::NotSupported) => Ok(()),
+            result => result,
+        },
+        (Some("start"), 1) => match run_standalone(handle) {
+            // A program that stopped short has been reported, the session carries on
+            Err(rusb::Error::Other | rusb::Error::NotSupported) => Ok(()),
+            result => result,
+        },
+        (Some("stop"), 1) => stop_program(handle),
+        (Some("status"), 1) => {
+            let protocol = standalone_protocol()?;
+            println!(
+                "{:?}, V{} = {} of {} cycles",
+                get_program_status(handle)?,
+                protocol.counter,
+                get_variable(handle, protocol.counter)?,
+                protocol.load_cycles
+            );
+            Ok(())
+        }
+        _ => {
+            eprintln!("Usage: standalone compile|download|start|stop|status");
+            Ok(())
+        }
+    }
+}
+
+// History and completion when built with them, plain stdin lines otherwise
+#[cfg(feature = "repl")]
+fn interact(handle: &dyn Transport) -> rusb::Result<()> {
+    crate::cli::repl::repl(handle)
+}
+
+#[cfg(not(feature = "repl"))]
+fn interact(handle: &dyn Transport) -> rusb::Result<()> {
+    rust_mechanical_loader::stage_control::commands::interactive_mode(handle)
+}
+
+// Takes `--flag=value` out of the args
+fn take_flag_value(args: &mut Vec<String>, flag: &str) -> Option<String> {
+    let prefix = format!("{}=", flag);
+    let i = args.iter().position(|arg| arg.starts_with(&prefix))?;
+    Some(args.remove(i)[prefix.len()..].to_string())
+}
 
-// 0x1589, 0xa101
 pub fn cli() -> rusb::Result<()> {
-    let handle = open(0x1589, 0xa101)?;
+    let mut args: Vec<String> = std::env::args().skip(1).collect();
+
+    // `--profile=file` for a controller other than the NSC-A1
+    if let Some(path) = take_flag_value(&mut args, "--profile") {
+        set_device_profile(read_device_profile(&path)?);
+    }
+
+    // `--record=file` saves every USB transfer, started before opening so that's in it too
+    if let Some(path) = take_flag_value(&mut args, "--record") {
+        if let Err(e) = start_recording(&path) {
+            eprintln!("Couldn't start recording to {}: {}", path, e);
+            return Err(rusb::Error::Io);
+        }
+        println!("Recording USB transfers to {}", path);
+    }
+
+    // `--replay=file` plays a recording back instead of opening the controller
+    let replay = match take_flag_value(&mut args, "--replay") {
+        Some(path) => Some(Arc::new(open_replay(&path)?)),
+        None => None,
+    };
+    set_replaying(replay.is_some());
+    // Shared with the Ctrl-C handler so it can stop the motor and close the device
+    let handle: Arc<dyn Transport> = match &replay {
+        Some(replay) => replay.clone(),
+        None => {
+            let profile = get_device_profile();
+            Arc::new(open(profile.vendor_id, profile.product_id)?)
+        }
+    };
+    if let Err(e) = install_abort_handler(Arc::clone(&handle)) {
+        eprintln!("WARNING: Couldn't install the Ctrl-C handler: {}", e);
+    }
+
+    let result = session(&handle, args);
+    stop_recording();
+    if let Some(replay) = replay {
+        report_replay(&replay);
+    }
+    result
+}
+
+fn report_replay(replay: &ReplayTransport) {
+    println!(
+        "Replay finished with {} transfers left over and {} writes that didn't match the recording",
+        replay.remaining(),
+        replay.mismatches()
+    );
+}
+
+fn session(handle: &Arc<dyn Transport>, mut args: Vec<String>) -> rusb::Result<()> {
+    // `--metrics` or `--metrics=host:port` serves metrics alongside whatever else happens
+    if let Some(i) = args
+        .iter()
+        .position(|arg| arg == "--metrics" || arg.starts_with("--metrics="))
+    {
+        // Scrapes come whenever Prometheus likes, a replay can't know where they went
+        if is_replaying() {
+            eprintln!("--metrics can't be used with --replay");
+            return Err(rusb::Error::InvalidParam);
+        }
+        let arg = args.remove(i);
+        let address = arg
+            .strip_prefix("--metrics=")
+            .unwrap_or(DEFAULT_METRICS_ADDRESS)
+            .to_string();
+        let handle = Arc::clone(handle);
+        thread::spawn(move || {
+            if let Err(e) = serve_metrics(handle, &address) {
+                eprintln!("Metrics server on {} stopped: {}", address, e);
+            }
+        });
+    }
+
+    // `loader serve [address]` skips the prompt so it can be started as a daemon
+    if args.first().map(String::as_str) == Some("serve") {
+        let address = args.get(1).map(String::as_str).unwrap_or(DEFAULT_ADDRESS);
+        let result = serve_until_error(handle, address);
+        close(handle)?;
+        return result;
+    }
+
+    // `loader script file.txt` runs it and exits
+    if args.first().map(String::as_str) == Some("script") {
+        let result = match args.get(1) {
+            Some(path) => run_script(handle, path),
+            None => {
+                eprintln!("Usage: loader script file.txt");
+                Err(rusb::Error::InvalidParam)
+            }
+        };
+        close(handle)?;
+        return result;
+    }
 
     loop {
         let mut raw_input = String::new();
-        writeln!(
-            std::io::stdout(),
-            "Entering main loop. Enter 'calibrate', 'run' or 'interact'."
-        )
-        .unwrap();
+        println!("Entering main loop. Enter 'calibrate', 'run' (or 'run plain'), 'standalone', 'home', 'interact', 'script file' or 'serve [address]'.");
 
         match stdin().read_line(&mut raw_input) {
             Ok(_n) => (),
-            Err(e) => writeln!(std::io::stderr(), "Failed to read line with error {}", e).unwrap(),
-        }
-
-        let input = raw_input.trim().to_ascii_lowercase();
-
-        match input.as_str() {
-            "exit" => break,
-            "run" => run(&handle)?,
-            "calibrate" => calibrate(&handle)?,
-            "interact" => interactive_mode(&handle)?,
-            _ => writeln!(
-                std::io::stderr(),
-                "Didn't understand '{}'. Enter 'run', 'calibrate', 'interact' or 'exit'",
-                input
-            )
-            .unwrap(),
+            Err(e) => eprintln!("Failed to read line with error {}", e),
+        }
+
+        // Only the command is case insensitive, a socket path after it isn't
+        let input = raw_input.trim();
+        let words: Vec<&str> = input.split_whitespace().collect();
+        let command = words.first().unwrap_or(&"").to_ascii_lowercase();
+
+        let result = match (command.as_str(), &words[words.len().min(1)..]) {
+            ("exit", []) => break,
+            ("run", []) => run_in_terminal(handle),
+            ("run", [plain]) if plain.eq_ignore_ascii_case("plain") => run(handle),
+            ("calibrate", []) => calibrate(handle),
+            ("interact", []) => interact(handle),
+            ("home", words) => home_from_words(handle, words),
+            ("standalone", words) => standalone_from_words(handle, words),
+            ("script", [path]) => match run_script(handle, path) {
+                // A bad script shouldn't end the session
+                Err(rusb::Error::Other | rusb::Error::InvalidParam) => Ok(()),
+                result => result,
+            },
+            ("serve", []) => serve_until_error(handle, DEFAULT_ADDRESS),
+            ("serve", [address]) => serve_until_error(handle, address),
+            _ => {
+                eprintln!("Didn't understand '{}'. Enter 'run', 'calibrate', 'standalone', 'home', 'interact', 'script', 'serve' or 'exit'",
+                    input);
+                Ok(())
+            }
         };
+
+        // Close the device on the way out whatever happened, an aborted run ends the session
+        match result {
+            Ok(()) => (),
+            Err(rusb::Error::Interrupted) => break,
+            Err(e) => {
+                close(handle)?;
+                return Err(e);
+            }
+        }
     }
 
-    close(&handle)?;
+    close(handle)?;
     Ok(())
 }
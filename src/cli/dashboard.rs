@@ -0,0 +1,499 @@
+//! Full screen dashboard for `run`. Shows progress and ETA, live position, the period error
+//! trend and the controller's state, with keys to pause, resume and abort.
+
+use rust_mechanical_loader::stage_control::{
+    abort::{is_paused, request_abort, request_pause, request_resume},
+    commands::{
+        get_idle_current, get_idle_time, get_microstepping, get_motor_status, get_pulse_position,
+        get_run_current,
+    },
+    driver::Transport,
+    motor_status::MotorStatus,
+    recording::mark_background_thread,
+    run::{run_with_progress, RunProgress},
+};
+
+use ratatui::{
+    backend::CrosstermBackend,
+    crossterm::{
+        event::{self, Event, KeyCode, KeyEventKind, KeyModifiers},
+        execute,
+        terminal::{disable_raw_mode, enable_raw_mode, EnterAlternateScreen, LeaveAlternateScreen},
+    },
+    layout::{Constraint, Layout, Rect},
+    style::{Color, Style, Stylize},
+    text::Line,
+    widgets::{Block, Gauge, Paragraph, Sparkline},
+    Frame, Terminal,
+};
+
+use std::{
+    collections::VecDeque,
+    fs::File,
+    io::{self, BufRead, BufReader, Write},
+    os::fd::{AsFd, AsRawFd, FromRawFd, OwnedFd},
+    panic::{catch_unwind, AssertUnwindSafe},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        mpsc::{channel, Sender},
+    },
+    thread,
+    time::Duration,
+};
+
+// PX and MST are polled this often. Each poll is two commands the run has to wait behind
+const SAMPLE_INTERVAL: Duration = Duration::from_millis(200);
+const POSITION_HISTORY: usize = 600;
+const MESSAGE_HISTORY: usize = 200;
+const KEY_POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+#[derive(Debug, Clone, Copy)]
+struct DriverSettings {
+    microsteps: u32,
+    idle_current: u32,
+    run_current: u32,
+    idle_time: u32,
+}
+
+enum Update {
+    Progress(RunProgress),
+    Sample {
+        position: i32,
+        motor_status: MotorStatus,
+    },
+    Driver(DriverSettings),
+    Message(String),
+    Done(rusb::Result<()>),
+}
+
+#[derive(Default)]
+struct Dashboard {
+    progress: Option<RunProgress>,
+    period_errors: Vec<f64>,
+    positions: VecDeque<i32>,
+    motor_status: Option<MotorStatus>,
+    driver: Option<DriverSettings>,
+    messages: VecDeque<String>,
+    result: Option<rusb::Result<()>>,
+    pause_requested: bool,
+}
+
+// Points stdout and stderr at a pipe for as long as it lives, so the run's println!s end up
+// in the messages pane instead of on top of the dashboard
+struct OutputCapture {
+    saved_stdout: OwnedFd,
+    saved_stderr: OwnedFd,
+}
+
+impl OutputCapture {
+    // Returns the real terminal to draw on as well
+    fn start(updates: Sender<Update>) -> io::Result<(OutputCapture, File)> {
+        let mut fds = [0; 2];
+        if unsafe { libc::pipe(fds.as_mut_ptr()) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let (read, write) = unsafe { (OwnedFd::from_raw_fd(fds[0]), OwnedFd::from_raw_fd(fds[1])) };
+
+        let saved_stdout = io::stdout().as_fd().try_clone_to_owned()?;
+        let saved_stderr = io::stderr().as_fd().try_clone_to_owned()?;
+        io::stdout().flush()?;
+        for fd in [libc::STDOUT_FILENO, libc::STDERR_FILENO] {
+            if unsafe { libc::dup2(write.as_raw_fd(), fd) } < 0 {
+                return Err(io::Error::last_os_error());
+            }
+        }
+        // Once stdout and stderr are put back nothing has the write end open and this ends
+        thread::spawn(move || {
+            for line in BufReader::new(File::from(read))
+                .lines()
+                .map_while(Result::ok)
+            {
+                let _ = updates.send(Update::Message(line));
+            }
+        });
+
+        let terminal = File::from(saved_stdout.try_clone()?);
+        Ok((
+            OutputCapture {
+                saved_stdout,
+                saved_stderr,
+            },
+            terminal,
+        ))
+    }
+}
+
+impl Drop for OutputCapture {
+    fn drop(&mut self) {
+        let _ = io::stdout().flush();
+        unsafe {
+            libc::dup2(self.saved_stdout.as_raw_fd(), libc::STDOUT_FILENO);
+            libc::dup2(self.saved_stderr.as_raw_fd(), libc::STDERR_FILENO);
+        }
+    }
+}
+
+// Leaves raw mode and the alternate screen however the dashboard ends, panics included
+struct TerminalGuard(File);
+
+impl TerminalGuard {
+    fn enter(mut terminal: File) -> io::Result<TerminalGuard> {
+        enable_raw_mode()?;
+        execute!(terminal, EnterAlternateScreen)?;
+        Ok(TerminalGuard(terminal))
+    }
+}
+
+impl Drop for TerminalGuard {
+    fn drop(&mut self) {
+        let _ = disable_raw_mode();
+        let _ = execute!(self.0, LeaveAlternateScreen);
+    }
+}
+
+fn read_driver_settings(handle: &dyn Transport) -> rusb::Result<DriverSettings> {
+    Ok(DriverSettings {
+        microsteps: get_microstepping(handle)?,
+        idle_current: get_idle_current(handle)?,
+        run_current: get_run_current(handle)?,
+        idle_time: get_idle_time(handle)?,
+    })
+}
+
+// Polls PX and MST until the run is over. The driver settings are read once the first cycle
+// is done, by then run has written the run file's
+fn sample(
+    handle: &dyn Transport,
+    updates: Sender<Update>,
+    done: &AtomicBool,
+    first_cycle_done: &AtomicBool,
+) {
+    mark_background_thread("dashboard");
+    let mut driver_read = false;
+    while !done.load(Ordering::SeqCst) {
+        let sampled = get_pulse_position(handle).and_then(|position| {
+            Ok(Update::Sample {
+                position,
+                motor_status: get_motor_status(handle)?,
+            })
+        });
+        match sampled {
+            Ok(update) => {
+                let _ = updates.send(update);
+            }
+            Err(e) => {
+                let _ = updates.send(Update::Message(format!(
+                    "WARNING: Failed to poll PX/MST: {}",
+                    e
+                )));
+            }
+        }
+
+        if !driver_read && first_cycle_done.load(Ordering::SeqCst) {
+            driver_read = true;
+            match read_driver_settings(handle) {
+                Ok(settings) => {
+                    let _ = updates.send(Update::Driver(settings));
+                }
+                Err(e) => {
+                    let _ = updates.send(Update::Message(format!(
+                        "WARNING: Failed to read the driver settings: {}",
+                        e
+                    )));
+                }
+            }
+        }
+        thread::sleep(SAMPLE_INTERVAL);
+    }
+}
+
+fn is_warning(message: &str) -> bool {
+    let lower = message.to_ascii_lowercase();
+    lower.contains("warning") || lower.contains("error") || lower.contains("fail")
+}
+
+fn format_duration(seconds: f64) -> String {
+    let seconds = seconds.max(0.0) as u64;
+    format!(
+        "{}h {:02}m {:02}s",
+        seconds / 3600,
+        seconds / 60 % 60,
+        seconds % 60
+    )
+}
+
+// Sparklines only do unsigned bars, so scale whatever range the values cover onto 1-100
+fn sparkline_data(values: impl Iterator<Item = f64> + Clone) -> Vec<u64> {
+    let min = values.clone().fold(f64::INFINITY, f64::min);
+    let max = values.clone().fold(f64::NEG_INFINITY, f64::max);
+    let range = (max - min).max(f64::EPSILON);
+    values
+        .map(|value| 1 + ((value - min) / range * 99.0) as u64)
+        .collect()
+}
+
+impl Dashboard {
+    fn apply(&mut self, update: Update) {
+        match update {
+            Update::Progress(progress) => {
+                self.progress = Some(progress);
+                self.period_errors.push(progress.period_error);
+            }
+            Update::Sample {
+                position,
+                motor_status,
+            } => {
+                if self.positions.len() == POSITION_HISTORY {
+                    self.positions.pop_front();
+                }
+                self.positions.push_back(position);
+                self.motor_status = Some(motor_status);
+            }
+            Update::Driver(settings) => self.driver = Some(settings),
+            Update::Message(message) => {
+                if self.messages.len() == MESSAGE_HISTORY {
+                    self.messages.pop_front();
+                }
+                // adjust_speed's lines are tab separated, tabs throw the layout off
+                self.messages.push_back(message.replace('\t', "  "));
+            }
+            Update::Done(result) => self.result = Some(result),
+        }
+    }
+
+    // What the run is doing, worked out from what has been reported so far
+    fn phase(&self) -> &'static str {
+        match (&self.result, self.progress) {
+            (Some(Ok(())), _) => "Finished",
+            (Some(Err(rusb::Error::Interrupted)), _) => "Aborted",
+            (Some(Err(_)), _) => "Failed",
+            (None, _) if is_paused() => "Paused",
+            (None, _) if self.pause_requested => "Pausing after this cycle",
+            (None, None) => "Setting up and moving to the offset",
+            (None, Some(progress)) if progress.cycle >= progress.load_cycles => "End of test",
+            (None, Some(_)) => "Cycling",
+        }
+    }
+
+    fn draw(&self, frame: &mut Frame) {
+        let [run_area, trends_area, bottom_area, keys_area] = Layout::vertical([
+            Constraint::Length(4),
+            Constraint::Min(6),
+            Constraint::Min(8),
+            Constraint::Length(1),
+        ])
+        .areas(frame.area());
+        let [position_area, error_area] =
+            Layout::horizontal([Constraint::Percentage(50); 2]).areas(trends_area);
+        let [controller_area, messages_area] =
+            Layout::horizontal([Constraint::Length(34), Constraint::Min(20)]).areas(bottom_area);
+
+        self.draw_run(frame, run_area);
+        self.draw_position(frame, position_area);
+        self.draw_period_error(frame, error_area);
+        self.draw_controller(frame, controller_area);
+        self.draw_messages(frame, messages_area);
+
+        let keys = match self.result {
+            None => " p pause   r resume   a abort",
+            Some(_) => " Press any key to leave the dashboard",
+        };
+        frame.render_widget(Paragraph::new(keys).dark_gray(), keys_area);
+    }
+
+    fn draw_run(&self, frame: &mut Frame, area: Rect) {
+        let block = Block::bordered().title(format!(" Run: {} ", self.phase()));
+        let (ratio, label) = match self.progress {
+            None => (0.0, "waiting for the first cycle".to_string()),
+            Some(progress) => {
+                let average = progress.elapsed / progress.cycle as f64;
+                let remaining = (progress.load_cycles - progress.cycle) as f64 * average;
+                (
+                    progress.cycle as f64 / progress.load_cycles.max(1) as f64,
+                    format!(
+                        "cycle {}/{}   elapsed {}   ETA {}",
+                        progress.cycle,
+                        progress.load_cycles,
+                        format_duration(progress.elapsed),
+                        format_duration(remaining)
+                    ),
+                )
+            }
+        };
+        let gauge = Gauge::default()
+            .block(block)
+            .gauge_style(Style::default().fg(Color::Green))
+            .ratio(ratio.clamp(0.0, 1.0))
+            .label(label);
+        frame.render_widget(gauge, area);
+    }
+
+    fn draw_position(&self, frame: &mut Frame, area: Rect) {
+        let title = match self.positions.back() {
+            None => " Position ".to_string(),
+            Some(position) => format!(" Position PX={} ", position),
+        };
+        let width = area.width.saturating_sub(2) as usize;
+        let shown = self
+            .positions
+            .iter()
+            .skip(self.positions.len().saturating_sub(width));
+        let sparkline = Sparkline::default()
+            .block(Block::bordered().title(title))
+            .style(Style::default().fg(Color::Cyan))
+            .data(sparkline_data(shown.map(|&position| position as f64)));
+        frame.render_widget(sparkline, area);
+    }
+
+    fn draw_period_error(&self, frame: &mut Frame, area: Rect) {
+        let title = match self.progress {
+            None => " Period error ".to_string(),
+            Some(progress) => format!(" Period error {:+.4} s ", progress.period_error),
+        };
+        let width = area.width.saturating_sub(2) as usize;
+        let shown = &self.period_errors[self.period_errors.len().saturating_sub(width)..];
+        let sparkline = Sparkline::default()
+            .block(Block::bordered().title(title))
+            .style(Style::default().fg(Color::Yellow))
+            .data(sparkline_data(shown.iter().copied()));
+        frame.render_widget(sparkline, area);
+    }
+
+    fn draw_controller(&self, frame: &mut Frame, area: Rect) {
+        let unknown = || "?".to_string();
+        let mut lines = vec![
+            Line::from(format!(
+                "HSPD   {}",
+                self.progress.map_or_else(unknown, |p| p.hspd.to_string())
+            )),
+            Line::from(match self.motor_status {
+                None => "MST    ?".to_string(),
+                Some(status) => format!("MST    {} {}", status.bits(), status.describe()),
+            }),
+            Line::from(""),
+        ];
+        match self.driver {
+            None => lines.push(Line::from("Driver settings after cycle 1")),
+            Some(driver) => {
+                lines.push(Line::from(format!("DRVMS  {}", driver.microsteps)));
+                lines.push(Line::from(format!("DRVIC  {} mA", driver.idle_current)));
+                lines.push(Line::from(format!("DRVRC  {} mA", driver.run_current)));
+                lines.push(Line::from(format!("DRVIT  {} cs", driver.idle_time)));
+            }
+        }
+        let paragraph = Paragraph::new(lines).block(Block::bordered().title(" Controller "));
+        frame.render_widget(paragraph, area);
+    }
+
+    fn draw_messages(&self, frame: &mut Frame, area: Rect) {
+        let height = area.height.saturating_sub(2) as usize;
+        let lines: Vec<Line> = self
+            .messages
+            .iter()
+            .skip(self.messages.len().saturating_sub(height))
+            .map(|message| match is_warning(message) {
+                true => Line::from(message.as_str()).red(),
+                false => Line::from(message.as_str()).dark_gray(),
+            })
+            .collect();
+        let paragraph = Paragraph::new(lines).block(Block::bordered().title(" Messages "));
+        frame.render_widget(paragraph, area);
+    }
+
+    // Returns true once the dashboard should close
+    fn handle_key(&mut self, code: KeyCode, modifiers: KeyModifiers) -> bool {
+        if self.result.is_some() {
+            return true;
+        }
+        match code {
+            KeyCode::Char('p') => {
+                request_pause();
+                self.pause_requested = true;
+            }
+            KeyCode::Char('r') => {
+                request_resume();
+                self.pause_requested = false;
+            }
+            // Raw mode swallows Ctrl-C as a signal, so it comes through here
+            KeyCode::Char('a') => request_abort(),
+            KeyCode::Char('c') if modifiers.contains(KeyModifiers::CONTROL) => request_abort(),
+            _ => (),
+        }
+        false
+    }
+}
+
+/// Runs the test like run::run, showing the dashboard instead of printing every cycle.
+pub fn run_dashboard(handle: &dyn Transport) -> rusb::Result<()> {
+    let (updates, received) = channel();
+    let (capture, terminal) = OutputCapture::start(updates.clone()).map_err(|e| {
+        eprintln!("Couldn't capture the output for the dashboard: {}", e);
+        rusb::Error::Other
+    })?;
+    let guard = TerminalGuard::enter(terminal.try_clone().map_err(|_| rusb::Error::Other)?)
+        .map_err(|_| rusb::Error::Other)?;
+    let mut terminal =
+        Terminal::new(CrosstermBackend::new(terminal)).map_err(|_| rusb::Error::Other)?;
+
+    let done = AtomicBool::new(false);
+    let first_cycle_done = AtomicBool::new(false);
+    let mut dashboard = Dashboard::default();
+
+    thread::scope(|s| {
+        let run_updates = updates.clone();
+        let (done, first_cycle_done) = (&done, &first_cycle_done);
+        s.spawn(move || {
+            // A panicking run still has to end the dashboard, or the terminal is left raw
+            let result = catch_unwind(AssertUnwindSafe(|| {
+                run_with_progress(handle, &mut |progress| {
+                    first_cycle_done.store(true, Ordering::SeqCst);
+                    let _ = run_updates.send(Update::Progress(*progress));
+                })
+            }))
+            .unwrap_or_else(|_| {
+                eprintln!("ERROR: The run panicked");
+                Err(rusb::Error::Other)
+            });
+            done.store(true, Ordering::SeqCst);
+            let _ = run_updates.send(Update::Done(result));
+        });
+        let sample_updates = updates.clone();
+        s.spawn(move || sample(handle, sample_updates, done, first_cycle_done));
+
+        loop {
+            while let Ok(update) = received.try_recv() {
+                dashboard.apply(update);
+            }
+            if terminal.draw(|frame| dashboard.draw(frame)).is_err() {
+                // Nothing to show it on, so don't leave the stage cycling unwatched
+                if dashboard.result.is_some() {
+                    break;
+                }
+                request_abort();
+                thread::sleep(KEY_POLL_INTERVAL);
+                continue;
+            }
+            if event::poll(KEY_POLL_INTERVAL).unwrap_or(false) {
+                if let Ok(Event::Key(key)) = event::read() {
+                    if key.kind == KeyEventKind::Press
+                        && dashboard.handle_key(key.code, key.modifiers)
+                    {
+                        break;
+                    }
+                }
+            }
+        }
+    });
+
+    drop(guard);
+    drop(capture);
+    // Warnings only went to the dashboard, don't let them disappear with it
+    for message in dashboard
+        .messages
+        .iter()
+        .filter(|message| is_warning(message))
+    {
+        eprintln!("{}", message);
+    }
+    dashboard.result.unwrap_or(Err(rusb::Error::Other))
+}
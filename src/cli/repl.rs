@@ -0,0 +1,129 @@
+//! Interactive mode with a line editor: history kept between sessions and tab completion
+//! of the command names and script paths.
+
+use rust_mechanical_loader::stage_control::{
+    command_table::{COMMANDS, LOCAL_COMMANDS},
+    commands::{interactive_mode, interactive_mode_with},
+    driver::Transport,
+};
+use rustyline::{
+    completion::{Completer, FilenameCompleter},
+    error::ReadlineError,
+    highlight::Highlighter,
+    hint::Hinter,
+    history::DefaultHistory,
+    validate::Validator,
+    Context, Editor, Helper,
+};
+
+use std::path::PathBuf;
+
+const HISTORY_FILE: &str = ".loader_history";
+
+struct CommandCompleter {
+    files: FilenameCompleter,
+}
+
+// Every name we know, the loader's own commands first. @file is completed as a path instead.
+fn command_names() -> impl Iterator<Item = &'static str> {
+    LOCAL_COMMANDS
+        .iter()
+        .filter_map(|(usage, _)| usage.split_whitespace().next())
+        .filter(|name| !name.starts_with('@'))
+        .chain(COMMANDS.iter().map(|info| info.name))
+}
+
+impl Completer for CommandCompleter {
+    type Candidate = String;
+
+    // Completes the command at the start of the line, or the one after HELP, or the script
+    // path after @
+    fn complete(
+        &self,
+        line: &str,
+        pos: usize,
+        _ctx: &Context<'_>,
+    ) -> rustyline::Result<(usize, Vec<String>)> {
+        if let Some(path) = line.strip_prefix('@') {
+            let (start, pairs) = self.files.complete_path(path, pos.saturating_sub(1))?;
+            let candidates = pairs.into_iter().map(|pair| pair.replacement).collect();
+            return Ok((start + 1, candidates));
+        }
+
+        let before = &line[..pos];
+        let start = before.rfind(' ').map_or(0, |i| i + 1);
+        let first_word = before[..start].trim();
+        if !(first_word.is_empty() || first_word.eq_ignore_ascii_case("HELP")) {
+            return Ok((pos, Vec::new()));
+        }
+
+        let word = before[start..].to_ascii_uppercase();
+        let candidates = command_names()
+            .filter(|name| name.starts_with(&word))
+            .map(String::from)
+            .collect();
+        Ok((start, candidates))
+    }
+}
+
+impl Hinter for CommandCompleter {
+    type Hint = String;
+}
+impl Highlighter for CommandCompleter {}
+impl Validator for CommandCompleter {}
+impl Helper for CommandCompleter {}
+
+// In the home directory so it's the same history wherever the loader is started from
+fn history_path() -> PathBuf {
+    std::env::var_os("HOME")
+        .or_else(|| std::env::var_os("USERPROFILE"))
+        .map_or_else(PathBuf::new, PathBuf::from)
+        .join(HISTORY_FILE)
+}
+
+pub fn repl(handle: &dyn Transport) -> rusb::Result<()> {
+    let mut editor = match Editor::<CommandCompleter, DefaultHistory>::new() {
+        Ok(editor) => editor,
+        Err(e) => {
+            eprintln!("WARNING: No line editor ({}), reading plain lines", e);
+            return interactive_mode(handle);
+        }
+    };
+    editor.set_helper(Some(CommandCompleter {
+        files: FilenameCompleter::new(),
+    }));
+    let history = history_path();
+    // Not there the first time round
+    let _ = editor.load_history(&history);
+
+    let result = interactive_mode_with(handle, &mut || {
+        match editor.readline("nsc> ") {
+            Ok(line) => {
+                if !line.trim().is_empty() {
+                    let _ = editor.add_history_entry(line.as_str());
+                }
+                Some(line)
+            }
+            // The terminal is raw while we wait for a line so Ctrl-C lands here rather than
+            // in the abort handler, stopping the motor is still the safe thing to do
+            Err(ReadlineError::Interrupted) => {
+                println!("Ctrl-C, sending STOP (EXIT to leave)");
+                Some("STOP".to_string())
+            }
+            Err(ReadlineError::Eof) => None,
+            Err(e) => {
+                eprintln!("Failed to read line with error {}", e);
+                None
+            }
+        }
+    });
+
+    if let Err(e) = editor.save_history(&history) {
+        eprintln!(
+            "WARNING: Couldn't save history to {}: {}",
+            history.display(),
+            e
+        );
+    }
+    result
+}
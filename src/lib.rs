@@ -0,0 +1,23 @@
+//! Driver for the Newmark NSC-A1 stepper motor controller over USB, plus the cyclic loading
+//! procedures (`run` and `calibrate`) built on top of it.
+//!
+//! The layers, from the bottom up:
+//! - [`stage_control::driver`]: raw USB transfers, with the endpoints, control codes and
+//!   timeouts from [`stage_control::profile`].
+//! - [`stage_control::commands`]: one function per controller command, motion waits and
+//!   interactive mode.
+//! - [`stage_control::run`] and [`stage_control::calibrate`]: the file driven procedures.
+//!
+//! ```no_run
+//! use rust_mechanical_loader::stage_control::{
+//!     commands::{close, get_pulse_position, open},
+//!     driver::{NSC_A1_PRODUCT_ID, NSC_A1_VENDOR_ID},
+//! };
+//!
+//! let handle = open(NSC_A1_VENDOR_ID, NSC_A1_PRODUCT_ID)?;
+//! println!("PX={}", get_pulse_position(&handle)?);
+//! close(&handle)?;
+//! # Ok::<(), rusb::Error>(())
+//! ```
+
+pub mod stage_control;
@@ -1,9 +1,7 @@
 use cli::cli::cli;
 
-pub mod cli;
-pub mod stage_control;
+mod cli;
 
-// 0x1589, 0xa101
 fn main() {
     cli().unwrap();
 }
@@ -1,4 +1,24 @@
+//! Everything that talks to the controller. See the crate docs for how the modules stack up.
+
+pub mod abort;
 pub mod calibrate;
+pub mod command_table;
 pub mod commands;
 pub mod driver;
+pub mod force;
+pub mod force_control;
+pub mod homing;
+pub mod io;
+pub mod limits;
+pub mod metrics;
+pub mod motor_status;
+pub mod profile;
+pub mod read_file;
+pub mod recording;
 pub mod run;
+pub mod script;
+pub mod server;
+pub mod standalone;
+pub mod triggers;
+pub mod units;
+pub mod watchdog;
@@ -0,0 +1,160 @@
+//! Ctrl-C/SIGTERM handling and the abort and pause checks used while the motor moves.
+
+use crate::stage_control::{
+    commands::{close, motor_is_moving, stop, turn_motor_off},
+    driver::Transport,
+};
+
+use rusb::Result;
+
+use std::{
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    thread::sleep,
+    time::{Duration, Instant},
+};
+
+// How often an interruptible sleep checks for an abort
+const ABORT_POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+static ABORT_REQUESTED: AtomicBool = AtomicBool::new(false);
+static IN_PROCEDURE: AtomicBool = AtomicBool::new(false);
+static MOTOR_OFF_ON_ABORT: AtomicBool = AtomicBool::new(false);
+static PAUSE_REQUESTED: AtomicBool = AtomicBool::new(false);
+static PAUSED: AtomicBool = AtomicBool::new(false);
+
+/// Installs a Ctrl-C/SIGTERM handler. While run or calibrate is going (see AbortScope) it only
+/// asks them to stop so they can stop the motor and finish their output files themselves.
+/// Otherwise, or on a second Ctrl-C, nobody is going to check so the handler stops the motor,
+/// closes the device and exits itself.
+pub fn install_abort_handler(handle: Arc<dyn Transport>) -> std::result::Result<(), ctrlc::Error> {
+    ctrlc::set_handler(move || {
+        if IN_PROCEDURE.load(Ordering::SeqCst) && !ABORT_REQUESTED.swap(true, Ordering::SeqCst) {
+            eprintln!("\nAbort requested, stopping the motor. Ctrl-C again to force quit.");
+            return;
+        }
+
+        eprintln!("\nStopping the motor and closing the device");
+        if let Err(e) = stop(&handle) {
+            eprintln!("Failed to stop the motor: {}", e);
+        }
+        if MOTOR_OFF_ON_ABORT.load(Ordering::SeqCst) {
+            let _ = turn_motor_off(&handle);
+        }
+        if let Err(e) = close(&handle) {
+            eprintln!("Failed to close the device: {}", e);
+        }
+        std::process::exit(130);
+    })
+}
+
+/// Whether the motor is turned off (EO=0) after an abort. Off by default since an energized
+/// motor holds the specimen where it is.
+pub fn set_motor_off_on_abort(motor_off: bool) {
+    MOTOR_OFF_ON_ABORT.store(motor_off, Ordering::SeqCst);
+}
+
+/// Asks the running procedure to stop, same as Ctrl-C.
+pub fn request_abort() {
+    ABORT_REQUESTED.store(true, Ordering::SeqCst);
+}
+
+/// Whether an abort has been asked for.
+pub fn abort_requested() -> bool {
+    ABORT_REQUESTED.load(Ordering::SeqCst)
+}
+
+/// Asks the running procedure to hold at the next cycle boundary.
+pub fn request_pause() {
+    PAUSE_REQUESTED.store(true, Ordering::SeqCst);
+}
+
+/// Lets a paused procedure carry on.
+pub fn request_resume() {
+    PAUSE_REQUESTED.store(false, Ordering::SeqCst);
+}
+
+/// Whether a pause has been asked for and not resumed yet.
+pub fn pause_requested() -> bool {
+    PAUSE_REQUESTED.load(Ordering::SeqCst)
+}
+
+/// Whether the procedure is holding at a cycle boundary right now.
+pub fn is_paused() -> bool {
+    PAUSED.load(Ordering::SeqCst)
+}
+
+/// Marks a procedure that checks for aborts. Clears any old abort or pause on the way in.
+pub struct AbortScope;
+
+impl AbortScope {
+    /// Start checking for aborts, stops when dropped.
+    pub fn enter() -> AbortScope {
+        ABORT_REQUESTED.store(false, Ordering::SeqCst);
+        PAUSE_REQUESTED.store(false, Ordering::SeqCst);
+        IN_PROCEDURE.store(true, Ordering::SeqCst);
+        AbortScope
+    }
+}
+
+impl Drop for AbortScope {
+    fn drop(&mut self) {
+        IN_PROCEDURE.store(false, Ordering::SeqCst);
+        ABORT_REQUESTED.store(false, Ordering::SeqCst);
+        PAUSE_REQUESTED.store(false, Ordering::SeqCst);
+    }
+}
+
+/// Called from anywhere that waits on the motor. If an abort was asked for, does a
+/// decelerated stop, waits for it, optionally turns the motor off and returns Interrupted
+/// so everything up the chain unwinds with `?`.
+pub fn check_abort(handle: &dyn Transport) -> Result<()> {
+    if !abort_requested() {
+        return Ok(());
+    }
+
+    stop(handle)?;
+    while motor_is_moving(handle)? {}
+    if MOTOR_OFF_ON_ABORT.load(Ordering::SeqCst) {
+        turn_motor_off(handle)?;
+    }
+    Err(rusb::Error::Interrupted)
+}
+
+/// thread::sleep that gives up early (through check_abort) if an abort comes in
+pub fn sleep_or_abort(handle: &dyn Transport, duration: Duration) -> Result<()> {
+    let start = Instant::now();
+    while start.elapsed() < duration {
+        check_abort(handle)?;
+        sleep(ABORT_POLL_INTERVAL.min(duration.saturating_sub(start.elapsed())));
+    }
+    check_abort(handle)
+}
+
+/// Called between cycles. Holds while a pause is requested (an abort still gets through) and
+/// returns how long it held so the caller can move its timing reference along.
+pub fn wait_while_paused(handle: &dyn Transport) -> Result<Duration> {
+    if !PAUSE_REQUESTED.load(Ordering::SeqCst) {
+        return Ok(Duration::ZERO);
+    }
+
+    let start = Instant::now();
+    PAUSED.store(true, Ordering::SeqCst);
+    println!("Paused");
+    let result = loop {
+        if let Err(e) = check_abort(handle) {
+            break Err(e);
+        }
+        if !PAUSE_REQUESTED.load(Ordering::SeqCst) {
+            break Ok(start.elapsed());
+        }
+        sleep(ABORT_POLL_INTERVAL);
+    };
+    PAUSED.store(false, Ordering::SeqCst);
+    if result.is_ok() {
+        println!("Resumed");
+    }
+    result
+}
@@ -1,16 +1,32 @@
-use crate::stage_control::commands::{
-    move_cycle_get_time, set_acceleration_time, set_deceleration_time, set_encoder_position,
-    set_high_speed, set_idle_time, set_low_speed, set_microstepping, set_movement_type,
-    set_pulse_position, turn_motor_on, write_driver_settings,
-};
+//! Finds the high speed that gives the wanted cycle period and writes a run file with it.
 
-use rusb::{DeviceHandle, GlobalContext};
+use crate::stage_control::{
+    abort::AbortScope,
+    commands::{
+        move_cycle_get_time, set_acceleration_time, set_deceleration_time, set_encoder_position,
+        set_high_speed, set_idle_time, set_low_speed, set_microstepping, set_movement_type,
+        set_pulse_position, turn_motor_on, write_driver_settings,
+    },
+    driver::Transport,
+    homing::{home, read_home_setting, HomeSettings},
+    read_file::parse_value,
+};
 
 use std::{
     fs::File,
     io::{Read, Write},
 };
 
+/// Reported after every set of averaging cycles while calibrating.
+#[derive(Debug, Clone, Copy)]
+pub struct CalibrateProgress {
+    pub iteration: u32,
+    pub average_period: f64, // s, averaged over AveragingCycles
+    pub target_period: f64,
+    pub hspd: u32, // High speed for the next iteration
+}
+
+/// Everything read from the calibrate file plus the state that changes while calibrating.
 // TODO fix this? I don't like it. Better names, maybe different structure entirely
 #[derive(Debug)]
 pub struct CalibrateParameters {
@@ -28,7 +44,8 @@ pub struct CalibrateParameters {
     tolerance: f64,
     min_period: f64,
     max_period: f64,
-    time: f64, // This is the current cycle time, changes every cycle
+    home: Option<HomeSettings>, // Home before calibrating instead of zeroing where the stage is
+    time: f64,                  // This is the current cycle time, changes every cycle
     hspd: u32, // This is the current high speed and not the inputted high max_speed, changes every cycle
 }
 
@@ -37,12 +54,12 @@ fn read_file_to_vector_of_lines(file_path: &str) -> std::io::Result<Vec<String>>
     let mut whole_file: String = String::new();
     file.read_to_string(&mut whole_file)?;
     let split_vec: Vec<String> = whole_file.split("\n").map(str::to_string).collect();
-    return Ok(split_vec);
+    Ok(split_vec)
 }
 
 // TODO make thise bad numbers, I'd rather error then let a user use defaults
 fn initialize_calibrate_parameters() -> CalibrateParameters {
-    return CalibrateParameters {
+    CalibrateParameters {
         high_speed: 1500u32,
         low_speed: 100u32,
         acceleration_time: 1u32,
@@ -57,75 +74,91 @@ fn initialize_calibrate_parameters() -> CalibrateParameters {
         tolerance: 0.997f64,
         min_period: 0f64,
         max_period: 0f64,
+        home: None,
         time: 0f64,
         hspd: 0u32,
-    };
+    }
 }
 
 fn get_average_of_vector(vec: &Vec<f64>) -> f64 {
     let mut sum: f64 = 0.0;
     for ele in vec {
-        sum = sum + ele;
+        sum += ele;
     }
-    return sum / vec.len() as f64;
+    sum / vec.len() as f64
 }
 
-fn adjust_speed(
-    handle: &DeviceHandle<GlobalContext>,
-    params: &CalibrateParameters,
-) -> rusb::Result<u32> {
+fn adjust_speed(handle: &dyn Transport, params: &CalibrateParameters) -> rusb::Result<u32> {
     let error = (params.time - params.period) * params.factor * 1000.0;
     let new_hspd: u32 = (params.hspd as i32 + error as i32) as u32;
     set_high_speed(handle, new_hspd)?;
-    return Ok(new_hspd);
+    Ok(new_hspd)
 }
 
 fn set_calibrate_parameters_from_file(
-    handle: &DeviceHandle<GlobalContext>,
+    handle: &dyn Transport,
     file_path: &str,
 ) -> rusb::Result<CalibrateParameters> {
     let mut params: CalibrateParameters = initialize_calibrate_parameters();
+    let mut home_settings = HomeSettings::default();
+    let mut home_configured = false;
 
-    let lines =
-        read_file_to_vector_of_lines(file_path).expect("Couldn't read inputs to run device");
+    let lines = read_file_to_vector_of_lines(file_path).map_err(|e| {
+        eprintln!("Couldn't read the calibrate file {}: {}", file_path, e);
+        rusb::Error::NotFound
+    })?;
 
     for whole_line in lines {
         let line: Vec<&str> = whole_line.split_whitespace().collect();
 
-        if whole_line.is_empty() || line[0].starts_with("#") {
+        if line.is_empty() || line[0].starts_with("#") {
             continue;
         }
+        let key = line[0];
+        let Some(&value) = line.get(1) else {
+            eprintln!("{} needs a value", key);
+            return Err(rusb::Error::InvalidParam);
+        };
 
-        match line[0].to_ascii_lowercase().as_str() {
+        match key.to_ascii_lowercase().as_str() {
             "highspeed" => {
-                let _ = set_high_speed(handle, line[1].parse::<u32>().unwrap())?;
-                params.high_speed = line[1].parse().unwrap();
-                params.hspd = line[1].parse().unwrap();
+                let speed = parse_value(key, value)?;
+                set_high_speed(handle, speed)?;
+                params.high_speed = speed;
+                params.hspd = speed;
             }
             "lowspeed" => {
-                let _ = set_low_speed(handle, line[1].parse::<u32>().unwrap())?;
-                params.low_speed = line[1].parse().unwrap();
+                let speed = parse_value(key, value)?;
+                set_low_speed(handle, speed)?;
+                params.low_speed = speed;
             }
             "accelerationtime" => {
-                let _ = set_acceleration_time(handle, line[1].parse::<u32>().unwrap())?;
-                params.acceleration_time = line[1].parse().unwrap();
+                let time = parse_value(key, value)?;
+                set_acceleration_time(handle, time)?;
+                params.acceleration_time = time;
             }
             "decelerationtime" => {
-                let _ = set_deceleration_time(handle, line[1].parse::<u32>().unwrap())?;
-                params.deceleration_time = line[1].parse().unwrap();
+                let time = parse_value(key, value)?;
+                set_deceleration_time(handle, time)?;
+                params.deceleration_time = time;
             }
             "idletime" => {
-                let _ = set_idle_time(handle, line[1].parse::<u32>().unwrap())?;
-                params.idle_time = line[1].parse().unwrap();
+                let time = parse_value(key, value)?;
+                set_idle_time(handle, time)?;
+                params.idle_time = time;
             }
 
-            "amplitude" => params.amplitude = line[1].parse().unwrap(),
-            "averagingcycles" => params.averaging_cycles = line[1].parse().unwrap(),
-            "dwelltime" => params.dwell_time = line[1].parse().unwrap(),
-            "factor" => params.factor = line[1].parse().unwrap(),
-            "maxspeed" => params.max_speed = line[1].parse().unwrap(),
-            "period" => params.period = line[1].parse().unwrap(),
-            "tolerance" => params.tolerance = line[1].parse().unwrap(),
+            "amplitude" => params.amplitude = parse_value(key, value)?,
+            "averagingcycles" => params.averaging_cycles = parse_value(key, value)?,
+            "dwelltime" => params.dwell_time = parse_value(key, value)?,
+            "factor" => params.factor = parse_value(key, value)?,
+            "maxspeed" => params.max_speed = parse_value(key, value)?,
+            "period" => params.period = parse_value(key, value)?,
+            "tolerance" => params.tolerance = parse_value(key, value)?,
+
+            key if read_home_setting(&mut home_settings, key, value)? => {
+                home_configured |= key == "homemethod";
+            }
 
             _ => println!(
                 "Couldn't understand {:?}",
@@ -133,9 +166,10 @@ fn set_calibrate_parameters_from_file(
             ),
         }
     }
+    params.home = home_configured.then_some(home_settings);
     write_driver_settings(handle)?;
     turn_motor_on(handle)?;
-    return Ok(params);
+    Ok(params)
 }
 
 fn write_run_file_after_calibration(params: CalibrateParameters) -> std::io::Result<()> {
@@ -184,37 +218,48 @@ fn write_run_file_after_calibration(params: CalibrateParameters) -> std::io::Res
         ]
         .concat(),
     )?;
-    return Ok(());
+    Ok(())
 }
 
-fn prepare_for_calibration(
-    handle: &DeviceHandle<GlobalContext>,
-) -> rusb::Result<CalibrateParameters> {
+fn prepare_for_calibration(handle: &dyn Transport) -> rusb::Result<CalibrateParameters> {
     let params =
         set_calibrate_parameters_from_file(handle, "./input_output_files/CalibrateInput.txt")?;
     set_microstepping(handle, 50)?;
     set_movement_type(handle, "inc")?;
-    set_pulse_position(handle, 0)?;
-    set_encoder_position(handle, 0)?;
-    return Ok(params);
+    match &params.home {
+        Some(settings) => home(handle, settings)?,
+        None => {
+            set_pulse_position(handle, 0)?;
+            set_encoder_position(handle, 0)?;
+        }
+    }
+    Ok(params)
 }
 
 fn calibration_loop(
-    handle: &DeviceHandle<GlobalContext>,
+    handle: &dyn Transport,
     params: &mut CalibrateParameters,
+    progress: &mut dyn FnMut(&CalibrateProgress),
 ) -> rusb::Result<()> {
     let mut times: Vec<f64> = vec![0.0; params.averaging_cycles as usize];
+    let mut iteration = 0;
 
     while params.time < params.min_period || params.time > params.max_period {
-        for i in 0..params.averaging_cycles as usize {
-            times[i] =
-                move_cycle_get_time(handle, params.amplitude, &mut None, None, params.dwell_time)?;
+        for time in times.iter_mut() {
+            *time = move_cycle_get_time(handle, params.amplitude, &mut None, params.dwell_time)?;
         }
 
         params.time = get_average_of_vector(&times);
         params.hspd = adjust_speed(handle, params)?;
 
         println!("{}\n{}\n", params.time, params.hspd);
+        iteration += 1;
+        progress(&CalibrateProgress {
+            iteration,
+            average_period: params.time,
+            target_period: params.period,
+            hspd: params.hspd,
+        });
 
         // This is really here to make sure the machine doesn't go crazy
         // I'm not really worried if the speed get too low, the user
@@ -224,19 +269,38 @@ fn calibration_loop(
             return Err(rusb::Error::Overflow); // Probably a better error for this
         }
     }
-    return Ok(());
+    Ok(())
+}
+
+/// Adjusts the high speed until a cycle from `input_output_files/CalibrateInput.txt` takes
+/// `Period` s, then writes `input_output_files/RunInput_calibrated.txt`.
+pub fn calibrate(handle: &dyn Transport) -> rusb::Result<()> {
+    calibrate_with_progress(handle, &mut |_| {})
 }
 
-pub fn calibrate(handle: &DeviceHandle<GlobalContext>) -> rusb::Result<()> {
+/// Same as calibrate, calling `progress` after every speed adjustment.
+pub fn calibrate_with_progress(
+    handle: &dyn Transport,
+    progress: &mut dyn FnMut(&CalibrateProgress),
+) -> rusb::Result<()> {
+    let _abort_scope = AbortScope::enter();
     let mut params = prepare_for_calibration(handle)?; // Initialize params and set some important
                                                        // motor controls
     params.min_period = params.period * params.tolerance;
     params.max_period = params.period * (2.0 - params.tolerance);
 
-    calibration_loop(handle, &mut params)?;
+    if let Err(e) = calibration_loop(handle, &mut params, progress) {
+        if e == rusb::Error::Interrupted {
+            println!(
+                "Calibration aborted, last average period {} at high speed {}. Nothing was written.",
+                params.time, params.hspd
+            );
+        }
+        return Err(e);
+    }
 
     println!("Calibration complete. Parameters outputted to 'RunInput_calibrated.txt'");
 
     write_run_file_after_calibration(params).unwrap();
-    return Ok(());
+    Ok(())
 }
@@ -0,0 +1,314 @@
+//! The controller commands interactive mode knows about, with their ranges and help text.
+//! Commands that aren't in here are still sent, the controller answers ?COMMAND if it
+//! doesn't know them either.
+
+/// How a command is written.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Syntax {
+    /// Just the name, e.g. STOP.
+    Action,
+    /// NAME reads it, NAME=value sets it. The range is inclusive, None isn't range checked.
+    Variable(Option<(i64, i64)>),
+    /// NAME reads it and there's no setting it, e.g. MST.
+    ReadOnly,
+    /// NAME reads the position, NAMEvalue moves there, e.g. X1000.
+    Move,
+}
+
+/// One controller command.
+#[derive(Debug, Clone, Copy)]
+pub struct CommandInfo {
+    pub name: &'static str,
+    pub syntax: Syntax,
+    pub unit: &'static str,
+    pub description: &'static str,
+    // Only takes effect after RW, see HELP RW
+    pub driver_parameter: bool,
+}
+
+const fn command(name: &'static str, syntax: Syntax, description: &'static str) -> CommandInfo {
+    CommandInfo {
+        name,
+        syntax,
+        unit: "",
+        description,
+        driver_parameter: false,
+    }
+}
+
+const fn variable(
+    name: &'static str,
+    range: Option<(i64, i64)>,
+    unit: &'static str,
+    description: &'static str,
+) -> CommandInfo {
+    CommandInfo {
+        name,
+        syntax: Syntax::Variable(range),
+        unit,
+        description,
+        driver_parameter: false,
+    }
+}
+
+const fn driver_parameter(
+    name: &'static str,
+    range: (i64, i64),
+    unit: &'static str,
+    description: &'static str,
+) -> CommandInfo {
+    CommandInfo {
+        name,
+        syntax: Syntax::Variable(Some(range)),
+        unit,
+        description,
+        driver_parameter: true,
+    }
+}
+
+const SPEED: Option<(i64, i64)> = Some((1, u32::MAX as i64));
+const TIME: Option<(i64, i64)> = Some((0, u32::MAX as i64));
+const POSITION: Option<(i64, i64)> = Some((i32::MIN as i64, i32::MAX as i64));
+
+/// Every command interactive mode knows about, roughly in the order you'd need them.
+pub const COMMANDS: &[CommandInfo] = &[
+    command("STOP", Syntax::Action, "Decelerate to a stop."),
+    command("ABORT", Syntax::Action, "Stop immediately without decelerating."),
+    command("X", Syntax::Move, "X reads the pulse position, X1000 moves to (ABS) or by (INC) 1000 pulses."),
+    command("J+", Syntax::Action, "Jog in the positive direction until STOP (or the soft limit, see LIMITS)."),
+    command("J-", Syntax::Action, "Jog in the negative direction until STOP (or the soft limit, see LIMITS)."),
+    command("H+", Syntax::Action, "Home against the home switch, searching in the positive direction. Doesn't zero EX, 'home' at the main prompt does."),
+    command("H-", Syntax::Action, "Home against the home switch, searching in the negative direction."),
+    command("HL+", Syntax::Action, "Home against the home switch at low speed, searching in the positive direction."),
+    command("HL-", Syntax::Action, "Home against the home switch at low speed, searching in the negative direction."),
+    command("L+", Syntax::Action, "Home against the positive limit switch. Leaves the limit error set, CLR clears it."),
+    command("L-", Syntax::Action, "Home against the negative limit switch. Leaves the limit error set, CLR clears it."),
+    command("ZH+", Syntax::Action, "Home against the home switch then the encoder index, searching in the positive direction."),
+    command("ZH-", Syntax::Action, "Home against the home switch then the encoder index, searching in the negative direction."),
+    command("ABS", Syntax::Action, "Make X moves absolute."),
+    command("INC", Syntax::Action, "Make X moves incremental."),
+    variable("HSPD", SPEED, "pulses/s", "High (running) speed."),
+    variable("LSPD", SPEED, "pulses/s", "Low (starting) speed."),
+    variable("ACC", TIME, "ms", "Acceleration time."),
+    variable("DEC", TIME, "ms", "Deceleration time."),
+    variable("EO", Some((0, 1)), "", "Motor power, 1 is on and 0 is off. Nothing holds the stage when it's off."),
+    variable("PX", POSITION, "pulses", "Pulse position. Setting it doesn't move anything, it changes where zero is."),
+    variable("EX", POSITION, "counts", "Encoder position. Setting it doesn't move anything, it changes where zero is."),
+    command("MST", Syntax::ReadOnly, "Motor status, 0 when idle. Bits 0-2 are constant speed, accelerating and decelerating, 3-5 the home, minus and plus limit inputs, 6-7 minus and plus limit errors, 8 latch, 9 Z index and 10 the TOC time-out."),
+    command("CLR", Syntax::Action, "Clear the limit errors in MST, move off the limit switch afterwards."),
+    command("DI", Syntax::ReadOnly, "Digital inputs, bit 0 is input 1."),
+    variable("DO", Some((0, u32::MAX as i64)), "", "Digital outputs, bit 0 is output 1. DO=5 turns on outputs 1 and 3 and the rest off."),
+    variable("SR", Some((0, 1)), "", "Standalone program, SR=1 runs the stored program and SR=0 stops it."),
+    command("SASTAT", Syntax::ReadOnly, "Standalone program status, 0 stopped, 1 running, 2 paused and 4 error. V1 to V100 are its variables."),
+    driver_parameter("DRVIC", (100, 2800), "mA", "Idle current."),
+    driver_parameter("DRVRC", (100, 3000), "mA", "Run current. Raise it if moves sputter instead of running smoothly."),
+    driver_parameter("DRVIT", (1, 100), "cs", "Time after a move before dropping to the idle current."),
+    driver_parameter("DRVMS", (2, 500), "microsteps/step", "Microstepping."),
+    command("RR", Syntax::Action, "Read the driver parameters from the driver. Wait 2 s, then check it worked with R2."),
+    command("R2", Syntax::ReadOnly, "Whether the last RR worked, 1 means it did."),
+    command("RW", Syntax::Action, "Write the driver parameters to the driver. Wait 2 s, then check it worked with R4. Turns the motor off, EO=1 turns it back on."),
+    command("R4", Syntax::ReadOnly, "Whether the last RW worked, 1 means it did."),
+];
+
+/// Commands interactive mode handles itself rather than sending, as (usage, description).
+pub const LOCAL_COMMANDS: &[(&str, &str)] = &[
+    ("EXIT", "Leave interactive mode."),
+    (
+        "HELP [COMMAND]",
+        "List the commands, or the range and description of one.",
+    ),
+    (
+        "LIMITS [min max | OFF]",
+        "Show, set or remove the soft travel limits in pulses.",
+    ),
+    (
+        "WATCHDOG [s [pulses]]",
+        "Show or set when jogs are stopped, after s seconds or travelling more than pulses.",
+    ),
+    (
+        "INTERLOCK [input | OFF]",
+        "Show, set or remove the input that has to stay on while the motor moves.",
+    ),
+    (
+        "WAIT_INPUT input [edge] [timeout]",
+        "Wait for an input to turn on (rising, the default), off (falling) or either (any).",
+    ),
+    (
+        "@file",
+        "Run a script file, see the script module docs or the README.",
+    ),
+];
+
+/// Looks a command up by name, case insensitive.
+pub fn find_command(name: &str) -> Option<&'static CommandInfo> {
+    COMMANDS
+        .iter()
+        .find(|info| info.name.eq_ignore_ascii_case(name))
+}
+
+/// Checks a command before it is sent, returning why it's wrong. Commands that aren't in the
+/// table pass as long as they look like a command, they might be ones we just don't know
+/// about.
+pub fn validate_command(command: &str) -> Result<(), String> {
+    let (name, value) = match command.split_once('=') {
+        Some((name, value)) => (name.trim(), Some(value.trim())),
+        None => (command.trim(), None),
+    };
+
+    let info = match find_command(name) {
+        Some(info) => info,
+        None => {
+            // X1000 and friends, the value is stuck to the name
+            if let Some(position) = name.strip_prefix(['X', 'x']) {
+                if value.is_none() && position.parse::<i32>().is_err() {
+                    return Err(format!(
+                        "X needs a whole number of pulses, got '{}'",
+                        position
+                    ));
+                }
+            }
+            // Controller commands are letters and digits with maybe a direction, anything
+            // else is a typo or something meant for us
+            if name.is_empty()
+                || !name
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || c == '+' || c == '-')
+            {
+                return Err(format!("'{}' isn't a controller command", name));
+            }
+            return Ok(());
+        }
+    };
+
+    match (info.syntax, value) {
+        (_, None) => Ok(()),
+        (Syntax::Action, Some(_)) | (Syntax::ReadOnly, Some(_)) => {
+            Err(format!("{} doesn't take a value", info.name))
+        }
+        (Syntax::Move, Some(_)) => Err(format!("Write {0}1000 rather than {0}=1000", info.name)),
+        (Syntax::Variable(range), Some(value)) => {
+            let value: i64 = value
+                .parse()
+                .map_err(|_| format!("{} needs a whole number, got '{}'", info.name, value))?;
+            match range {
+                Some((min, max)) if !(min..=max).contains(&value) => Err(format!(
+                    "{} must be between {} and {}{}",
+                    info.name,
+                    min,
+                    max,
+                    unit_suffix(info.unit)
+                )),
+                _ => Ok(()),
+            }
+        }
+    }
+}
+
+fn unit_suffix(unit: &str) -> String {
+    match unit {
+        "" => String::new(),
+        unit => format!(" {}", unit),
+    }
+}
+
+/// Help for one command: how to write it, the range and what it does.
+pub fn command_help(info: &CommandInfo) -> String {
+    let usage = match info.syntax {
+        Syntax::Action | Syntax::ReadOnly => info.name.to_string(),
+        Syntax::Move => format!("{0}  or  {0}<pulses>", info.name),
+        Syntax::Variable(None) => format!("{0}  or  {0}=<value>", info.name),
+        Syntax::Variable(Some((min, max))) => {
+            format!(
+                "{0}  or  {0}=[{1}-{2}]{3}",
+                info.name,
+                min,
+                max,
+                unit_suffix(info.unit)
+            )
+        }
+    };
+    let mut help = format!("{}\n    {}", usage, info.description);
+    if info.driver_parameter {
+        help.push_str("\n    Driver parameter: RR reads it from the driver, RW writes it.");
+    }
+    help
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn checks_variables_set_with_equals() {
+        assert_eq!(validate_command("HSPD=1000"), Ok(()));
+        assert_eq!(validate_command("hspd = 1000"), Ok(()));
+        assert_eq!(validate_command("PX=-50"), Ok(()));
+        assert_eq!(
+            validate_command("HSPD=fast"),
+            Err("HSPD needs a whole number, got 'fast'".to_string())
+        );
+        assert_eq!(
+            validate_command("DRVRC=50"),
+            Err("DRVRC must be between 100 and 3000 mA".to_string())
+        );
+        assert_eq!(validate_command("DRVRC=3000"), Ok(()));
+        assert_eq!(
+            validate_command("EO=2"),
+            Err("EO must be between 0 and 1".to_string())
+        );
+    }
+
+    #[test]
+    fn queries_and_actions_dont_take_values() {
+        // Reading a variable or a read only command
+        assert_eq!(validate_command("HSPD"), Ok(()));
+        assert_eq!(validate_command("MST"), Ok(()));
+        assert_eq!(validate_command("STOP"), Ok(()));
+        assert_eq!(
+            validate_command("MST=1"),
+            Err("MST doesn't take a value".to_string())
+        );
+        assert_eq!(
+            validate_command("ABORT=1"),
+            Err("ABORT doesn't take a value".to_string())
+        );
+    }
+
+    #[test]
+    fn moves_have_the_position_stuck_on() {
+        assert_eq!(validate_command("X"), Ok(()));
+        assert_eq!(validate_command("X1000"), Ok(()));
+        assert_eq!(validate_command("x-1000"), Ok(()));
+        assert_eq!(
+            validate_command("X=1000"),
+            Err("Write X1000 rather than X=1000".to_string())
+        );
+        assert_eq!(
+            validate_command("X10.5"),
+            Err("X needs a whole number of pulses, got '10.5'".to_string())
+        );
+    }
+
+    #[test]
+    fn rejects_what_cant_be_a_command() {
+        assert_eq!(
+            validate_command(""),
+            Err("'' isn't a controller command".to_string())
+        );
+        assert_eq!(
+            validate_command("=5"),
+            Err("'' isn't a controller command".to_string())
+        );
+        assert_eq!(
+            validate_command("LIMITS 0 100"),
+            Err("'LIMITS 0 100' isn't a controller command".to_string())
+        );
+        assert_eq!(
+            validate_command("HSPD;"),
+            Err("'HSPD;' isn't a controller command".to_string())
+        );
+        // Ones the table doesn't know still go to the controller
+        assert_eq!(validate_command("V1=5"), Ok(()));
+        assert_eq!(validate_command("ZL+"), Ok(()));
+    }
+}
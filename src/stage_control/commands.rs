@@ -1,5 +1,26 @@
-use crate::stage_control::driver::{
-    get_handle_from_vendor_product_id, read_from_bulk, saftey_read, write_to_bulk, write_to_control,
+//! One function per controller command, plus motion waits and interactive mode.
+
+use crate::stage_control::{
+    abort::{check_abort, sleep_or_abort, AbortScope},
+    command_table::{command_help, find_command, validate_command, COMMANDS, LOCAL_COMMANDS},
+    driver::{
+        discard_stale, get_handle_from_device_number, get_handle_from_serial_number,
+        get_handle_from_vendor_product_id, read_from_bulk, write_to_bulk, write_to_control,
+        Transport,
+    },
+    force::ForceMonitor,
+    io::{
+        enforce_interlock, get_interlock, parse_wait_input, set_interlock, wait_for_input_edge,
+        Interlock,
+    },
+    limits::{get_soft_limits, set_soft_limits, SoftLimits},
+    metrics::record_command,
+    motor_status::MotorStatus,
+    profile::get_device_profile,
+    recording::ReplayTransport,
+    script::run_script_with_watchdog,
+    units::UnitConversion,
+    watchdog::{JogWatchdog, WatchdogSettings},
 };
 
 use rusb::{DeviceHandle, GlobalContext, Result};
@@ -7,51 +28,143 @@ use rusb::{DeviceHandle, GlobalContext, Result};
 use std::{
     fs::File,
     io::{stdin, BufWriter, Write},
+    str::FromStr,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Mutex,
+    },
     time::{Duration, Instant},
 };
 
+// The controller can't tell us if it's in ABS or INC, so we keep track of the last one we
+// set. Needed to work out where a move will end up before it is sent.
+static ABSOLUTE_MOVES: AtomicBool = AtomicBool::new(true);
+
+// Commands and responses share one pair of endpoints, so two threads talking at once would
+// read each other's responses. Every command goes through send_command_get_response, so
+// that is where we lock.
+static BUS_LOCK: Mutex<()> = Mutex::new(());
+
+/// Finds the controller by vendor/product id, claims its interface and opens communication.
 pub fn open(vendor_id: u16, product_id: u16) -> Result<DeviceHandle<GlobalContext>> {
-    let mut handle = get_handle_from_vendor_product_id(vendor_id, product_id)?;
-    handle.claim_interface(0)?;
-    write_to_control(&handle, 2)?;
+    start_communication(get_handle_from_vendor_product_id(vendor_id, product_id)?)
+}
+
+/// Same as open, but picks the controller by its USB serial number.
+pub fn open_by_serial(
+    vendor_id: u16,
+    product_id: u16,
+    serial: &str,
+) -> Result<DeviceHandle<GlobalContext>> {
+    start_communication(get_handle_from_serial_number(
+        vendor_id, product_id, serial,
+    )?)
+}
+
+/// Same as open, but picks the `number`th (from 0) matching controller.
+pub fn open_by_number(
+    vendor_id: u16,
+    product_id: u16,
+    number: usize,
+) -> Result<DeviceHandle<GlobalContext>> {
+    start_communication(get_handle_from_device_number(
+        vendor_id, product_id, number,
+    )?)
+}
 
-    saftey_read(&handle)?;
+fn start_communication(
+    mut handle: DeviceHandle<GlobalContext>,
+) -> Result<DeviceHandle<GlobalContext>> {
+    handle.claim_interface(get_device_profile().interface)?;
+    begin_communication(&handle)?;
     Ok(handle)
 }
 
+/// Plays back a recording made with `recording::start_recording` instead of opening a
+/// controller, see the recording module. Goes through the same start up as open.
+pub fn open_replay(path: &str) -> Result<ReplayTransport> {
+    let replay = ReplayTransport::open(path).map_err(|e| {
+        eprintln!("Couldn't read the recording {}: {}", path, e);
+        rusb::Error::NotFound
+    })?;
+    begin_communication(&replay)?;
+    Ok(replay)
+}
+
+fn begin_communication(handle: &dyn Transport) -> Result<()> {
+    write_to_control(handle, get_device_profile().open_value)?;
+
+    discard_stale(handle)?;
+    // Whatever mode the last session left it in, start from one we know
+    set_movement_type(handle, "abs")?;
+    Ok(())
+}
+
+/// Tells the controller we're done with it (the profile's close value, 4 on the NSC-A1).
 // Notice we don't release the interface, rusb does that automatically when the
 // variable goes out of scope and it means we don't need the handle as mutable
-pub fn close(handle: &DeviceHandle<GlobalContext>) -> Result<()> {
-    write_to_control(handle, 4)?;
+pub fn close(handle: &dyn Transport) -> Result<()> {
+    write_to_control(handle, get_device_profile().close_value)?;
     Ok(())
 }
 
-pub fn send_command_get_response(
-    handle: &DeviceHandle<GlobalContext>,
-    command: &[u8],
-) -> Result<String> {
-    saftey_read(handle)?;
-    write_to_bulk(handle, command)?;
-    read_from_bulk(handle)
+/// Sends one ASCII command (null terminated) and returns the controller's reply.
+/// Safe to call from several threads, commands are never interleaved.
+pub fn send_command_get_response(handle: &dyn Transport, command: &[u8]) -> Result<String> {
+    // A thread that panicked mid-command can't leave the bus in a worse state than a
+    // timeout does, so don't let it poison every command after it
+    let _bus = BUS_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+    // Timed from here so waiting for another thread's command doesn't count as latency
+    let start = Instant::now();
+    // Responses don't say which command they're for, so anything already waiting would be
+    // taken for this one's
+    let response = discard_stale(handle)
+        .and_then(|_| write_to_bulk(handle, command))
+        .and_then(|_| read_from_bulk(handle));
+    record_command(start.elapsed(), response.as_ref().err());
+    response
+}
+
+/// Sends a command that reads back a number. A reply that isn't one, the response to an
+/// earlier command that turned up late for one, is an error rather than a panic.
+pub fn send_command_get_number<T: FromStr>(handle: &dyn Transport, command: &[u8]) -> Result<T> {
+    let response = send_command_get_response(handle, command)?;
+    response.parse().map_err(|_| {
+        let name = String::from_utf8_lossy(command);
+        eprintln!(
+            "Didn't understand {:?} from {}",
+            response,
+            name.trim_end_matches('\0')
+        );
+        rusb::Error::Other
+    })
 }
 
-pub fn write_driver_settings(handle: &DeviceHandle<GlobalContext>) -> Result<()> {
+/// Writes the driver parameters (DRVIC, DRVRC, DRVIT, DRVMS) with RW and checks it worked.
+pub fn write_driver_settings(handle: &dyn Transport) -> Result<()> {
     let _ = send_command_get_response(handle, b"RW\0")?;
-    std::thread::sleep(std::time::Duration::from_secs(3));
+    std::thread::sleep(get_device_profile().settle_time);
     check_driver_write(handle)?;
     Ok(())
 }
 
-pub fn check_driver_write(handle: &DeviceHandle<GlobalContext>) -> Result<()> {
+/// Reads the driver parameters back into the controller with RR so DRVMS, DRVIC, DRVRC and
+/// DRVIT report what the driver actually has.
+pub fn read_driver_settings(handle: &dyn Transport) -> Result<()> {
+    let _ = send_command_get_response(handle, b"RR\0")?;
+    std::thread::sleep(get_device_profile().settle_time);
+    Ok(())
+}
+
+/// Checks the last driver write with R4, anything but 1 is an error.
+pub fn check_driver_write(handle: &dyn Transport) -> Result<()> {
     let response = send_command_get_response(handle, b"R4\0")?;
     // This should probably error in a more breaking way. Bad driver writes can be bad
     if response != "1" {
-        writeln!(
-            std::io::stderr(),
+        eprintln!(
             "WARNING: Driver write failed, values may not be set, device responded '{}'",
             response
-        )
-        .unwrap();
+        );
         return Err(rusb::Error::Other);
     }
     Ok(())
@@ -59,7 +172,8 @@ pub fn check_driver_write(handle: &DeviceHandle<GlobalContext>) -> Result<()> {
 
 // Serious question, should this be unsigned? A negative high speed is not understood,
 // but this requires some casting later on. It seems safer to do this, but will see.
-pub fn set_high_speed(handle: &DeviceHandle<GlobalContext>, new_high_speed: u32) -> Result<()> {
+/// Sets the high (running) speed, HSPD, in pulses/s.
+pub fn set_high_speed(handle: &dyn Transport, new_high_speed: u32) -> Result<()> {
     let _ = send_command_get_response(
         handle,
         &[b"HSPD=", new_high_speed.to_string().as_bytes(), b"\0"].concat(),
@@ -67,7 +181,45 @@ pub fn set_high_speed(handle: &DeviceHandle<GlobalContext>, new_high_speed: u32)
     Ok(())
 }
 
-pub fn move_stage(handle: &DeviceHandle<GlobalContext>, position: i32) -> Result<()> {
+/// Works out where a move will end up and refuses it if that is outside the soft limits.
+/// Reads PX for incremental moves, so only does anything when limits are set.
+pub fn check_move_target(handle: &dyn Transport, position: i32) -> Result<()> {
+    let limits = match get_soft_limits() {
+        None => return Ok(()),
+        Some(limits) => limits,
+    };
+
+    let target = match ABSOLUTE_MOVES.load(Ordering::SeqCst) {
+        true => position,
+        false => {
+            let current = get_pulse_position(handle)?;
+            match current.checked_add(position) {
+                Some(target) => target,
+                None => {
+                    eprintln!(
+                        "Move rejected, {} pulses from {} is past the end of the position range",
+                        position, current
+                    );
+                    return Err(rusb::Error::InvalidParam);
+                }
+            }
+        }
+    };
+
+    if !limits.contains(target) {
+        eprintln!(
+            "Move rejected, target {} is outside the soft limits [{}, {}]",
+            target, limits.min, limits.max
+        );
+        return Err(rusb::Error::InvalidParam);
+    }
+    Ok(())
+}
+
+/// Moves to `position` (ABS) or by `position` (INC) with X.
+/// Rejected if it would leave the soft limits.
+pub fn move_stage(handle: &dyn Transport, position: i32) -> Result<()> {
+    check_move_target(handle, position)?;
     let _ = send_command_get_response(
         handle,
         &[b"X", position.to_string().as_bytes(), b"\0"].concat(),
@@ -75,7 +227,38 @@ pub fn move_stage(handle: &DeviceHandle<GlobalContext>, position: i32) -> Result
     Ok(())
 }
 
-pub fn set_low_speed(handle: &DeviceHandle<GlobalContext>, new_low_speed: u32) -> Result<()> {
+/// Starts a jog (J+ or J-) that runs until stopped. With soft limits set it becomes a move
+/// to the limit instead, and is rejected if the stage is already there.
+pub fn jog(handle: &dyn Transport, positive: bool) -> Result<()> {
+    let command = if positive { "J+" } else { "J-" };
+    match limit_interactive_command(handle, command)? {
+        None => Err(rusb::Error::InvalidParam),
+        Some(command) => {
+            let _ = send_command_get_response(handle, &[command.as_bytes(), b"\0"].concat())?;
+            Ok(())
+        }
+    }
+}
+
+/// Sends a raw command with the soft limits applied like in interactive mode: a move
+/// outside them is rejected with InvalidParam, as is a jog at the limit, and other jogs
+/// become a move to the limit. Homing and setting PX or EX are rejected while limits are set.
+/// Anything else is sent as is. Returns the response.
+pub fn send_command_within_limits(handle: &dyn Transport, command: &str) -> Result<String> {
+    let checked = command.trim().to_ascii_uppercase();
+    let response = match limit_interactive_command(handle, &checked)? {
+        None => return Err(rusb::Error::InvalidParam),
+        Some(limited) if limited != checked => {
+            send_command_get_response(handle, &[limited.as_bytes(), b"\0"].concat())?
+        }
+        Some(_) => send_command_get_response(handle, &[command.as_bytes(), b"\0"].concat())?,
+    };
+    track_movement_type(&checked);
+    Ok(response)
+}
+
+/// Sets the low (start/stop) speed, LSPD, in pulses/s.
+pub fn set_low_speed(handle: &dyn Transport, new_low_speed: u32) -> Result<()> {
     let _ = send_command_get_response(
         handle,
         &[b"LSPD=", new_low_speed.to_string().as_bytes(), b"\0"].concat(),
@@ -83,7 +266,13 @@ pub fn set_low_speed(handle: &DeviceHandle<GlobalContext>, new_low_speed: u32) -
     Ok(())
 }
 
-pub fn set_acceleration_time(handle: &DeviceHandle<GlobalContext>, time: u32) -> Result<()> {
+/// Reads the low speed, LSPD, in pulses/s.
+pub fn get_low_speed(handle: &dyn Transport) -> Result<u32> {
+    send_command_get_number(handle, b"LSPD\0")
+}
+
+/// Sets the acceleration time, ACC, in ms.
+pub fn set_acceleration_time(handle: &dyn Transport, time: u32) -> Result<()> {
     let _ = send_command_get_response(
         handle,
         &[b"ACC=", time.to_string().as_bytes(), b"\0"].concat(),
@@ -91,10 +280,8 @@ pub fn set_acceleration_time(handle: &DeviceHandle<GlobalContext>, time: u32) ->
     Ok(())
 }
 
-pub fn set_acceleration_profile(
-    handle: &DeviceHandle<GlobalContext>,
-    sin_trap: &str,
-) -> Result<()> {
+/// Sets the acceleration profile, `"sin"` for S-curve or `"trap"` for trapezoidal.
+pub fn set_acceleration_profile(handle: &dyn Transport, sin_trap: &str) -> Result<()> {
     let command = match sin_trap {
         "sin" => b"SCV=1\0",
         "trap" => b"SCV=0\0",
@@ -105,7 +292,8 @@ pub fn set_acceleration_profile(
     Ok(())
 }
 
-pub fn set_deceleration_time(handle: &DeviceHandle<GlobalContext>, time: u32) -> Result<()> {
+/// Sets the deceleration time, DEC, in ms.
+pub fn set_deceleration_time(handle: &dyn Transport, time: u32) -> Result<()> {
     let _ = send_command_get_response(
         handle,
         &[b"DEC=", time.to_string().as_bytes(), b"\0"].concat(),
@@ -113,8 +301,9 @@ pub fn set_deceleration_time(handle: &DeviceHandle<GlobalContext>, time: u32) ->
     Ok(())
 }
 
-pub fn set_idle_time(handle: &DeviceHandle<GlobalContext>, time: u32) -> Result<()> {
-    if time < 1 || time > 100 {
+/// Sets the time before dropping to idle current, DRVIT, 1-100 cs. Driver parameter.
+pub fn set_idle_time(handle: &dyn Transport, time: u32) -> Result<()> {
+    if !(1..=100).contains(&time) {
         return Err(rusb::Error::InvalidParam);
     }
 
@@ -125,14 +314,46 @@ pub fn set_idle_time(handle: &DeviceHandle<GlobalContext>, time: u32) -> Result<
     Ok(())
 }
 
-pub fn turn_motor_on(handle: &DeviceHandle<GlobalContext>) -> Result<()> {
+/// Energizes the motor (EO=1) and gives it the profile's settle time, 3 s on the NSC-A1.
+pub fn turn_motor_on(handle: &dyn Transport) -> Result<()> {
     let _ = send_command_get_response(handle, b"EO=1\0")?;
-    std::thread::sleep(Duration::from_secs(3));
+    std::thread::sleep(get_device_profile().settle_time);
+    Ok(())
+}
+
+/// Decelerates to a stop
+pub fn stop(handle: &dyn Transport) -> Result<()> {
+    let _ = send_command_get_response(handle, b"STOP\0")?;
+    Ok(())
+}
+
+/// Stops immediately without decelerating, may lose steps
+pub fn abort_motion(handle: &dyn Transport) -> Result<()> {
+    let _ = send_command_get_response(handle, b"ABORT\0")?;
+    Ok(())
+}
+
+/// De-energizes the motor (EO=0). Nothing holds the stage after this.
+pub fn turn_motor_off(handle: &dyn Transport) -> Result<()> {
+    let _ = send_command_get_response(handle, b"EO=0\0")?;
     Ok(())
 }
 
-pub fn set_microstepping(handle: &DeviceHandle<GlobalContext>, microsteps: u32) -> Result<()> {
-    if microsteps < 2 || microsteps > 500 {
+/// Whether the motor is energized (EO).
+pub fn get_motor_enabled(handle: &dyn Transport) -> Result<bool> {
+    match send_command_get_response(handle, b"EO\0")?.as_str() {
+        "1" => Ok(true),
+        "0" => Ok(false),
+        response => {
+            eprintln!("Didn't understand {:?} from EO", response);
+            Err(rusb::Error::Other)
+        }
+    }
+}
+
+/// Sets the microsteps per full step, DRVMS, 2-500. Driver parameter.
+pub fn set_microstepping(handle: &dyn Transport, microsteps: u32) -> Result<()> {
+    if !(2..=500).contains(&microsteps) {
         return Err(rusb::Error::InvalidParam); // TODO Certainly there is a better error than this
     }
 
@@ -143,8 +364,9 @@ pub fn set_microstepping(handle: &DeviceHandle<GlobalContext>, microsteps: u32)
     Ok(())
 }
 
-pub fn set_idle_current(handle: &DeviceHandle<GlobalContext>, current: u32) -> Result<()> {
-    if current < 100 || current > 2800 {
+/// Sets the idle current, DRVIC, 100-2800 mA. Driver parameter.
+pub fn set_idle_current(handle: &dyn Transport, current: u32) -> Result<()> {
+    if !(100..=2800).contains(&current) {
         return Err(rusb::Error::InvalidParam); // TODO Certainly there is a better error than this
     }
 
@@ -155,8 +377,9 @@ pub fn set_idle_current(handle: &DeviceHandle<GlobalContext>, current: u32) -> R
     Ok(())
 }
 
-pub fn set_run_current(handle: &DeviceHandle<GlobalContext>, current: u32) -> Result<()> {
-    if current < 100 || current > 3000 {
+/// Sets the run current, DRVRC, 100-3000 mA. Driver parameter.
+pub fn set_run_current(handle: &dyn Transport, current: u32) -> Result<()> {
+    if !(100..=3000).contains(&current) {
         return Err(rusb::Error::InvalidParam); // TODO Certainly there is a better error than this
     }
 
@@ -167,7 +390,34 @@ pub fn set_run_current(handle: &DeviceHandle<GlobalContext>, current: u32) -> Re
     Ok(())
 }
 
-pub fn set_movement_type(handle: &DeviceHandle<GlobalContext>, abs_inc: &str) -> Result<()> {
+// Driver parameters all read back as a plain number
+fn get_driver_parameter(handle: &dyn Transport, command: &[u8]) -> Result<u32> {
+    send_command_get_number(handle, command)
+}
+
+/// Reads the microsteps per full step, DRVMS. Call read_driver_settings first to get the
+/// driver's value rather than the last one set.
+pub fn get_microstepping(handle: &dyn Transport) -> Result<u32> {
+    get_driver_parameter(handle, b"DRVMS\0")
+}
+
+/// Reads the idle current, DRVIC, in mA.
+pub fn get_idle_current(handle: &dyn Transport) -> Result<u32> {
+    get_driver_parameter(handle, b"DRVIC\0")
+}
+
+/// Reads the run current, DRVRC, in mA.
+pub fn get_run_current(handle: &dyn Transport) -> Result<u32> {
+    get_driver_parameter(handle, b"DRVRC\0")
+}
+
+/// Reads the idle time, DRVIT, in cs.
+pub fn get_idle_time(handle: &dyn Transport) -> Result<u32> {
+    get_driver_parameter(handle, b"DRVIT\0")
+}
+
+/// Sets absolute (`"abs"`) or incremental (`"inc"`) moves.
+pub fn set_movement_type(handle: &dyn Transport, abs_inc: &str) -> Result<()> {
     let command = match abs_inc.to_ascii_lowercase().as_str() {
         "abs" => b"ABS\0",
         "inc" => b"INC\0",
@@ -175,17 +425,17 @@ pub fn set_movement_type(handle: &DeviceHandle<GlobalContext>, abs_inc: &str) ->
     };
 
     let _ = send_command_get_response(handle, command)?;
+    ABSOLUTE_MOVES.store(command == b"ABS\0", Ordering::SeqCst);
     Ok(())
 }
 
-pub fn get_high_speed(handle: &DeviceHandle<GlobalContext>) -> Result<u32> {
-    let response: u32 = send_command_get_response(handle, b"HSPD\0")?
-        .parse()
-        .unwrap();
-    Ok(response)
+/// Reads the high speed, HSPD, in pulses/s.
+pub fn get_high_speed(handle: &dyn Transport) -> Result<u32> {
+    send_command_get_number(handle, b"HSPD\0")
 }
 
-pub fn set_pulse_position(handle: &DeviceHandle<GlobalContext>, position: i32) -> Result<()> {
+/// Sets the current pulse position, PX.
+pub fn set_pulse_position(handle: &dyn Transport, position: i32) -> Result<()> {
     let _ = send_command_get_response(
         handle,
         &[b"PX=", position.to_string().as_bytes(), b"\0"].concat(),
@@ -193,7 +443,8 @@ pub fn set_pulse_position(handle: &DeviceHandle<GlobalContext>, position: i32) -
     Ok(())
 }
 
-pub fn set_encoder_position(handle: &DeviceHandle<GlobalContext>, position: i32) -> Result<()> {
+/// Sets the current encoder position, EX.
+pub fn set_encoder_position(handle: &dyn Transport, position: i32) -> Result<()> {
     let _ = send_command_get_response(
         handle,
         &[b"EX=", position.to_string().as_bytes(), b"\0"].concat(),
@@ -201,216 +452,601 @@ pub fn set_encoder_position(handle: &DeviceHandle<GlobalContext>, position: i32)
     Ok(())
 }
 
-pub fn get_pulse_position(handle: &DeviceHandle<GlobalContext>) -> Result<i32> {
-    let response: i32 = send_command_get_response(handle, b"PX\0")?.parse().unwrap();
-    Ok(response)
+/// Reads the pulse position, PX.
+pub fn get_pulse_position(handle: &dyn Transport) -> Result<i32> {
+    send_command_get_number(handle, b"PX\0")
 }
 
-pub fn get_encoder_position(handle: &DeviceHandle<GlobalContext>) -> Result<i32> {
-    let response: i32 = send_command_get_response(handle, b"EX\0")?.parse().unwrap();
-    Ok(response)
+/// Reads the encoder position, EX.
+pub fn get_encoder_position(handle: &dyn Transport) -> Result<i32> {
+    send_command_get_number(handle, b"EX\0")
+}
+
+/// Reads the motor status word, MST.
+pub fn get_motor_status(handle: &dyn Transport) -> Result<MotorStatus> {
+    Ok(MotorStatus::from_mst(send_command_get_number(
+        handle, b"MST\0",
+    )?))
 }
 
-pub fn get_motor_status(handle: &DeviceHandle<GlobalContext>) -> Result<i32> {
-    let response: i32 = send_command_get_response(handle, b"MST\0")?
-        .parse()
+/// Whether MST says the motor is moving. Input bits can stay set with the motor stopped,
+/// sitting on the home switch for one, so only the motion bits count.
+pub fn motor_is_moving(handle: &dyn Transport) -> Result<bool> {
+    Ok(get_motor_status(handle)?.is_moving())
+}
+
+// A limit or time-out stops the motor and latches a bit in MST. Waiting on that would carry on
+// as if the move had finished, so it's an error straight away.
+fn check_motor_fault(status: MotorStatus) -> Result<()> {
+    match status.fault() {
+        None => Ok(()),
+        Some(fault) => {
+            eprintln!(
+                "Motor stopped, {} (MST={} {})",
+                fault,
+                status.bits(),
+                status.describe()
+            );
+            Err(rusb::Error::Other)
+        }
+    }
+}
+
+/// Everything needed to log position while the motor is moving. Bundled together so the
+/// motion functions don't need another argument every time the output grows a column.
+pub struct PositionLog {
+    pub file: BufWriter<File>,
+    pub time: Instant, // Times in the file are measured from this
+    pub units: UnitConversion,
+    pub encoder: bool,               // Log EX too
+    pub force: Option<ForceMonitor>, // Log the newest force reading too
+}
+
+impl PositionLog {
+    /// Creates (truncates) the output file and writes the column header.
+    pub fn new(file_path: &str, units: UnitConversion) -> std::io::Result<PositionLog> {
+        PositionLog::with_columns(file_path, units, false, None)
+    }
+
+    /// Same as new with an encoder column and force columns (force and how old the reading is
+    /// compared to the line's time, in s) if asked for.
+    pub fn with_columns(
+        file_path: &str,
+        units: UnitConversion,
+        encoder: bool,
+        force: Option<ForceMonitor>,
+    ) -> std::io::Result<PositionLog> {
+        let mut file = BufWriter::new(File::create(file_path)?);
+        let mut header = "# time(s)\tposition(pulses)\tposition(mm)".to_string();
+        if encoder {
+            header.push_str("\tencoder(counts)\tencoder(mm)");
+        }
+        if let Some(force) = &force {
+            header.push_str(&format!("\tforce({})\tforce age(s)", force.unit));
+        }
+        file.write_all(format!("{}\n", header).as_bytes())?;
+        Ok(PositionLog {
+            file,
+            time: Instant::now(),
+            units,
+            encoder,
+            force,
+        })
+    }
+}
+
+/// Writes one line of time, pulse position and position in mm, then the encoder and force
+/// columns if the log has them. Force is nan until the sensor's first reading.
+pub fn output_time_pos_to_file(handle: &dyn Transport, log: &mut PositionLog) -> Result<()> {
+    let position = get_pulse_position(handle)?;
+    let time = Instant::now();
+    let counts = match log.encoder {
+        true => Some(get_encoder_position(handle)?),
+        false => None,
+    };
+    write_position_line(log, time, position, counts);
+    Ok(())
+}
+
+// `counts` is there when the log has an encoder column
+fn write_position_line(log: &mut PositionLog, time: Instant, position: i32, counts: Option<i32>) {
+    let mut line = format!(
+        "{}\t{}\t{}",
+        time.duration_since(log.time).as_secs_f64(),
+        position,
+        log.units.pulses_to_mm(position)
+    );
+    if let Some(counts) = counts {
+        line.push_str(&format!(
+            "\t{}\t{}",
+            counts,
+            log.units.encoder_to_mm(counts)
+        ));
+    }
+    if let Some(force) = &log.force {
+        match force.latest() {
+            Some(sample) => line.push_str(&format!(
+                "\t{}\t{}",
+                sample.force,
+                time.saturating_duration_since(sample.time).as_secs_f64()
+            )),
+            None => line.push_str("\tnan\tnan"),
+        }
+    }
+    log.file
+        .write_all(format!("{}\n", line).as_bytes())
         .unwrap();
-    Ok(response)
 }
 
-pub fn output_time_pos_to_file(
-    handle: &DeviceHandle<GlobalContext>,
-    file: &mut BufWriter<File>,
-    time: Instant,
+// Checked on every poll while the motor moves. Stops the motor dead the moment it leaves the
+// soft limits (a few lost steps are better than running into the frame) or the interlock goes
+// off. PX and EX are read once and shared with the log, every extra round trip is that much
+// longer before either is noticed.
+pub(crate) fn check_while_moving(
+    handle: &dyn Transport,
+    log: Option<&mut PositionLog>,
 ) -> Result<()> {
-    file.write_all(
-        &[
-            time.elapsed().as_secs_f64().to_string().as_bytes(),
-            b"\t",
-            get_pulse_position(handle)?.to_string().as_bytes(),
-            b"\n",
-        ]
-        .concat(),
-    )
-    .unwrap();
+    let limits = get_soft_limits();
+    let pulse_position = match limits.is_some() || log.is_some() {
+        true => Some(get_pulse_position(handle)?),
+        false => None,
+    };
+    let time = Instant::now();
+    let wants_encoder = limits.is_some_and(|limits| limits.encoder.is_some())
+        || log.as_ref().is_some_and(|log| log.encoder);
+    let encoder_position = match wants_encoder {
+        true => Some(get_encoder_position(handle)?),
+        false => None,
+    };
+
+    if let (Some(limits), Some(position)) = (limits, pulse_position) {
+        // EX is only checked with an encoder conversion, and then it has been read
+        if let Some(message) = limits.check_position(position, encoder_position.unwrap_or(0)) {
+            abort_motion(handle)?;
+            eprintln!("Soft limit tripped, motor stopped: {}", message);
+            return Err(rusb::Error::Other);
+        }
+    }
+    enforce_interlock(handle)?;
+    check_abort(handle)?;
+    if let (Some(log), Some(position)) = (log, pulse_position) {
+        write_position_line(log, time, position, encoder_position);
+    }
     Ok(())
 }
 
-pub fn wait_for_motor_idle(
-    handle: &DeviceHandle<GlobalContext>,
-    file: &mut Option<BufWriter<File>>,
-    time: Option<Instant>,
-) -> Result<()> {
-    match file {
-        None => while get_motor_status(handle)? != 0 {},
-        Some(file) => {
-            while get_motor_status(handle)? != 0 {
-                output_time_pos_to_file(handle, file, time.unwrap())?;
-            }
-            file.flush().unwrap();
+/// Polls MST until the motor stops, logging position if given a log. Stops early with an
+/// error if the soft limits are left, the interlock goes off, an abort comes in or the
+/// controller reports a limit or time-out fault.
+pub fn wait_for_motor_idle(handle: &dyn Transport, log: &mut Option<PositionLog>) -> Result<()> {
+    loop {
+        let status = get_motor_status(handle)?;
+        check_motor_fault(status)?;
+        if !status.is_moving() {
+            break;
         }
+        check_while_moving(handle, log.as_mut())?;
+    }
+    if let Some(log) = log {
+        log.file.flush().unwrap();
     }
     Ok(())
 }
 
+/// One down-then-up cycle of `distance` with `dwell` s after each move.
+/// Returns how long it took in s.
 pub fn move_cycle_get_time(
-    handle: &DeviceHandle<GlobalContext>,
+    handle: &dyn Transport,
     distance: i32,
-    file: &mut Option<BufWriter<File>>,
-    time: Option<Instant>,
+    log: &mut Option<PositionLog>,
+    dwell: f64,
+) -> Result<f64> {
+    move_cycle_get_time_with(handle, distance, distance, log, dwell, &mut |_, _| Ok(()))
+}
+
+/// Points in a load cycle something can happen at.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CyclePhase {
+    Start,  // Before the first move
+    Peak,   // The first move is done, before the dwell
+    Valley, // The second move is done, before the dwell
+}
+
+/// Same as move_cycle_get_time, calling `at_phase` at the start, the peak and the valley. The
+/// first move is `out` pulses down and the second `back` up, so the cycle can end somewhere
+/// else than it started. Time spent in `at_phase` counts towards the cycle time.
+pub fn move_cycle_get_time_with(
+    handle: &dyn Transport,
+    out: i32,
+    back: i32,
+    log: &mut Option<PositionLog>,
     dwell: f64,
+    at_phase: &mut dyn FnMut(CyclePhase, &mut Option<PositionLog>) -> Result<()>,
 ) -> Result<f64> {
     let cycle_time = Instant::now();
-    move_stage(handle, -distance)?;
-    wait_for_motor_idle(handle, file, time)?;
-    std::thread::sleep(Duration::from_secs_f64(dwell));
-    move_stage(handle, distance)?;
-    wait_for_motor_idle(handle, file, time)?;
-    std::thread::sleep(Duration::from_secs_f64(dwell));
+    at_phase(CyclePhase::Start, log)?;
+    move_stage(handle, -out)?;
+    wait_for_motor_idle(handle, log)?;
+    at_phase(CyclePhase::Peak, log)?;
+    sleep_or_abort(handle, Duration::from_secs_f64(dwell))?;
+    move_stage(handle, back)?;
+    wait_for_motor_idle(handle, log)?;
+    at_phase(CyclePhase::Valley, log)?;
+    sleep_or_abort(handle, Duration::from_secs_f64(dwell))?;
     Ok(cycle_time.elapsed().as_secs_f64())
 }
 
-pub fn move_cycle(handle: &DeviceHandle<GlobalContext>, distance: i32, dwell: f64) -> Result<()> {
+/// Same as move_cycle_get_time without logging or timing.
+pub fn move_cycle(handle: &dyn Transport, distance: i32, dwell: f64) -> Result<()> {
     move_stage(handle, -distance)?;
-    wait_for_motor_idle(handle, &mut None, None)?;
-    std::thread::sleep(std::time::Duration::from_secs_f64(dwell));
+    wait_for_motor_idle(handle, &mut None)?;
+    sleep_or_abort(handle, Duration::from_secs_f64(dwell))?;
     move_stage(handle, distance)?;
-    wait_for_motor_idle(handle, &mut None, None)?;
-    std::thread::sleep(std::time::Duration::from_secs_f64(dwell));
+    wait_for_motor_idle(handle, &mut None)?;
+    sleep_or_abort(handle, Duration::from_secs_f64(dwell))?;
     Ok(())
 }
 
-fn _print_interactive_mode_help_message() {
+fn print_interactive_help() {
     println!(
         "
-        DESCRIPTION:
-            You are currently in interactive mode. In this mode you can send commands which are
-            provided by the Newark stepper motor control box. These controls have two main structures:
-            simply 'COMMAND' and 'VAR=###'. The two methods are to control the device, and set variables
-            which control how it moves. 
+        Commands are sent straight to the controller, either 'COMMAND' or 'VAR=###', in upper or
+        lower case. Known commands are checked before they are sent, HELP COMMAND shows the range
+        and what it does. Tab completes command names and the up arrow goes through the history.
 
-            For example, if I want to move the device, I can send the command: J+ which will move the 
-            motor up indefinitely. If I want to control how quickly it moves, I would write: HSPD=1000
-            and it will move at a speed of 1000 pulses/second. Importantly, the device expects all caps
-            but the program automatically capitalizes all words so commands can be sent as either lower 
-            or upper case. ie hspd=1000 and HSPD=1000 will both work fine.
+        J+ and J- move until STOP. As a saftey percaution a watchdog sends STOP if a jog runs longer
+        than a timeout (10 s by default) or travels further than a maximum distance, see WATCHDOG.
+        It is still the users job to monitor the device while it is moving.
 
-            'J+' and 'J-' will move until a 'STOP' command is submitted. I want to implement a saftey 
-            percaution that automatically stops movement after some time. For now, it is the users job
-            to make sure they monitor the device while it is moving.
+        Driver parameters (DRVIC, DRVRC, DRVIT, DRVMS) only reach the driver through RR and RW, and
+        both can fail, see HELP RW.
 
-            There are special driver parameters, they are the idle and run current, idle time and microsteps.
-            If you want to either read, or write these variables you have to enter special commands that either
-            actually to the writting or reading. Additionally, driver reads and writes can fail. To tell if it 
-            failed there are other command that can be submitted to check. Run DW to write driver parameters and 
-            R4 to check the write. 1 means it was succesful, all else means it failed. Run RR to read driver 
-            parameters, R2 to check the read. 1 means it was successfull, all else means it failed. An example 
-            of how to first read a driver parameter, and change it is below in EXAMPLES section.
+        The manual has every command, these are the most important and useful ones.
+"
+    );
+    for (usage, description) in LOCAL_COMMANDS {
+        println!("        {:<24}{}", usage, description);
+    }
+    println!();
+    for info in COMMANDS {
+        println!("        {:<24}{}", info.name, info.description);
+    }
+    println!(
+        "
+        RETURN VALUES:
+            ?[command]  -> Command was not understood
+            ?Moving     -> A move or position change was sent while the motor is moving
+"
+    );
+}
 
-            The following Return Values sections shows what a command may return and what it means
+// The read/write dance is what trips people up, so RR and RW get the full example
+fn print_driver_parameter_example() {
+    println!(
+        "
+        EXAMPLE:
+            RR          ; Read all driver parameters, wait 2 seconds
+                        ; Returns OK, the command was understood, not that the read worked
+            R2          ; Returns 1 if the read worked
+            DRVIT       ; Returns 100, idles 100 cs before dropping to the idle current
+            DRVIT=200   ; Returns OK, but the driver hasn't got the value yet
+            RW          ; Actually write it, wait 2 seconds
+            R4          ; Returns 1 if the write worked
+            EO=1        ; Driver reads/writes turn off the motor, this turns it back on
+"
+    );
+}
 
-            There are many other commands and messages in the manual but these are the most important and useful
+fn print_command_help(name: &str) {
+    if let Some((usage, description)) = LOCAL_COMMANDS.iter().find(|(usage, _)| {
+        usage
+            .split_whitespace()
+            .next()
+            .is_some_and(|local| local.eq_ignore_ascii_case(name))
+    }) {
+        println!("{}\n    {}", usage, description);
+        return;
+    }
+    match find_command(name) {
+        Some(info) => {
+            println!("{}", command_help(info));
+            if matches!(info.name, "RR" | "RW" | "R2" | "R4") {
+                print_driver_parameter_example();
+            }
+        }
+        None => println!(
+            "No help for {}, it will still be sent. Section 10 of the manual has every command.",
+            name
+        ),
+    }
+}
 
-        COMMANDS:
-            EXIT        Exit interactive mode 
-            STOP        Stop motor movement
+pub(crate) fn set_limits_from_interactive_command(command: &str) {
+    let args: Vec<&str> = command.split_whitespace().skip(1).collect();
+    match args.as_slice() {
+        [] => match get_soft_limits() {
+            None => println!("No soft limits set"),
+            Some(limits) => println!("Soft limits [{}, {}]", limits.min, limits.max),
+        },
+        ["OFF"] => {
+            set_soft_limits(None);
+            println!("Soft limits removed");
+        }
+        [min, max] => match (min.parse::<i32>(), max.parse::<i32>()) {
+            (Ok(min), Ok(max)) if min < max => {
+                set_soft_limits(Some(SoftLimits {
+                    min,
+                    max,
+                    encoder: None,
+                }));
+                println!("Soft limits set to [{}, {}]", min, max);
+            }
+            _ => eprintln!("LIMITS needs two integers with min < max, got {:?}", args),
+        },
+        _ => eprintln!("Usage: LIMITS, LIMITS OFF or LIMITS min max"),
+    }
+}
 
-            X####       Move to pulse position ####
-            X           Get current pulse position
+// Homing commands, H+, HL-, L+, ZH- and so on
+fn is_homing_command(command: &str) -> bool {
+    match command.strip_suffix('+').or(command.strip_suffix('-')) {
+        Some(name) => ["H", "HL", "L", "Z", "ZH"].contains(&name),
+        None => false,
+    }
+}
 
-            J+          Move motor up until a stop command is issued
-            J-          Move motor down until a stop command is issued
+// Soft limit handling for motion typed in interactive mode. Returns the command that should
+// actually be sent, which for jogs is a move to the limit, or None if it was rejected.
+fn limit_interactive_command(handle: &dyn Transport, command: &str) -> Result<Option<String>> {
+    let limits = match get_soft_limits() {
+        None => return Ok(Some(command.to_string())),
+        Some(limits) => limits,
+    };
 
-            ACC=###     Set acceleration value to ###
-            ACC         Get current acceleration value 
+    if let Some(Ok(position)) = command.strip_prefix('X').map(str::parse::<i32>) {
+        return match check_move_target(handle, position) {
+            Ok(()) => Ok(Some(command.to_string())),
+            Err(rusb::Error::InvalidParam) => Ok(None),
+            Err(e) => Err(e),
+        };
+    }
 
-            HSPD=###    Set high speed
-            HSPD        Get current high speed
+    // Homing stops wherever the switch is, there's no target to check
+    if is_homing_command(command) {
+        eprintln!(
+            "{} rejected, homing can't be checked against the soft limits, LIMITS OFF first",
+            command
+        );
+        return Ok(None);
+    }
 
-            LSPD=###    Set low speed
-            LSPD        Get current low speed
+    // The limits are relative to zero, so moving zero moves them
+    if command.starts_with("PX=") || command.starts_with("EX=") {
+        eprintln!(
+            "{} rejected, it would move the soft limits with it, LIMITS OFF first",
+            command
+        );
+        return Ok(None);
+    }
 
-            INC         Set movement mode to incremental
-            ABS         Set movement mode to absolute
+    let (limit, already_there) = match command {
+        "J+" => (limits.max, get_pulse_position(handle)? >= limits.max),
+        "J-" => (limits.min, get_pulse_position(handle)? <= limits.min),
+        _ => return Ok(Some(command.to_string())),
+    };
+    if already_there {
+        eprintln!("{} rejected, already at the soft limit {}", command, limit);
+        return Ok(None);
+    }
 
-            EO=1        Turn motor on 
-            EO=0        Turn motor off
-            EO          Get motor status (1=on, 0=off)
+    let target = match ABSOLUTE_MOVES.load(Ordering::SeqCst) {
+        true => limit,
+        false => match limit.checked_sub(get_pulse_position(handle)?) {
+            Some(distance) => distance,
+            None => {
+                eprintln!(
+                    "{} rejected, the soft limit {} is too far away",
+                    command, limit
+                );
+                return Ok(None);
+            }
+        },
+    };
+    println!(
+        "{} limited to a move to the soft limit at {}",
+        command, limit
+    );
+    Ok(Some(format!("X{}", target)))
+}
 
-            EX=###      Set encoder position 
-            EX          Get encoder position
+pub(crate) fn set_watchdog_from_interactive_command(watchdog: &JogWatchdog, command: &str) {
+    let args: Vec<&str> = command.split_whitespace().skip(1).collect();
+    let settings = match args.as_slice() {
+        [] => {
+            let settings = watchdog.settings();
+            match settings.max_distance {
+                None => println!("Jogs stop after {} s", settings.timeout.as_secs_f64()),
+                Some(distance) => println!(
+                    "Jogs stop after {} s or {} pulses",
+                    settings.timeout.as_secs_f64(),
+                    distance
+                ),
+            }
+            return;
+        }
+        [timeout] => timeout.parse::<f64>().ok().map(|timeout| (timeout, None)),
+        [timeout, distance] => match (timeout.parse::<f64>(), distance.parse::<u32>()) {
+            (Ok(timeout), Ok(distance)) => Some((timeout, Some(distance))),
+            _ => None,
+        },
+        _ => None,
+    };
 
-            MST         Get motor status (0=motor running, 1=motor acceleration, 2=motor decelerating)
+    match settings {
+        Some((timeout, max_distance)) if timeout > 0.0 => {
+            watchdog.set_settings(WatchdogSettings {
+                timeout: Duration::from_secs_f64(timeout),
+                max_distance,
+            });
+            println!("Jog watchdog updated");
+        }
+        _ => eprintln!("Usage: WATCHDOG or WATCHDOG seconds [pulses], seconds > 0"),
+    }
+}
 
-            PX=###      Set current pulse position 
-            PX          Get current pulse position
+pub(crate) fn set_interlock_from_interactive_command(command: &str) {
+    let args: Vec<&str> = command.split_whitespace().skip(1).collect();
+    match args.as_slice() {
+        [] => match get_interlock() {
+            None => println!("No interlock set"),
+            Some(interlock) => println!("Motion stops if input {} goes off", interlock.input),
+        },
+        ["OFF"] => {
+            set_interlock(None);
+            println!("Interlock removed");
+        }
+        [input] => match input.parse::<u8>() {
+            Ok(input) if (1..=32).contains(&input) => {
+                set_interlock(Some(Interlock { input }));
+                println!("Motion stops if input {} goes off", input);
+            }
+            _ => eprintln!("INTERLOCK needs an input from 1 to 32, got {}", input),
+        },
+        _ => eprintln!("Usage: INTERLOCK, INTERLOCK OFF or INTERLOCK input"),
+    }
+}
 
-            DRVIC=[100-2800]mA  Set idle current (DRIVER PARAMETER)
-            DRVIC               Get idle current (DRIVER PARAMETER)
+// Ctrl-C gives up on the wait rather than quitting the loader
+fn wait_input_from_interactive_command(handle: &dyn Transport, command: &str) -> Result<()> {
+    let args: Vec<&str> = command.split_whitespace().skip(1).collect();
+    let (input, edge, timeout) = match parse_wait_input(&args) {
+        Ok(wait) => wait,
+        Err(e) => {
+            eprintln!("{}", e);
+            return Ok(());
+        }
+    };
 
-            DRVRC=[100-3000]mA  Set running current (DRIVER PARAMETER) 
-            DRVRC               Get running current (DRIVER PARAMETER) 
+    println!("Waiting for input {} ({:?})", input, edge);
+    let result = {
+        let _abort_scope = AbortScope::enter();
+        wait_for_input_edge(handle, input, edge, timeout)
+    };
+    match result {
+        Ok(true) => println!("Input {} changed", input),
+        Ok(false) => println!("Input {} didn't change in time", input),
+        Err(rusb::Error::Interrupted) => println!("Stopped waiting"),
+        Err(e) => return Err(e),
+    }
+    Ok(())
+}
 
-            DRVIT=[1-100]cs     Set idle time (DRIVER PARAMETER) 
-            DRVIT               Get idle time (DRIVER PARAMETER) 
+/// Reads commands from stdin and sends them straight to the controller until EXIT.
+pub fn interactive_mode(handle: &dyn Transport) -> Result<()> {
+    interactive_mode_with(handle, &mut || {
+        let mut line = String::new();
+        match stdin().read_line(&mut line) {
+            Ok(0) => None,
+            Ok(_n) => Some(line),
+            Err(e) => {
+                eprintln!("Failed to read line with error {}", e);
+                None
+            }
+        }
+    })
+}
 
-            DRVMS=[2-500]       Set microsteps (DRIVER PARAMETER) 
-            DRVMS               Get microsteps (DRIVER PARAMETER) 
+/// Interactive mode with the lines coming from `next_line` (a line editor, say) instead of
+/// stdin. Returning None leaves like EXIT does.
+// The jog watchdog runs on its own thread for as long as we're in here
+pub fn interactive_mode_with(
+    handle: &dyn Transport,
+    next_line: &mut dyn FnMut() -> Option<String>,
+) -> Result<()> {
+    println!("Entering interactive mode, HELP lists the commands\n");
+    let watchdog = JogWatchdog::new(WatchdogSettings::default());
 
+    watchdog.watch_while(handle, || interactive_loop(handle, &watchdog, next_line))?;
 
-        RETURN VALUES:
-            ?[command]  -> Command was not understood 
-            ?Moving     -> A move or position change was sent while the motor is moving 
-        
-        EXAMPLES:
-            Read and write driver parameter:
-                RR ; update all driver parameters, wait 2 seconds
-                    ; Returns OK    this means the command was understood, not that the read was sucessfull
-                R2 ; Make sure the read was succesful, wait 2 seconds
-                    ; Returns 1     Successful
-                DRVIT ; Check what the current idle time is 
-                    ; Returns 100   Idles 100 cs before saying it's done moving
-                DRVIT=200 ; Increase the idle time to 200 cs 
-                    ; Returns OK    Again, the command was understood, but the motor hasn't actually written the value 
-                RW  ; Actaully writes the 200cs value to DRVIT, wait 2 seconds
-                    ; Returns OK    Again Again, command was understood but the write may have failed 
-                R4  ; Check if the write was succeesfull
-                    ; Returns 1     Success 
-                EO=1    ; Driver reads/writes turn of the motor, this turns it back on
-                    ; Returns OK
+    println!("Exiting interactive mode\n");
+    Ok(())
+}
 
+/// Sends one command the way interactive mode does: checked against the command table and
+/// the soft limits, jogs handed to the watchdog and ABS/INC tracked. Returns the response, or
+/// None if the command wasn't sent (the reason is printed).
+pub(crate) fn send_interactive_command(
+    handle: &dyn Transport,
+    watchdog: &JogWatchdog,
+    command: &str,
+) -> Result<Option<String>> {
+    if let Err(reason) = validate_command(command) {
+        eprintln!("{} not sent: {}", command, reason);
+        return Ok(None);
+    }
+    let command = match limit_interactive_command(handle, command)? {
+        None => return Ok(None),
+        Some(command) => command,
+    };
+    let jog_start = match matches!(command.as_str(), "J+" | "J-") {
+        true => Some(get_pulse_position(handle)?),
+        false => None,
+    };
+    let response = send_command_get_response(handle, &[command.as_bytes(), b"\0"].concat())?;
+    if let Some(start_position) = jog_start {
+        watchdog.jog_started(&command, start_position);
+    }
+    track_movement_type(&command);
+    Ok(Some(response))
+}
 
-        "
-    );
+// Jogs limited to a move need to know whether X is absolute
+fn track_movement_type(command: &str) {
+    match command {
+        "ABS" => ABSOLUTE_MOVES.store(true, Ordering::SeqCst),
+        "INC" => ABSOLUTE_MOVES.store(false, Ordering::SeqCst),
+        _ => (),
+    }
 }
 
-pub fn interactive_mode(handle: &DeviceHandle<GlobalContext>) -> Result<()> {
-    writeln!(std::io::stdout(), "Entering interactive mode\n").unwrap();
-    let (mut raw_command, mut command, mut response): (String, String, String);
-    loop {
-        raw_command = String::new();
-        match stdin().read_line(&mut raw_command) {
-            Ok(_n) => (),
-            Err(e) => writeln!(std::io::stderr(), "Failed to read line with error {}", e).unwrap(),
+fn interactive_loop(
+    handle: &dyn Transport,
+    watchdog: &JogWatchdog,
+    next_line: &mut dyn FnMut() -> Option<String>,
+) -> Result<()> {
+    while let Some(line) = next_line() {
+        // The path keeps its case, everything else is upper cased for the controller
+        if let Some(path) = line.trim().strip_prefix('@') {
+            match run_script_with_watchdog(handle, watchdog, path.trim()) {
+                Ok(()) => (),
+                Err(
+                    e @ (rusb::Error::Other | rusb::Error::InvalidParam | rusb::Error::Interrupted),
+                ) => {
+                    eprintln!("Script stopped ({}), back to interactive mode", e)
+                }
+                Err(e) => return Err(e),
+            }
+            continue;
         }
 
-        command = raw_command.trim().to_ascii_uppercase();
-
-        match command.as_str() {
-            "EXIT" => break,
-            "HELP" => _print_interactive_mode_help_message(),
+        let command = line.trim().to_ascii_uppercase();
+        let words: Vec<&str> = command.split_whitespace().collect();
+
+        match words.as_slice() {
+            [] => (),
+            ["EXIT"] => break,
+            ["HELP"] => print_interactive_help(),
+            ["HELP", name] => print_command_help(name),
+            ["LIMITS", ..] => set_limits_from_interactive_command(&command),
+            ["WATCHDOG", ..] => set_watchdog_from_interactive_command(watchdog, &command),
+            ["INTERLOCK", ..] => set_interlock_from_interactive_command(&command),
+            ["WAIT_INPUT", ..] => wait_input_from_interactive_command(handle, &command)?,
             _ => {
-                response =
-                    send_command_get_response(handle, &[command.as_bytes(), b"\0"].concat())?;
-
-                // Move to begining of previous line then clear line
-                // Gives a psudo-way to delete the previous line
-                print!("\x1b[1F\x1b[2K");
-
-                println!("{} --> {}", command, response);
+                if let Some(response) = send_interactive_command(handle, watchdog, &command)? {
+                    println!("--> {}", response);
+                }
             }
         };
     }
-    println!("Exiting interactive mode\n");
     Ok(())
 }
@@ -1,37 +1,228 @@
+//! Raw USB communication with the controller.
+
+use crate::stage_control::{
+    metrics::record_stale_packets,
+    profile::get_device_profile,
+    recording::{record_transfer, TransferKind},
+};
+
 use rusb::{devices, DeviceDescriptor, DeviceHandle, GlobalContext};
 
-use std::{string::FromUtf8Error, time::Duration};
+use std::{
+    collections::BTreeMap,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
 
-const TIMEOUT: Duration = Duration::from_secs(3);
+// Most packets thrown away before a command, a controller that never stops talking is broken
+const MAX_DRAIN_PACKETS: usize = 64;
 
-// When a bulk_read returns, it will be a byte vector which will look like:
-// [#, #, #, 0, ...] where ... is garbage after the null byte we need to ignore.
-// This removes all the garbage after the null byte and then converts it to a String.
-fn byte_vec_to_string(byte_vec: &Vec<u8>) -> Result<String, FromUtf8Error> {
-    let mut string_vec: Vec<u8> = Vec::new();
+// Longest response put together from several packets, real ones are a few bytes
+const MAX_RESPONSE_LENGTH: usize = 1024;
 
-    for &i in byte_vec.iter() {
-        if i == 0 {
-            break;
-        }
-        string_vec.push(i);
+// Late responses (see Transport::late_responses) of real handles by libusb handle, the count
+// can't live in rusb's DeviceHandle. Only handles that are owed one are in here.
+static HANDLE_LATE_RESPONSES: Mutex<BTreeMap<usize, usize>> = Mutex::new(BTreeMap::new());
+
+/// USB vendor id of the NSC-A1.
+pub const NSC_A1_VENDOR_ID: u16 = 0x1589;
+/// USB product id of the NSC-A1.
+pub const NSC_A1_PRODUCT_ID: u16 = 0xa101;
+
+/// The three kinds of transfer the controller needs. The USB handle does them for real,
+/// `recording::ReplayTransport` plays a recorded session back instead.
+pub trait Transport: Send + Sync {
+    fn write_bulk(&self, endpoint: u8, buf: &[u8], timeout: Duration) -> rusb::Result<usize>;
+    fn read_bulk(&self, endpoint: u8, buf: &mut [u8], timeout: Duration) -> rusb::Result<usize>;
+    fn write_control(
+        &self,
+        request_type: u8,
+        request: u8,
+        value: u16,
+        index: u16,
+        buf: &[u8],
+        timeout: Duration,
+    ) -> rusb::Result<usize>;
+
+    /// Responses owed to commands on this transport that timed out. The controller can still
+    /// send them, and they'd be taken for the next command's response, so discard_stale
+    /// waits a short while for them first. Only a count, a response can't be tied to its
+    /// command, so one later than that still goes to the next command. Kept per transport so
+    /// one controller's timeouts don't slow another's commands.
+    fn late_responses(&self) -> usize;
+    fn set_late_responses(&self, late: usize);
+}
+
+impl Transport for DeviceHandle<GlobalContext> {
+    fn write_bulk(&self, endpoint: u8, buf: &[u8], timeout: Duration) -> rusb::Result<usize> {
+        DeviceHandle::write_bulk(self, endpoint, buf, timeout)
+    }
+
+    fn read_bulk(&self, endpoint: u8, buf: &mut [u8], timeout: Duration) -> rusb::Result<usize> {
+        DeviceHandle::read_bulk(self, endpoint, buf, timeout)
+    }
+
+    fn write_control(
+        &self,
+        request_type: u8,
+        request: u8,
+        value: u16,
+        index: u16,
+        buf: &[u8],
+        timeout: Duration,
+    ) -> rusb::Result<usize> {
+        DeviceHandle::write_control(self, request_type, request, value, index, buf, timeout)
+    }
+
+    fn late_responses(&self) -> usize {
+        let handles = HANDLE_LATE_RESPONSES.lock().unwrap();
+        handles.get(&(self.as_raw() as usize)).copied().unwrap_or(0)
+    }
+
+    fn set_late_responses(&self, late: usize) {
+        let mut handles = HANDLE_LATE_RESPONSES.lock().unwrap();
+        match late {
+            0 => handles.remove(&(self.as_raw() as usize)),
+            late => handles.insert(self.as_raw() as usize, late),
+        };
+    }
+}
+
+// So a shared handle can be passed wherever one is wanted
+impl<T: Transport + ?Sized> Transport for Arc<T> {
+    fn write_bulk(&self, endpoint: u8, buf: &[u8], timeout: Duration) -> rusb::Result<usize> {
+        (**self).write_bulk(endpoint, buf, timeout)
+    }
+
+    fn read_bulk(&self, endpoint: u8, buf: &mut [u8], timeout: Duration) -> rusb::Result<usize> {
+        (**self).read_bulk(endpoint, buf, timeout)
+    }
+
+    fn write_control(
+        &self,
+        request_type: u8,
+        request: u8,
+        value: u16,
+        index: u16,
+        buf: &[u8],
+        timeout: Duration,
+    ) -> rusb::Result<usize> {
+        (**self).write_control(request_type, request, value, index, buf, timeout)
     }
-    return String::from_utf8(string_vec);
+
+    fn late_responses(&self) -> usize {
+        (**self).late_responses()
+    }
+
+    fn set_late_responses(&self, late: usize) {
+        (**self).set_late_responses(late)
+    }
+}
+
+/// Sends a vendor control transfer with `value`. 2 opens communication, 4 closes it.
+pub fn write_to_control(handle: &dyn Transport, value: u16) -> rusb::Result<()> {
+    let profile = get_device_profile();
+    let result = handle.write_control(
+        profile.control_request_type,
+        profile.control_request,
+        value,
+        0,
+        &[],
+        profile.timeout,
+    );
+    record_transfer(TransferKind::Control, value, result.as_ref().err(), &[]);
+    result?;
+    Ok(())
 }
 
-pub fn write_to_control(handle: &DeviceHandle<GlobalContext>, value: u16) -> rusb::Result<()> {
-    let _ = handle.write_control(64, 2, value, 0, &[], TIMEOUT)?;
-    return Ok(());
+// One packet from the bulk in endpoint, only the bytes actually read
+fn read_packet(handle: &dyn Transport, timeout: Duration) -> rusb::Result<Vec<u8>> {
+    let endpoint = get_device_profile().endpoint_in;
+    let mut packet = vec![0u8; get_device_profile().packet_size];
+    let result = handle.read_bulk(endpoint, &mut packet, timeout);
+    let read = *result.as_ref().unwrap_or(&0);
+    record_transfer(
+        TransferKind::Read,
+        endpoint as u16,
+        result.as_ref().err(),
+        &packet[..read],
+    );
+    packet.truncate(result?);
+    Ok(packet)
 }
 
-pub fn saftey_read(handle: &DeviceHandle<GlobalContext>) -> rusb::Result<()> {
-    // This will almost always error (usually timout) and doesn't effect
-    // communication so all errors are ignored. We also don't care how many
-    // bytes were written. just full of apathy
-    let _ = handle.read_bulk(0x82, &mut [0u8; 64], TIMEOUT);
-    return Ok(());
+/// Throws away anything the controller sent that nobody read, so it isn't taken for the
+/// next response. Only waits the profile's drain_timeout for it (1 ms on the NSC-A1), unless
+/// a command timed out since the last call, then its response gets late_response_timeout to
+/// turn up. Returns how many packets were thrown away.
+pub fn discard_stale(handle: &dyn Transport) -> rusb::Result<usize> {
+    let profile = get_device_profile();
+    let mut discarded = 0;
+    while discarded < MAX_DRAIN_PACKETS {
+        let timeout = match handle.late_responses() {
+            0 => profile.drain_timeout,
+            _ => profile.late_response_timeout,
+        };
+        match read_packet(handle, timeout) {
+            Ok(packet) => {
+                discarded += 1;
+                if packet.contains(&0) {
+                    handle.set_late_responses(handle.late_responses().saturating_sub(1));
+                }
+            }
+            // Nothing (more) waiting. A late response that hasn't come by now isn't coming
+            Err(rusb::Error::Timeout) => {
+                handle.set_late_responses(0);
+                break;
+            }
+            Err(e) => return Err(e),
+        }
+    }
+    record_stale_packets(discarded);
+    Ok(discarded)
 }
 
+/// Reads one response, the text up to the null byte the controller ends it with. Long
+/// responses are put together from as many packets as it takes, anything after the null
+/// in the last packet is padding and ignored. Errors with Other if the response isn't text
+/// and Overflow if it never ends.
+pub fn read_from_bulk(handle: &dyn Transport) -> rusb::Result<String> {
+    let timeout = get_device_profile().timeout;
+    let mut response = Vec::new();
+    loop {
+        let packet = match read_packet(handle, timeout) {
+            Ok(packet) => packet,
+            Err(e) => {
+                // Whatever was on its way will turn up before the next command
+                if e == rusb::Error::Timeout {
+                    handle.set_late_responses(handle.late_responses() + 1);
+                }
+                return Err(e);
+            }
+        };
+        if let Some(end) = packet.iter().position(|&byte| byte == 0) {
+            response.extend_from_slice(&packet[..end]);
+            break;
+        }
+        response.extend_from_slice(&packet);
+        if response.len() > MAX_RESPONSE_LENGTH {
+            eprintln!(
+                "Controller response is over {} bytes without ending, starts {:?}",
+                MAX_RESPONSE_LENGTH,
+                String::from_utf8_lossy(&response[..32])
+            );
+            // The rest of it is stale now
+            handle.set_late_responses(handle.late_responses() + 1);
+            return Err(rusb::Error::Overflow);
+        }
+    }
+    String::from_utf8(response).map_err(|e| {
+        eprintln!("Controller response isn't text: {:02x?}", e.as_bytes());
+        rusb::Error::Other
+    })
+}
+
+/// Opens the first USB device with a matching vendor and product id.
 pub fn get_handle_from_vendor_product_id(
     vendor_id: u16,
     product_id: u16,
@@ -45,20 +236,193 @@ pub fn get_handle_from_vendor_product_id(
             return device.open();
         }
     }
-    return Err(rusb::Error::NotFound);
+    Err(rusb::Error::NotFound)
 }
 
-pub fn read_from_bulk(handle: &DeviceHandle<GlobalContext>) -> rusb::Result<String> {
-    let raw_output = &mut [0u8; 64].to_vec();
-    handle.read_bulk(0x82, raw_output, TIMEOUT)?;
-    return Ok(byte_vec_to_string(raw_output).unwrap());
+/// How many USB devices have a matching vendor and product id.
+pub fn count_devices(vendor_id: u16, product_id: u16) -> rusb::Result<usize> {
+    let mut count = 0;
+    for device in devices()?.iter() {
+        let device_desc: DeviceDescriptor = device.device_descriptor()?;
+        if device_desc.vendor_id() == vendor_id && device_desc.product_id() == product_id {
+            count += 1;
+        }
+    }
+    Ok(count)
 }
 
-pub fn write_to_bulk(handle: &DeviceHandle<GlobalContext>, command: &[u8]) -> rusb::Result<()> {
-    let bytes_written = handle.write_bulk(0x02, command, TIMEOUT)?;
+/// Opens the `number`th (from 0) device with a matching vendor and product id, in the order
+/// libusb lists them. The numbering is only stable while nothing is plugged in or out.
+pub fn get_handle_from_device_number(
+    vendor_id: u16,
+    product_id: u16,
+    number: usize,
+) -> rusb::Result<DeviceHandle<GlobalContext>> {
+    let mut matching = 0;
+    for device in devices()?.iter() {
+        let device_desc: DeviceDescriptor = device.device_descriptor()?;
+
+        if device_desc.vendor_id() != vendor_id || device_desc.product_id() != product_id {
+            continue;
+        }
+        if matching == number {
+            return device.open();
+        }
+        matching += 1;
+    }
+    Err(rusb::Error::NotFound)
+}
+
+/// Opens the device with a matching vendor and product id and USB serial number `serial`,
+/// for when more than one controller is plugged in.
+pub fn get_handle_from_serial_number(
+    vendor_id: u16,
+    product_id: u16,
+    serial: &str,
+) -> rusb::Result<DeviceHandle<GlobalContext>> {
+    let devices = devices()?;
+
+    for device in devices.iter() {
+        let device_desc: DeviceDescriptor = device.device_descriptor()?;
+
+        if device_desc.vendor_id() != vendor_id || device_desc.product_id() != product_id {
+            continue;
+        }
+        let handle = device.open()?;
+        // A device we can't read a serial number from just isn't the one we want
+        if handle
+            .read_serial_number_string_ascii(&device_desc)
+            .ok()
+            .as_deref()
+            == Some(serial)
+        {
+            return Ok(handle);
+        }
+    }
+    Err(rusb::Error::NotFound)
+}
+
+/// Writes a command to the bulk out endpoint, errors if it wasn't all written.
+pub fn write_to_bulk(handle: &dyn Transport, command: &[u8]) -> rusb::Result<()> {
+    let profile = get_device_profile();
+    let result = handle.write_bulk(profile.endpoint_out, command, profile.timeout);
+    record_transfer(
+        TransferKind::Write,
+        profile.endpoint_out as u16,
+        result.as_ref().err(),
+        command,
+    );
+    let bytes_written = result?;
 
     if bytes_written != command.len() {
         return Err(rusb::Error::Io);
     }
-    return Ok(());
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::stage_control::{
+        commands::{get_motor_status, get_pulse_position, send_command_get_response},
+        recording::{
+            script::{exchange, failed, read, write},
+            ReplayTransport, TransferKind,
+        },
+    };
+
+    #[test]
+    fn puts_a_response_together_from_several_packets() {
+        let first = [b'1'; 64];
+        let replay = ReplayTransport::new(vec![read(&first), read(b"23\0padding"), read(b"OK\0")]);
+        let expected = format!("{}23", String::from_utf8_lossy(&first));
+        assert_eq!(read_from_bulk(&replay), Ok(expected));
+        // Nothing after the null was kept for the next one
+        assert_eq!(read_from_bulk(&replay), Ok("OK".to_string()));
+        assert_eq!(replay.remaining(), 0);
+    }
+
+    #[test]
+    fn a_response_that_never_ends_overflows_and_is_owed() {
+        let packets = MAX_RESPONSE_LENGTH / 64 + 1;
+        let mut transfers: Vec<_> = (0..packets).map(|_| read(&[b'A'; 64])).collect();
+        // The end of it turns up before the next command and is thrown away
+        transfers.push(read(b"AA\0"));
+        transfers.extend(exchange("PX", "5"));
+        let replay = ReplayTransport::new(transfers);
+
+        assert_eq!(read_from_bulk(&replay), Err(rusb::Error::Overflow));
+        assert_eq!(replay.late_responses(), 1);
+        assert_eq!(
+            send_command_get_response(&replay, b"PX\0"),
+            Ok("5".to_string())
+        );
+        assert_eq!(replay.late_responses(), 0);
+        assert_eq!(replay.remaining(), 0);
+    }
+
+    #[test]
+    fn a_response_that_isnt_text_is_an_error() {
+        let replay = ReplayTransport::new(vec![read(&[0xff, 0xfe, b'1', 0])]);
+        assert_eq!(read_from_bulk(&replay), Err(rusb::Error::Other));
+        // A whole response arrived, nothing is owed
+        assert_eq!(replay.late_responses(), 0);
+    }
+
+    #[test]
+    fn late_responses_are_counted_per_transport() {
+        let mut transfers = exchange("PX", "1");
+        // The response times out, then turns up just before the next command
+        transfers.pop();
+        transfers.push(failed(TransferKind::Read, rusb::Error::Timeout));
+        transfers.push(read(b"1\0"));
+        transfers.extend(exchange("EX", "2"));
+        let timed_out = ReplayTransport::new(transfers);
+        let other = ReplayTransport::new(exchange("PX", "3"));
+
+        assert_eq!(
+            send_command_get_response(&timed_out, b"PX\0"),
+            Err(rusb::Error::Timeout)
+        );
+        assert_eq!(timed_out.late_responses(), 1);
+        // Another controller doesn't wait for it
+        assert_eq!(
+            send_command_get_response(&other, b"PX\0"),
+            Ok("3".to_string())
+        );
+        assert_eq!(other.late_responses(), 0);
+        assert_eq!(timed_out.late_responses(), 1);
+
+        // The late PX answer isn't taken for EX's
+        assert_eq!(
+            send_command_get_response(&timed_out, b"EX\0"),
+            Ok("2".to_string())
+        );
+        assert_eq!(timed_out.late_responses(), 0);
+        assert_eq!(timed_out.remaining(), 0);
+        assert_eq!(timed_out.mismatches(), 0);
+    }
+
+    #[test]
+    fn a_reply_that_isnt_a_number_is_an_error() {
+        // An OK owed to an earlier command, later than the wait for it
+        let mut transfers = exchange("PX", "OK");
+        transfers.extend(exchange("MST", ""));
+        let replay = ReplayTransport::new(transfers);
+        assert_eq!(get_pulse_position(&replay), Err(rusb::Error::Other));
+        assert_eq!(get_motor_status(&replay), Err(rusb::Error::Other));
+        assert_eq!(replay.remaining(), 0);
+    }
+
+    #[test]
+    fn discard_stale_stops_at_the_first_timeout() {
+        let replay = ReplayTransport::new(vec![
+            read(b"old\0"),
+            read(b"older\0"),
+            failed(TransferKind::Read, rusb::Error::Timeout),
+            write(b"PX\0"),
+        ]);
+        assert_eq!(discard_stale(&replay), Ok(2));
+        assert_eq!(replay.remaining(), 1);
+    }
 }
@@ -0,0 +1,479 @@
+//! Force from an external load cell. Amplifiers stream ASCII lines like `+0012.34 N`, a regex
+//! picks the number out and scale/offset turn it into force. The sensor is read on its own
+//! thread and the run output takes the newest reading every time it logs a position.
+
+use crate::stage_control::read_file::parse_value;
+
+use regex::Regex;
+
+use std::{
+    fs::File,
+    io::{BufRead, BufReader, ErrorKind},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+/// Anything that gives force readings, a load cell amplifier or a stand-in for one.
+pub trait ForceSensor: Send {
+    /// Blocks until the next reading, None once there are no more. A TimedOut error just
+    /// means nothing came yet and is worth trying again.
+    fn read_force(&mut self) -> std::io::Result<Option<f64>>;
+}
+
+/// Turns a line from the sensor into force: scale * number + offset.
+#[derive(Debug, Clone)]
+pub struct ForceParser {
+    pattern: Regex, // The first capture group is the number, or the whole match if there's none
+    scale: f64,
+    offset: f64,
+}
+
+// Any decimal number, with an exponent if the amplifier likes those
+const NUMBER_PATTERN: &str = r"[-+]?(?:\d+\.?\d*|\.\d+)(?:[eE][-+]?\d+)?";
+
+impl ForceParser {
+    pub fn new(pattern: &str, scale: f64, offset: f64) -> Result<ForceParser, regex::Error> {
+        Ok(ForceParser {
+            pattern: Regex::new(pattern)?,
+            scale,
+            offset,
+        })
+    }
+
+    /// None if the line doesn't match, e.g. a header or a status line.
+    pub fn parse(&self, line: &str) -> Option<f64> {
+        let captures = self.pattern.captures(line)?;
+        let number = captures.get(1).or_else(|| captures.get(0))?;
+        let value: f64 = number.as_str().trim().parse().ok()?;
+        Some(self.scale * value + self.offset)
+    }
+}
+
+impl Default for ForceParser {
+    fn default() -> Self {
+        ForceParser::new(NUMBER_PATTERN, 1.0, 0.0).unwrap()
+    }
+}
+
+// A line can be cut in half by a read timing out, so `line` keeps the start until the rest
+// comes in
+fn read_matching_line(
+    reader: &mut dyn BufRead,
+    parser: &ForceParser,
+    line: &mut String,
+) -> std::io::Result<Option<f64>> {
+    loop {
+        let read = reader.read_line(line)?;
+        if read == 0 {
+            // A file that doesn't end in a newline still has a last line
+            let force = parser.parse(line.trim());
+            line.clear();
+            return Ok(force);
+        }
+        if !line.ends_with('\n') {
+            continue;
+        }
+        let force = parser.parse(line.trim());
+        line.clear();
+        if force.is_some() {
+            return Ok(force);
+        }
+    }
+}
+
+/// A load cell amplifier on a serial port.
+#[cfg(feature = "serial")]
+pub struct SerialForceSensor {
+    reader: BufReader<Box<dyn serialport::SerialPort>>,
+    parser: ForceParser,
+    line: String,
+}
+
+#[cfg(feature = "serial")]
+impl SerialForceSensor {
+    /// `framing` is data bits, parity and stop bits, e.g. 8N1 or 7E1.
+    pub fn open(
+        path: &str,
+        baud: u32,
+        framing: &str,
+        parser: ForceParser,
+    ) -> serialport::Result<SerialForceSensor> {
+        use serialport::{DataBits, Parity, StopBits};
+
+        let bad_framing = || {
+            serialport::Error::new(
+                serialport::ErrorKind::InvalidInput,
+                format!("framing must look like 8N1, got {:?}", framing),
+            )
+        };
+        let framing = framing.to_ascii_uppercase();
+        let (data_bits, parity, stop_bits) = match framing.as_bytes() {
+            [data_bits, parity, stop_bits] => (data_bits, parity, stop_bits),
+            _ => return Err(bad_framing()),
+        };
+        let data_bits = match data_bits {
+            b'5' => DataBits::Five,
+            b'6' => DataBits::Six,
+            b'7' => DataBits::Seven,
+            b'8' => DataBits::Eight,
+            _ => return Err(bad_framing()),
+        };
+        let parity = match parity {
+            b'N' => Parity::None,
+            b'E' => Parity::Even,
+            b'O' => Parity::Odd,
+            _ => return Err(bad_framing()),
+        };
+        let stop_bits = match stop_bits {
+            b'1' => StopBits::One,
+            b'2' => StopBits::Two,
+            _ => return Err(bad_framing()),
+        };
+
+        // The timeout only decides how often the reading thread looks up to see if it should stop
+        let port = serialport::new(path, baud)
+            .data_bits(data_bits)
+            .parity(parity)
+            .stop_bits(stop_bits)
+            .timeout(Duration::from_millis(200))
+            .open()?;
+        Ok(SerialForceSensor {
+            reader: BufReader::new(port),
+            parser,
+            line: String::new(),
+        })
+    }
+}
+
+#[cfg(feature = "serial")]
+impl ForceSensor for SerialForceSensor {
+    fn read_force(&mut self) -> std::io::Result<Option<f64>> {
+        read_matching_line(&mut self.reader, &self.parser, &mut self.line)
+    }
+}
+
+/// Lines from a file or a pty in the same format as the amplifier, for trying things out
+/// without one. A recorded file can be played back at `interval` per line, a pty is read
+/// as lines come.
+pub struct FileForceSensor {
+    reader: BufReader<File>,
+    parser: ForceParser,
+    interval: Option<Duration>,
+    line: String,
+}
+
+impl FileForceSensor {
+    pub fn open(
+        path: &str,
+        interval: Option<Duration>,
+        parser: ForceParser,
+    ) -> std::io::Result<FileForceSensor> {
+        Ok(FileForceSensor {
+            reader: BufReader::new(File::open(path)?),
+            parser,
+            interval,
+            line: String::new(),
+        })
+    }
+}
+
+impl ForceSensor for FileForceSensor {
+    fn read_force(&mut self) -> std::io::Result<Option<f64>> {
+        if let Some(interval) = self.interval {
+            thread::sleep(interval);
+        }
+        read_matching_line(&mut self.reader, &self.parser, &mut self.line)
+    }
+}
+
+/// One force reading and when it arrived.
+#[derive(Debug, Clone, Copy)]
+pub struct ForceSample {
+    pub force: f64,
+    pub time: Instant,
+}
+
+/// Reads a sensor on its own thread and keeps the newest reading. Stops reading when dropped.
+pub struct ForceMonitor {
+    latest: Arc<Mutex<Option<ForceSample>>>,
+    stop: Arc<AtomicBool>,
+    pub unit: String, // Only for the column header
+}
+
+impl ForceMonitor {
+    pub fn start(mut sensor: Box<dyn ForceSensor>, unit: &str) -> ForceMonitor {
+        let latest = Arc::new(Mutex::new(None));
+        let stop = Arc::new(AtomicBool::new(false));
+        let (thread_latest, thread_stop) = (latest.clone(), stop.clone());
+        // Not joined, a pty with nothing coming can block for ever and that's fine
+        thread::spawn(move || {
+            while !thread_stop.load(Ordering::Relaxed) {
+                match sensor.read_force() {
+                    Ok(Some(force)) => {
+                        *thread_latest.lock().unwrap() = Some(ForceSample {
+                            force,
+                            time: Instant::now(),
+                        })
+                    }
+                    Ok(None) => break,
+                    Err(e) if e.kind() == ErrorKind::TimedOut => (),
+                    Err(e) => {
+                        eprintln!("Stopped reading the force sensor: {}", e);
+                        break;
+                    }
+                }
+            }
+        });
+        ForceMonitor {
+            latest,
+            stop,
+            unit: unit.to_string(),
+        }
+    }
+
+    /// The newest reading, None until the first one comes in.
+    pub fn latest(&self) -> Option<ForceSample> {
+        *self.latest.lock().unwrap()
+    }
+
+    /// Waits for a reading that arrived after `since`, None if none did within `timeout`.
+    pub fn reading_after(&self, since: Instant, timeout: Duration) -> Option<ForceSample> {
+        let start = Instant::now();
+        loop {
+            match self.latest() {
+                Some(sample) if sample.time > since => return Some(sample),
+                _ if start.elapsed() > timeout => return None,
+                _ => thread::sleep(Duration::from_millis(2)),
+            }
+        }
+    }
+}
+
+impl Drop for ForceMonitor {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+    }
+}
+
+/// Where force comes from
+#[derive(Debug, Clone, PartialEq)]
+pub enum ForceSource {
+    Serial(String), // Port name, /dev/ttyUSB0 or COM3
+    File(String),   // A recording or a pty
+}
+
+/// The Force... keys of a run file.
+#[derive(Debug, Clone)]
+pub struct ForceSettings {
+    pub source: Option<ForceSource>, // None leaves force out
+    pub baud: u32,
+    pub framing: String,
+    pub pattern: String,
+    pub scale: f64,
+    pub offset: f64,
+    pub unit: String,
+    pub interval: Option<Duration>, // Between lines of a file, None reads as fast as they come
+}
+
+impl Default for ForceSettings {
+    fn default() -> Self {
+        ForceSettings {
+            source: None,
+            baud: 9600,
+            framing: "8N1".to_string(),
+            pattern: NUMBER_PATTERN.to_string(),
+            scale: 1.0,
+            offset: 0.0,
+            unit: "N".to_string(),
+            interval: None,
+        }
+    }
+}
+
+/// Reads one of the Force... keys (lower case) into `settings`. `value` is the rest of the
+/// line so patterns can have spaces. Returns false if `key` isn't one of them.
+pub fn read_force_setting(
+    settings: &mut ForceSettings,
+    key: &str,
+    value: &str,
+) -> rusb::Result<bool> {
+    match key {
+        "forceport" => settings.source = Some(ForceSource::Serial(value.to_string())),
+        "forcefile" => settings.source = Some(ForceSource::File(value.to_string())),
+        "forcebaud" => settings.baud = parse_value(key, value)?,
+        "forceframing" => settings.framing = value.to_string(),
+        "forcepattern" => settings.pattern = value.to_string(),
+        "forcescale" => settings.scale = parse_value(key, value)?,
+        "forceoffset" => settings.offset = parse_value(key, value)?,
+        "forceunit" => settings.unit = value.to_string(),
+        "forceinterval" => {
+            settings.interval = Some(Duration::from_millis(parse_value(key, value)?))
+        }
+        _ => return Ok(false),
+    }
+    Ok(true)
+}
+
+impl ForceSettings {
+    /// Opens the sensor and starts reading it, None if there's no ForcePort or ForceFile.
+    pub fn start(&self) -> rusb::Result<Option<ForceMonitor>> {
+        let source = match &self.source {
+            None => return Ok(None),
+            Some(source) => source,
+        };
+        let parser = ForceParser::new(&self.pattern, self.scale, self.offset).map_err(|e| {
+            eprintln!("ForcePattern isn't a regex: {}", e);
+            rusb::Error::InvalidParam
+        })?;
+        let sensor: Box<dyn ForceSensor> = match source {
+            #[cfg(feature = "serial")]
+            ForceSource::Serial(path) => Box::new(
+                SerialForceSensor::open(path, self.baud, &self.framing, parser).map_err(|e| {
+                    eprintln!("Couldn't open the force sensor on {}: {}", path, e);
+                    rusb::Error::NotFound
+                })?,
+            ),
+            #[cfg(not(feature = "serial"))]
+            ForceSource::Serial(path) => {
+                eprintln!(
+                    "Built without the serial feature, can't open the force sensor on {}",
+                    path
+                );
+                return Err(rusb::Error::NotSupported);
+            }
+            ForceSource::File(path) => Box::new(
+                FileForceSensor::open(path, self.interval, parser).map_err(|e| {
+                    eprintln!("Couldn't open the force file {}: {}", path, e);
+                    rusb::Error::NotFound
+                })?,
+            ),
+        };
+        Ok(Some(ForceMonitor::start(sensor, &self.unit)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::io::Read;
+
+    // A recording from an amplifier, with the things that turn up between readings
+    const READINGS: &str = "\
+Load cell v2.1
++0012.34 N
+ST,GS,  -5.5 kg
+-1.5e1 N
+overload!
+   .25 N
+12.5 lb";
+
+    fn readings_file(name: &str) -> String {
+        let path = std::env::temp_dir().join(format!("loader_{}_{}.txt", name, std::process::id()));
+        std::fs::write(&path, READINGS).unwrap();
+        path.to_str().unwrap().to_string()
+    }
+
+    fn read_all(sensor: &mut dyn ForceSensor) -> Vec<f64> {
+        let mut forces = Vec::new();
+        while let Some(force) = sensor.read_force().unwrap() {
+            forces.push(force);
+        }
+        forces
+    }
+
+    #[test]
+    fn parses_numbers_with_scale_and_offset() {
+        let parser = ForceParser::new(NUMBER_PATTERN, 2.0, 1.0).unwrap();
+        assert_eq!(parser.parse("+0012.34 N"), Some(25.68));
+        assert_eq!(parser.parse("-1.5e1"), Some(-29.0));
+        assert_eq!(parser.parse("no number here"), None);
+        assert_eq!(parser.parse(""), None);
+    }
+
+    #[test]
+    fn reads_every_line_with_a_number_from_a_file() {
+        let path = readings_file("force_any");
+        let mut sensor = FileForceSensor::open(&path, None, ForceParser::default()).unwrap();
+        let forces = read_all(&mut sensor);
+        std::fs::remove_file(&path).unwrap();
+        // "v2.1" in the header counts too, the default pattern takes any number
+        assert_eq!(forces, vec![2.1, 12.34, -5.5, -15.0, 0.25, 12.5]);
+    }
+
+    #[test]
+    fn a_pattern_with_a_unit_skips_the_other_lines() {
+        let path = readings_file("force_newtons");
+        let parser = ForceParser::new(r"([-+]?[\d.]+(?:e[-+]?\d+)?)\s*N$", 1.0, 0.0).unwrap();
+        let mut sensor = FileForceSensor::open(&path, None, parser).unwrap();
+        let forces = read_all(&mut sensor);
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(forces, vec![12.34, -15.0, 0.25]);
+    }
+
+    // Hands out what it's given a piece at a time, timing out after each like a serial port
+    // that hasn't got the rest of the line yet
+    struct Trickle {
+        pieces: Vec<&'static str>,
+        timed_out: bool,
+    }
+
+    impl Read for Trickle {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            if self.pieces.is_empty() {
+                return Ok(0);
+            }
+            self.timed_out = !self.timed_out;
+            if self.timed_out {
+                return Err(ErrorKind::TimedOut.into());
+            }
+            let piece = self.pieces.remove(0).as_bytes();
+            buf[..piece.len()].copy_from_slice(piece);
+            Ok(piece.len())
+        }
+    }
+
+    #[test]
+    fn keeps_a_line_cut_in_half_by_a_timeout() {
+        let mut reader = BufReader::new(Trickle {
+            pieces: vec!["+12", ".5 N\n-3", " N\n"],
+            timed_out: false,
+        });
+        let parser = ForceParser::default();
+        let mut line = String::new();
+        let mut forces = Vec::new();
+        loop {
+            match read_matching_line(&mut reader, &parser, &mut line) {
+                Ok(Some(force)) => forces.push(force),
+                Ok(None) => break,
+                Err(e) => assert_eq!(e.kind(), ErrorKind::TimedOut),
+            }
+        }
+        assert_eq!(forces, vec![12.5, -3.0]);
+    }
+
+    #[test]
+    fn the_monitor_keeps_the_newest_reading() {
+        let path = readings_file("force_monitor");
+        let settings = ForceSettings {
+            source: Some(ForceSource::File(path.clone())),
+            ..Default::default()
+        };
+        let start = Instant::now();
+        let monitor = settings.start().unwrap().unwrap();
+        assert!(monitor
+            .reading_after(start, Duration::from_secs(5))
+            .is_some());
+        // The file runs out and the thread stops with the last line
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while monitor.latest().map(|sample| sample.force) != Some(12.5) {
+            assert!(Instant::now() < deadline, "{:?}", monitor.latest());
+            thread::sleep(Duration::from_millis(2));
+        }
+        std::fs::remove_file(&path).unwrap();
+    }
+}
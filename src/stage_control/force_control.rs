@@ -0,0 +1,258 @@
+//! Load controlled cycling. Instead of a fixed amplitude the peak and valley of each cycle are
+//! moved so the load cell reads ForceMax at the peak and ForceMin at the valley, and keep
+//! reading that as the specimen gets softer.
+
+use crate::stage_control::{limits::SoftLimits, read_file::parse_value};
+
+/// Forces to cycle between and how hard to chase them.
+#[derive(Debug, Clone, Copy)]
+pub struct ForceControl {
+    pub min: f64,      // Force at the valley, in the load cell's units
+    pub max: f64,      // Force at the peak
+    pub gain: f64,     // Fraction of the force error corrected per cycle, 1 is all of it
+    pub max_step: i32, // Most a target moves in one cycle, pulses
+}
+
+/// Where a cycle turns around, in pulses past where cycling started in the direction of the
+/// first move. The first cycle goes to Amplitude and back to 0.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct CycleTargets {
+    pub peak: i32,
+    pub valley: i32,
+}
+
+impl CycleTargets {
+    /// Both targets moved inside the soft limits, with the targets measured down from pulse
+    /// position `top`. None if that squashes the cycle flat, there'd be no stiffness to get
+    /// out of it.
+    pub fn within_limits(&self, top: i32, limits: &SoftLimits) -> Option<CycleTargets> {
+        // A target d pulses down is at pulse position top - d
+        let (min, max) = (
+            top.saturating_sub(limits.max),
+            top.saturating_sub(limits.min),
+        );
+        let clamped = CycleTargets {
+            peak: self.peak.clamp(min, max),
+            valley: self.valley.clamp(min, max),
+        };
+        match clamped.peak > clamped.valley {
+            true => Some(clamped),
+            false => None,
+        }
+    }
+}
+
+/// What the load cell read when the motor stopped at the peak and the valley.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct CycleForces {
+    pub peak: Option<f64>,
+    pub valley: Option<f64>,
+}
+
+impl ForceControl {
+    fn new(min: f64, max: f64) -> ForceControl {
+        ForceControl {
+            min,
+            max,
+            gain: 0.5,
+            max_step: 500,
+        }
+    }
+
+    /// Next cycle's targets from this cycle's. The specimen's stiffness comes from the two
+    /// ends of the cycle, each target is moved by gain * force error / stiffness. Returns
+    /// None and the targets stay put if a reading is missing or the two forces are too close
+    /// to get a stiffness from, a broken specimen looks like that.
+    pub fn next_targets(
+        &self,
+        targets: CycleTargets,
+        forces: CycleForces,
+    ) -> Option<(CycleTargets, f64)> {
+        let (peak_force, valley_force) = (forces.peak?, forces.valley?);
+        let travel = (targets.peak - targets.valley) as f64;
+        let stiffness = (peak_force - valley_force) / travel; // Force per pulse
+        if travel == 0.0
+            || !stiffness.is_finite()
+            || (peak_force - valley_force).abs() < (self.max - self.min).abs() * 0.01
+        {
+            return None;
+        }
+
+        let step = |error: f64| {
+            (self.gain * error / stiffness)
+                .round()
+                .clamp(-self.max_step as f64, self.max_step as f64) as i32
+        };
+        let peak = targets.peak + step(self.max - peak_force);
+        let valley = targets.valley + step(self.min - valley_force);
+        // Never let the cycle turn inside out
+        let valley = valley.min(peak - 1);
+        Some((CycleTargets { peak, valley }, stiffness))
+    }
+}
+
+/// The ForceMin/ForceMax/ForceGain/ForceMaxStep keys of a run file, turned into ForceControl
+/// once the whole file is read.
+#[derive(Debug, Default)]
+pub struct ForceControlSettings {
+    min: Option<f64>,
+    max: Option<f64>,
+    gain: Option<f64>,
+    max_step: Option<i32>,
+}
+
+impl ForceControlSettings {
+    /// Reads one key (lower case). Returns false if `key` isn't one of them.
+    pub fn read(&mut self, key: &str, value: &str) -> rusb::Result<bool> {
+        match key {
+            "forcemin" => self.min = Some(parse_value(key, value)?),
+            "forcemax" => self.max = Some(parse_value(key, value)?),
+            "forcegain" => self.gain = Some(parse_value(key, value)?),
+            "forcemaxstep" => self.max_step = Some(parse_value(key, value)?),
+            _ => return Ok(false),
+        }
+        Ok(true)
+    }
+
+    /// None without ForceMin and ForceMax, the run is displacement controlled then.
+    pub fn build(&self) -> rusb::Result<Option<ForceControl>> {
+        let (min, max) = match (self.min, self.max) {
+            (None, None) => return Ok(None),
+            (Some(min), Some(max)) if min != max => (min, max),
+            _ => {
+                eprintln!("Force control needs both ForceMin and ForceMax, and different ones");
+                return Err(rusb::Error::InvalidParam);
+            }
+        };
+        let mut control = ForceControl::new(min, max);
+        if let Some(gain) = self.gain {
+            control.gain = gain;
+        }
+        if let Some(max_step) = self.max_step {
+            control.max_step = max_step;
+        }
+        if !(control.gain > 0.0 && control.gain <= 1.0) || control.max_step < 1 {
+            eprintln!("ForceGain must be above 0 and at most 1, and ForceMaxStep at least 1");
+            return Err(rusb::Error::InvalidParam);
+        }
+        Ok(Some(control))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A specimen that reads 0.01 N per pulse down, cycled between 2 and 8 N
+    const STIFFNESS: f64 = 0.01;
+
+    fn forces(targets: CycleTargets) -> CycleForces {
+        CycleForces {
+            peak: Some(targets.peak as f64 * STIFFNESS),
+            valley: Some(targets.valley as f64 * STIFFNESS),
+        }
+    }
+
+    fn limits(min: i32, max: i32) -> SoftLimits {
+        SoftLimits {
+            min,
+            max,
+            encoder: None,
+        }
+    }
+
+    #[test]
+    fn converges_on_the_forces() {
+        let control = ForceControl::new(2.0, 8.0);
+        let mut targets = CycleTargets {
+            peak: 1000,
+            valley: 0,
+        };
+        for _ in 0..20 {
+            let (next, stiffness) = control.next_targets(targets, forces(targets)).unwrap();
+            assert!((stiffness - STIFFNESS).abs() < 1e-9);
+            targets = next;
+        }
+        assert!((targets.peak - 800).abs() <= 1, "{:?}", targets);
+        assert!((targets.valley - 200).abs() <= 1, "{:?}", targets);
+    }
+
+    #[test]
+    fn moves_at_most_max_step_a_cycle() {
+        let control = ForceControl {
+            gain: 1.0,
+            max_step: 50,
+            ..ForceControl::new(2.0, 8.0)
+        };
+        let targets = CycleTargets {
+            peak: 1000,
+            valley: 0,
+        };
+        let (next, _) = control.next_targets(targets, forces(targets)).unwrap();
+        assert_eq!(
+            next,
+            CycleTargets {
+                peak: 950,
+                valley: 50
+            }
+        );
+    }
+
+    #[test]
+    fn leaves_the_targets_without_usable_forces() {
+        let control = ForceControl::new(2.0, 8.0);
+        let targets = CycleTargets {
+            peak: 1000,
+            valley: 0,
+        };
+        let missing = CycleForces {
+            peak: None,
+            valley: Some(0.0),
+        };
+        assert_eq!(control.next_targets(targets, missing), None);
+        // A broken specimen reads the same at both ends
+        let flat = CycleForces {
+            peak: Some(0.01),
+            valley: Some(0.0),
+        };
+        assert_eq!(control.next_targets(targets, flat), None);
+    }
+
+    #[test]
+    fn clamps_the_targets_to_the_soft_limits() {
+        // Cycling down from pulse position 5000, the limits allow 4200 to 4900, so 100 to 800
+        // pulses down
+        let targets = CycleTargets {
+            peak: 900,
+            valley: 50,
+        };
+        assert_eq!(
+            targets.within_limits(5000, &limits(4200, 4900)),
+            Some(CycleTargets {
+                peak: 800,
+                valley: 100
+            })
+        );
+        // Already inside, nothing changes
+        let inside = CycleTargets {
+            peak: 700,
+            valley: 200,
+        };
+        assert_eq!(
+            inside.within_limits(5000, &limits(4200, 4900)),
+            Some(inside)
+        );
+    }
+
+    #[test]
+    fn stops_when_the_limits_squash_the_targets_together() {
+        let targets = CycleTargets {
+            peak: 900,
+            valley: 700,
+        };
+        // Nothing further down than 600 pulses, both targets end up there
+        assert_eq!(targets.within_limits(5000, &limits(4400, 4900)), None);
+        // A limit window a single pulse wide
+        assert_eq!(targets.within_limits(5000, &limits(4300, 4300)), None);
+    }
+}
@@ -0,0 +1,216 @@
+//! Homing against the controller's home switch, limit switches or encoder index, so zero is
+//! the same place every session instead of wherever the stage was left.
+
+use crate::stage_control::{
+    abort::check_abort,
+    commands::{
+        get_high_speed, get_low_speed, get_motor_status, move_stage, send_command_get_response,
+        set_encoder_position, set_high_speed, set_low_speed, set_pulse_position, stop,
+        wait_for_motor_idle,
+    },
+    driver::Transport,
+    motor_status::MotorStatus,
+    read_file::parse_value,
+};
+
+use rusb::Result;
+
+use std::{
+    thread::sleep,
+    time::{Duration, Instant},
+};
+
+const POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+// Same as for jogs, MST can still read idle right after the command before the motor starts
+const HOME_START_GRACE: Duration = Duration::from_millis(250);
+
+/// What the controller homes against
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum HomeMethod {
+    HomeSwitch,  // H+/H-
+    LimitSwitch, // L+/L-, the limit in the homing direction. HL+/HL- is the home switch at LSPD
+    HomeIndex,   // ZH+/ZH-, the home switch then the encoder's index pulse
+}
+
+/// How to home. The controller searches at HSPD and finishes at LSPD.
+#[derive(Debug, Clone, Copy)]
+pub struct HomeSettings {
+    pub method: HomeMethod,
+    pub positive: bool,          // Direction to search in
+    pub high_speed: Option<u32>, // pulses/s while homing, None uses the current HSPD
+    pub low_speed: Option<u32>,  // pulses/s while homing, None uses the current LSPD
+    pub offset: i32,             // Pulses to move after homing, zero ends up there
+    pub timeout: Duration,       // STOP and give up if homing takes longer
+}
+
+impl Default for HomeSettings {
+    fn default() -> Self {
+        HomeSettings {
+            method: HomeMethod::HomeSwitch,
+            positive: false,
+            high_speed: None,
+            low_speed: None,
+            offset: 0,
+            timeout: Duration::from_secs(60),
+        }
+    }
+}
+
+impl HomeSettings {
+    /// The controller command, e.g. H- or ZH+.
+    pub fn command(&self) -> String {
+        let method = match self.method {
+            HomeMethod::HomeSwitch => "H",
+            HomeMethod::LimitSwitch => "L",
+            HomeMethod::HomeIndex => "ZH",
+        };
+        let direction = match self.positive {
+            true => "+",
+            false => "-",
+        };
+        format!("{}{}", method, direction)
+    }
+}
+
+/// Reads one of the Home... keys (lower case) from a run or calibrate file into `settings`.
+/// Returns false if `key` isn't one of them.
+pub fn read_home_setting(settings: &mut HomeSettings, key: &str, value: &str) -> Result<bool> {
+    match key {
+        "homemethod" => {
+            settings.method = match value.to_ascii_lowercase().as_str() {
+                "switch" => HomeMethod::HomeSwitch,
+                "limit" => HomeMethod::LimitSwitch,
+                "index" => HomeMethod::HomeIndex,
+                _ => {
+                    eprintln!(
+                        "HomeMethod must be 'switch', 'limit' or 'index', got {:?}",
+                        value
+                    );
+                    return Err(rusb::Error::InvalidParam);
+                }
+            }
+        }
+        "homedirection" => {
+            settings.positive = match value {
+                "+" => true,
+                "-" => false,
+                _ => {
+                    eprintln!("HomeDirection must be '+' or '-', got {:?}", value);
+                    return Err(rusb::Error::InvalidParam);
+                }
+            }
+        }
+        "homehighspeed" => settings.high_speed = Some(parse_value(key, value)?),
+        "homelowspeed" => settings.low_speed = Some(parse_value(key, value)?),
+        "homeoffset" => settings.offset = parse_value(key, value)?,
+        "hometimeout" => settings.timeout = Duration::from_secs_f64(parse_value(key, value)?),
+        _ => return Ok(false),
+    }
+    Ok(true)
+}
+
+// Polls MST rather than waiting on the response, the controller answers straight away. Only
+// a fault that wasn't there before homing (`faults_before`) fails it, and hitting the limit
+// we're homing against isn't one.
+fn wait_for_home(
+    handle: &dyn Transport,
+    settings: &HomeSettings,
+    faults_before: MotorStatus,
+) -> Result<()> {
+    let (start, timeout) = (Instant::now(), settings.timeout);
+    let expected = match (settings.method, settings.positive) {
+        (HomeMethod::LimitSwitch, true) => MotorStatus::PLUS_LIMIT_ERROR,
+        (HomeMethod::LimitSwitch, false) => MotorStatus::MINUS_LIMIT_ERROR,
+        _ => MotorStatus::empty(),
+    };
+    loop {
+        check_abort(handle)?;
+        let status = get_motor_status(handle)?;
+        if let Some(fault) = status.difference(faults_before.union(expected)).fault() {
+            eprintln!("Homing failed, {}", fault);
+            return Err(rusb::Error::Other);
+        }
+        if !status.is_moving() && start.elapsed() > HOME_START_GRACE {
+            return Ok(());
+        }
+        if start.elapsed() > timeout {
+            stop(handle)?;
+            eprintln!(
+                "Homing took longer than {} s, motor stopped. Is the switch connected?",
+                timeout.as_secs_f64()
+            );
+            return Err(rusb::Error::Timeout);
+        }
+        sleep(POLL_INTERVAL);
+    }
+}
+
+/// Homes, zeroes PX and EX there, then moves `offset` pulses and zeroes again. HSPD and
+/// LSPD are put back afterwards. Soft limits aren't checked while searching, nobody knows
+/// where home is until it's found.
+pub fn home(handle: &dyn Transport, settings: &HomeSettings) -> Result<()> {
+    let command = settings.command();
+    let (high_speed, low_speed) = (get_high_speed(handle)?, get_low_speed(handle)?);
+    if let Some(speed) = settings.high_speed {
+        set_high_speed(handle, speed)?;
+    }
+    if let Some(speed) = settings.low_speed {
+        set_low_speed(handle, speed)?;
+    }
+
+    println!("Homing with {}", command);
+    let faults_before = get_motor_status(handle)?.intersection(MotorStatus::FAULTS);
+    let result = send_command_get_response(handle, &[command.as_bytes(), b"\0"].concat()).and_then(
+        |response| match response.starts_with('?') {
+            true => {
+                eprintln!(
+                    "Controller didn't understand {}, it answered {}",
+                    command, response
+                );
+                Err(rusb::Error::InvalidParam)
+            }
+            false => wait_for_home(handle, settings, faults_before),
+        },
+    );
+
+    // Put the speeds back whatever happened so the next move isn't at homing speed
+    set_high_speed(handle, high_speed)?;
+    set_low_speed(handle, low_speed)?;
+    result?;
+    // Homing against a limit leaves its error latched, and every move after would be refused
+    if settings.method == HomeMethod::LimitSwitch {
+        let _ = send_command_get_response(handle, b"CLR\0")?;
+    }
+
+    set_pulse_position(handle, 0)?;
+    set_encoder_position(handle, 0)?;
+    if settings.offset != 0 {
+        // From zero this is the same move in ABS and INC
+        move_stage(handle, settings.offset)?;
+        wait_for_motor_idle(handle, &mut None)?;
+        set_pulse_position(handle, 0)?;
+        set_encoder_position(handle, 0)?;
+    }
+    println!("Homed, zero is {} pulses from home", settings.offset);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn picks_the_homing_command() {
+        let command = |method: &str, direction: &str| {
+            let mut settings = HomeSettings::default();
+            read_home_setting(&mut settings, "homemethod", method).unwrap();
+            read_home_setting(&mut settings, "homedirection", direction).unwrap();
+            settings.command()
+        };
+        assert_eq!(command("switch", "+"), "H+");
+        assert_eq!(command("limit", "-"), "L-");
+        assert_eq!(command("limit", "+"), "L+");
+        assert_eq!(command("index", "-"), "ZH-");
+    }
+}
@@ -0,0 +1,234 @@
+//! The controller's digital inputs (DI) and outputs (DO), waiting on an input edge, and an
+//! interlock input that has to stay on while the motor moves.
+
+use crate::stage_control::{
+    abort::check_abort,
+    commands::{abort_motion, send_command_get_number, send_command_get_response},
+    driver::Transport,
+    script::parse_duration,
+};
+
+use rusb::Result;
+
+use std::{
+    sync::Mutex,
+    thread::sleep,
+    time::{Duration, Instant},
+};
+
+const POLL_INTERVAL: Duration = Duration::from_millis(20);
+
+/// Which change on an input to wait for
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Edge {
+    Rising,  // Off to on
+    Falling, // On to off
+    Any,
+}
+
+impl Edge {
+    /// `rising`, `falling` or `any`, case insensitive.
+    pub fn parse(text: &str) -> Option<Edge> {
+        match text.to_ascii_lowercase().as_str() {
+            "rising" => Some(Edge::Rising),
+            "falling" => Some(Edge::Falling),
+            "any" => Some(Edge::Any),
+            _ => None,
+        }
+    }
+}
+
+// Inputs and outputs are numbered from 1 like on the connector, bit 0 is number 1
+pub(crate) fn bit_mask(number: u8) -> Result<u32> {
+    match number {
+        1..=32 => Ok(1 << (number - 1)),
+        _ => {
+            eprintln!("Digital I/O are numbered from 1 to 32, got {}", number);
+            Err(rusb::Error::InvalidParam)
+        }
+    }
+}
+
+/// Reads every digital input, DI. Bit 0 is input 1.
+pub fn get_digital_inputs(handle: &dyn Transport) -> Result<u32> {
+    send_command_get_number(handle, b"DI\0")
+}
+
+/// Whether input `input` (from 1) is on.
+pub fn get_digital_input(handle: &dyn Transport, input: u8) -> Result<bool> {
+    let mask = bit_mask(input)?;
+    Ok(get_digital_inputs(handle)? & mask != 0)
+}
+
+/// Reads every digital output, DO. Bit 0 is output 1.
+pub fn get_digital_outputs(handle: &dyn Transport) -> Result<u32> {
+    send_command_get_number(handle, b"DO\0")
+}
+
+/// Sets every digital output at once with DO=.
+pub fn set_digital_outputs(handle: &dyn Transport, outputs: u32) -> Result<()> {
+    let _ = send_command_get_response(
+        handle,
+        &[b"DO=", outputs.to_string().as_bytes(), b"\0"].concat(),
+    )?;
+    Ok(())
+}
+
+/// Turns output `output` (from 1) on or off, leaving the others as they are.
+pub fn set_digital_output(handle: &dyn Transport, output: u8, on: bool) -> Result<()> {
+    let mask = bit_mask(output)?;
+    let outputs = get_digital_outputs(handle)?;
+    let outputs = match on {
+        true => outputs | mask,
+        false => outputs & !mask,
+    };
+    set_digital_outputs(handle, outputs)
+}
+
+/// Polls input `input` until it changes the way `edge` says. Returns false if `timeout`
+/// ran out first, None waits for ever. An abort gets through as Interrupted.
+pub fn wait_for_input_edge(
+    handle: &dyn Transport,
+    input: u8,
+    edge: Edge,
+    timeout: Option<Duration>,
+) -> Result<bool> {
+    let start = Instant::now();
+    let mut last = get_digital_input(handle, input)?;
+    loop {
+        check_abort(handle)?;
+        let now = get_digital_input(handle, input)?;
+        let fired = match edge {
+            Edge::Rising => !last && now,
+            Edge::Falling => last && !now,
+            Edge::Any => last != now,
+        };
+        if fired {
+            return Ok(true);
+        }
+        if timeout.is_some_and(|timeout| start.elapsed() > timeout) {
+            return Ok(false);
+        }
+        last = now;
+        sleep(POLL_INTERVAL);
+    }
+}
+
+/// Reads `input [rising|falling|any] [timeout]` for WAIT_INPUT in interactive mode and
+/// scripts. The edge defaults to rising and the timeout to none.
+pub fn parse_wait_input(
+    args: &[&str],
+) -> std::result::Result<(u8, Edge, Option<Duration>), String> {
+    let usage = || "Usage: WAIT_INPUT input [rising|falling|any] [timeout]".to_string();
+    let (input, rest) = match args.split_first() {
+        Some((input, rest)) => (input.parse::<u8>().map_err(|_| usage())?, rest),
+        None => return Err(usage()),
+    };
+    bit_mask(input).map_err(|_| format!("Inputs are numbered from 1 to 32, got {}", input))?;
+    let (edge, timeout) = match rest {
+        [] => (Edge::Rising, None),
+        [edge] => match Edge::parse(edge) {
+            Some(edge) => (edge, None),
+            None => (Edge::Rising, Some(parse_duration(edge)?)),
+        },
+        [edge, timeout] => (
+            Edge::parse(edge).ok_or_else(usage)?,
+            Some(parse_duration(timeout)?),
+        ),
+        _ => return Err(usage()),
+    };
+    Ok((input, edge, timeout))
+}
+
+/// A digital input that has to be on for the motor to move, a door switch say.
+#[derive(Debug, Clone, Copy)]
+pub struct Interlock {
+    pub input: u8,
+}
+
+// Global for the same reason as the soft limits, every wait on the motor has to check it
+static INTERLOCK: Mutex<Option<Interlock>> = Mutex::new(None);
+
+/// Sets (or with None removes) the interlock checked while the motor moves.
+pub fn set_interlock(interlock: Option<Interlock>) {
+    *INTERLOCK.lock().unwrap() = interlock;
+}
+
+/// The current interlock, if any.
+pub fn get_interlock() -> Option<Interlock> {
+    *INTERLOCK.lock().unwrap()
+}
+
+/// Errors if the interlock is set and its input is off. Call before starting anything.
+pub fn check_interlock(handle: &dyn Transport) -> Result<()> {
+    let interlock = match get_interlock() {
+        None => return Ok(()),
+        Some(interlock) => interlock,
+    };
+    match get_digital_input(handle, interlock.input)? {
+        true => Ok(()),
+        false => {
+            eprintln!("Interlock input {} is off", interlock.input);
+            Err(rusb::Error::Other)
+        }
+    }
+}
+
+// Checked on every poll while the motor moves. An open door stops the motor dead, like
+// leaving the soft limits does.
+pub(crate) fn enforce_interlock(handle: &dyn Transport) -> Result<()> {
+    let interlock = match get_interlock() {
+        None => return Ok(()),
+        Some(interlock) => interlock,
+    };
+    if !get_digital_input(handle, interlock.input)? {
+        abort_motion(handle)?;
+        eprintln!(
+            "Interlock input {} went off, motor stopped",
+            interlock.input
+        );
+        return Err(rusb::Error::Other);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reads_wait_input_arguments() {
+        assert_eq!(parse_wait_input(&["3"]), Ok((3, Edge::Rising, None)));
+        assert_eq!(
+            parse_wait_input(&["3", "FALLING"]),
+            Ok((3, Edge::Falling, None))
+        );
+        // A time on its own is the timeout for a rising edge
+        assert_eq!(
+            parse_wait_input(&["32", "500ms"]),
+            Ok((32, Edge::Rising, Some(Duration::from_millis(500))))
+        );
+        assert_eq!(
+            parse_wait_input(&["1", "any", "2s"]),
+            Ok((1, Edge::Any, Some(Duration::from_secs(2))))
+        );
+    }
+
+    #[test]
+    fn refuses_bad_wait_input_arguments() {
+        let usage = "Usage: WAIT_INPUT input [rising|falling|any] [timeout]".to_string();
+        assert_eq!(parse_wait_input(&[]), Err(usage.clone()));
+        assert_eq!(parse_wait_input(&["door"]), Err(usage.clone()));
+        assert_eq!(parse_wait_input(&["1", "up", "2s"]), Err(usage.clone()));
+        assert_eq!(parse_wait_input(&["1", "any", "2s", "3"]), Err(usage));
+        assert_eq!(
+            parse_wait_input(&["0"]),
+            Err("Inputs are numbered from 1 to 32, got 0".to_string())
+        );
+        assert_eq!(
+            parse_wait_input(&["33"]),
+            Err("Inputs are numbered from 1 to 32, got 33".to_string())
+        );
+        assert!(parse_wait_input(&["1", "soon"]).is_err());
+    }
+}
@@ -0,0 +1,60 @@
+//! Software travel limits checked on every motion command.
+
+use crate::stage_control::units::UnitConversion;
+
+use std::sync::Mutex;
+
+/// Software travel limits in pulses. Every motion command is checked against these before
+/// it is sent, and the position is watched against them while the motor moves.
+/// Zero is wherever PX was last set to 0, so set these after zeroing.
+#[derive(Debug, Clone, Copy)]
+pub struct SoftLimits {
+    pub min: i32,
+    pub max: i32,
+    // Only check EX if we know how to turn encoder counts into pulses. If the encoder
+    // resolution is a guess this would trip for no reason.
+    pub encoder: Option<UnitConversion>,
+}
+
+// Global because every motion function and interactive mode has to respect the same
+// limits, and threading them through every call that can move the stage is asking for
+// one to be missed
+static SOFT_LIMITS: Mutex<Option<SoftLimits>> = Mutex::new(None);
+
+/// Sets (or with None removes) the limits every motion command is checked against.
+pub fn set_soft_limits(limits: Option<SoftLimits>) {
+    *SOFT_LIMITS.lock().unwrap() = limits;
+}
+
+/// The current soft limits, if any.
+pub fn get_soft_limits() -> Option<SoftLimits> {
+    *SOFT_LIMITS.lock().unwrap()
+}
+
+impl SoftLimits {
+    /// Whether `position` (pulses) is within the limits, inclusive.
+    pub fn contains(&self, position: i32) -> bool {
+        (self.min..=self.max).contains(&position)
+    }
+
+    /// Checks both the pulse and (if configured) encoder position, returns a message saying
+    /// what was out of bounds so the caller can decide how loud to be about it
+    pub fn check_position(&self, pulse_position: i32, encoder_position: i32) -> Option<String> {
+        if !self.contains(pulse_position) {
+            return Some(format!(
+                "PX={} is outside the soft limits [{}, {}]",
+                pulse_position, self.min, self.max
+            ));
+        }
+        if let Some(units) = self.encoder {
+            let encoder_pulses = units.encoder_to_pulses(encoder_position);
+            if !self.contains(encoder_pulses) {
+                return Some(format!(
+                    "EX={} ({} pulses) is outside the soft limits [{}, {}]",
+                    encoder_position, encoder_pulses, self.min, self.max
+                ));
+            }
+        }
+        None
+    }
+}
@@ -0,0 +1,421 @@
+//! Counters and gauges for long runs, served over HTTP in the Prometheus text format so
+//! existing monitoring can alarm when a run stalls or the USB link gets flaky.
+
+use crate::stage_control::{
+    commands::{get_encoder_position, get_motor_status, get_pulse_position},
+    driver::Transport,
+    motor_status::MotorStatus,
+    recording::mark_background_thread,
+    run::RunProgress,
+    units::UnitConversion,
+};
+
+use std::{
+    collections::BTreeMap,
+    fmt::Write as _,
+    io::{BufRead, BufReader, Write},
+    net::{TcpListener, TcpStream},
+    sync::{Arc, Mutex},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+/// Where serve_metrics listens if nothing else is given.
+pub const DEFAULT_METRICS_ADDRESS: &str = "127.0.0.1:9464";
+
+// Upper bounds in s. A command that hits the 3 s read timeout took the stale drain and the
+// write on top of that, so it lands in the 10 s bucket rather than the 3 s one
+const LATENCY_BUCKETS: [f64; 11] = [
+    0.001, 0.002, 0.005, 0.01, 0.02, 0.05, 0.1, 0.5, 1.0, 3.0, 10.0,
+];
+
+// A client that connects and says nothing shouldn't block the next scrape for long
+const CLIENT_TIMEOUT: Duration = Duration::from_secs(5);
+
+struct Metrics {
+    commands: u64,
+    stale_packets: u64,
+    usb_errors: BTreeMap<String, u64>,
+    latency_buckets: [u64; LATENCY_BUCKETS.len()],
+    latency_sum: f64,
+    run: Option<RunProgress>,
+    last_cycle_time: Option<f64>, // Unix time in s
+    encoder_units: Option<UnitConversion>,
+}
+
+// Global so send_command_get_response can count every command without a handle to anything
+static METRICS: Mutex<Metrics> = Mutex::new(Metrics {
+    commands: 0,
+    stale_packets: 0,
+    usb_errors: BTreeMap::new(),
+    latency_buckets: [0; LATENCY_BUCKETS.len()],
+    latency_sum: 0.0,
+    run: None,
+    last_cycle_time: None,
+    encoder_units: None,
+});
+
+fn metrics() -> std::sync::MutexGuard<'static, Metrics> {
+    METRICS.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+fn unix_time() -> f64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs_f64()
+}
+
+/// Counts one command and how long its round trip took, or the USB error it failed with.
+pub fn record_command(latency: Duration, error: Option<&rusb::Error>) {
+    let mut metrics = metrics();
+    metrics.commands += 1;
+    let seconds = latency.as_secs_f64();
+    metrics.latency_sum += seconds;
+    if let Some(bucket) = LATENCY_BUCKETS.iter().position(|&le| seconds <= le) {
+        metrics.latency_buckets[bucket] += 1;
+    }
+    if let Some(e) = error {
+        *metrics.usb_errors.entry(format!("{:?}", e)).or_insert(0) += 1;
+    }
+}
+
+/// Counts packets thrown away before a command because nobody read them.
+pub fn record_stale_packets(count: usize) {
+    if count > 0 {
+        metrics().stale_packets += count as u64;
+    }
+}
+
+/// Records a finished cycle.
+pub fn record_cycle(progress: &RunProgress) {
+    let mut metrics = metrics();
+    metrics.run = Some(*progress);
+    metrics.last_cycle_time = Some(unix_time());
+}
+
+/// Sets how encoder counts turn into pulses for the following error. None (the default)
+/// leaves it out, a guessed encoder resolution would just make it noise.
+pub fn set_encoder_units(units: Option<UnitConversion>) {
+    metrics().encoder_units = units;
+}
+
+fn write_metric(
+    out: &mut String,
+    name: &str,
+    kind: &str,
+    help: &str,
+    value: impl std::fmt::Display,
+) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+    let _ = writeln!(out, "{} {}", name, value);
+}
+
+/// Everything in the Prometheus text format. PX, EX and MST are read from the controller,
+/// if that fails they are left out and the error shows up in the USB error count.
+pub fn render(handle: &dyn Transport) -> String {
+    let pulse_position = get_pulse_position(handle).ok();
+    let encoder_position = get_encoder_position(handle).ok();
+    let motor_status = get_motor_status(handle).ok();
+
+    let metrics = metrics();
+    let mut out = String::new();
+
+    if let Some(pulses) = pulse_position {
+        write_metric(
+            &mut out,
+            "loader_position_pulses",
+            "gauge",
+            "Pulse position (PX).",
+            pulses,
+        );
+    }
+    if let Some(counts) = encoder_position {
+        write_metric(
+            &mut out,
+            "loader_encoder_position_counts",
+            "gauge",
+            "Encoder position (EX).",
+            counts,
+        );
+    }
+    if let (Some(pulses), Some(counts), Some(units)) =
+        (pulse_position, encoder_position, metrics.encoder_units)
+    {
+        write_metric(
+            &mut out,
+            "loader_following_error_pulses",
+            "gauge",
+            "Pulse position minus the encoder position in pulses.",
+            pulses - units.encoder_to_pulses(counts),
+        );
+    }
+    if let Some(status) = motor_status {
+        write_metric(
+            &mut out,
+            "loader_motor_status",
+            "gauge",
+            "Raw MST status word, 0 when idle.",
+            status.bits(),
+        );
+        // One series per bit so limits and faults can be alarmed on by name
+        let _ = writeln!(
+            out,
+            "# HELP loader_motor_status_flag MST bits by name, 1 when set."
+        );
+        let _ = writeln!(out, "# TYPE loader_motor_status_flag gauge");
+        for (name, flag) in MotorStatus::all().iter_names() {
+            let _ = writeln!(
+                out,
+                "loader_motor_status_flag{{flag=\"{}\"}} {}",
+                name,
+                u8::from(status.contains(flag))
+            );
+        }
+    }
+
+    if let Some(run) = metrics.run {
+        write_metric(
+            &mut out,
+            "loader_run_cycle",
+            "gauge",
+            "Last finished cycle of the current or last run.",
+            run.cycle,
+        );
+        write_metric(
+            &mut out,
+            "loader_run_load_cycles",
+            "gauge",
+            "Cycles in the current or last run.",
+            run.load_cycles,
+        );
+        write_metric(
+            &mut out,
+            "loader_run_hspd",
+            "gauge",
+            "High speed for the next cycle in pulses/s.",
+            run.hspd,
+        );
+        write_metric(
+            &mut out,
+            "loader_run_period_error_seconds",
+            "gauge",
+            "Elapsed time minus cycles times period, positive is running late.",
+            run.period_error,
+        );
+    }
+    if let Some(time) = metrics.last_cycle_time {
+        write_metric(
+            &mut out,
+            "loader_run_last_cycle_timestamp_seconds",
+            "gauge",
+            "Unix time the last cycle finished, alarm on time() minus this for stalls.",
+            time,
+        );
+    }
+
+    write_metric(
+        &mut out,
+        "loader_usb_commands_total",
+        "counter",
+        "Commands sent to the controller.",
+        metrics.commands,
+    );
+    write_metric(
+        &mut out,
+        "loader_usb_stale_packets_total",
+        "counter",
+        "Packets nobody read, thrown away before the next command.",
+        metrics.stale_packets,
+    );
+
+    let _ = writeln!(
+        out,
+        "# HELP loader_usb_errors_total Commands that failed, by USB error."
+    );
+    let _ = writeln!(out, "# TYPE loader_usb_errors_total counter");
+    for (error, count) in metrics.usb_errors.iter() {
+        let _ = writeln!(
+            out,
+            "loader_usb_errors_total{{error=\"{}\"}} {}",
+            error, count
+        );
+    }
+
+    let _ = writeln!(
+        out,
+        "# HELP loader_command_latency_seconds Round trip time of one command."
+    );
+    let _ = writeln!(out, "# TYPE loader_command_latency_seconds histogram");
+    let mut cumulative = 0;
+    for (le, count) in LATENCY_BUCKETS.iter().zip(metrics.latency_buckets.iter()) {
+        cumulative += count;
+        let _ = writeln!(
+            out,
+            "loader_command_latency_seconds_bucket{{le=\"{}\"}} {}",
+            le, cumulative
+        );
+    }
+    let _ = writeln!(
+        out,
+        "loader_command_latency_seconds_bucket{{le=\"+Inf\"}} {}",
+        metrics.commands
+    );
+    let _ = writeln!(
+        out,
+        "loader_command_latency_seconds_sum {}",
+        metrics.latency_sum
+    );
+    let _ = writeln!(
+        out,
+        "loader_command_latency_seconds_count {}",
+        metrics.commands
+    );
+    out
+}
+
+fn respond(handle: &dyn Transport, stream: TcpStream) -> std::io::Result<()> {
+    stream.set_read_timeout(Some(CLIENT_TIMEOUT))?;
+    let mut reader = BufReader::new(&stream);
+    let mut request_line = String::new();
+    reader.read_line(&mut request_line)?;
+    // Read the headers too, closing with them unread can reset the connection under the client
+    let mut header = String::new();
+    while reader.read_line(&mut header)? > 2 {
+        header.clear();
+    }
+
+    let path = request_line.split_whitespace().nth(1).unwrap_or("");
+    let (status, body) = match (request_line.starts_with("GET "), path) {
+        (true, "/metrics") => ("200 OK", render(handle)),
+        (true, _) => ("404 Not Found", "Metrics are at /metrics\n".to_string()),
+        (false, _) => ("405 Method Not Allowed", "Only GET\n".to_string()),
+    };
+    let mut stream = &stream;
+    write!(
+        stream,
+        "HTTP/1.1 {}\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        body.len(),
+        body
+    )?;
+    stream.flush()
+}
+
+/// Serves /metrics on `address` (`host:port`) until the process exits, one scrape at a time.
+/// Meant for its own thread next to whatever the loader is doing.
+pub fn serve_metrics(handle: Arc<dyn Transport>, address: &str) -> std::io::Result<()> {
+    let listener = TcpListener::bind(address)?;
+    println!(
+        "Serving metrics on http://{}/metrics",
+        listener.local_addr()?
+    );
+    serve_listener(handle, listener)
+}
+
+fn serve_listener(handle: Arc<dyn Transport>, listener: TcpListener) -> std::io::Result<()> {
+    mark_background_thread("metrics");
+    for stream in listener.incoming() {
+        let result = stream.and_then(|stream| respond(&handle, stream));
+        if let Err(e) = result {
+            eprintln!("Metrics request failed: {}", e);
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::stage_control::recording::{
+        script::{exchange, failed, read, write},
+        ReplayTransport, TransferKind,
+    };
+
+    use std::io::Read;
+
+    // The counters are shared with every other test sending commands, so only what this
+    // scrape adds is certain
+    fn value(metrics: &str, name: &str) -> f64 {
+        metrics
+            .lines()
+            .find_map(|line| line.strip_prefix(name)?.strip_prefix(' ')?.parse().ok())
+            .unwrap_or_else(|| panic!("{} isn't in\n{}", name, metrics))
+    }
+
+    #[test]
+    fn serves_counters_and_latency_histogram() {
+        let mut transfers = vec![
+            // A packet left over from before, then PX
+            read(b"99\0"),
+            failed(TransferKind::Read, rusb::Error::Timeout),
+            write(b"PX\0"),
+            read(b"1234\0"),
+        ];
+        transfers.extend(exchange("EX", "1200"));
+        transfers.push(failed(TransferKind::Read, rusb::Error::Timeout));
+        transfers.push(failed(TransferKind::Write, rusb::Error::Pipe));
+        let handle: Arc<dyn Transport> = Arc::new(ReplayTransport::new(transfers));
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        std::thread::spawn(move || serve_listener(handle, listener));
+
+        let mut stream = TcpStream::connect(address).unwrap();
+        write!(stream, "GET /metrics HTTP/1.1\r\nHost: localhost\r\n\r\n").unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{}", response);
+        let body = response.split_once("\r\n\r\n").unwrap().1;
+        assert_eq!(value(body, "loader_position_pulses"), 1234.0);
+        assert_eq!(value(body, "loader_encoder_position_counts"), 1200.0);
+        // MST failed, so it's left out and counted as an error instead
+        assert!(!body.contains("loader_motor_status"));
+        assert!(value(body, "loader_usb_errors_total{error=\"Pipe\"}") >= 1.0);
+        assert!(value(body, "loader_usb_stale_packets_total") >= 1.0);
+
+        let commands = value(body, "loader_usb_commands_total");
+        assert!(commands >= 3.0);
+        assert_eq!(
+            value(body, "loader_command_latency_seconds_count"),
+            commands
+        );
+        assert_eq!(
+            value(body, "loader_command_latency_seconds_bucket{le=\"+Inf\"}"),
+            commands
+        );
+        // Buckets are cumulative, a replayed command is well under the 3 s one
+        let buckets: Vec<f64> = LATENCY_BUCKETS
+            .iter()
+            .map(|le| {
+                value(
+                    body,
+                    &format!("loader_command_latency_seconds_bucket{{le=\"{}\"}}", le),
+                )
+            })
+            .collect();
+        assert!(buckets.windows(2).all(|pair| pair[0] <= pair[1]));
+        assert!(buckets[buckets.len() - 1] >= 3.0);
+    }
+
+    #[test]
+    fn a_timed_out_command_lands_above_the_timeout() {
+        let bucket = |body: &str, le: &str| {
+            value(
+                body,
+                &format!("loader_command_latency_seconds_bucket{{le=\"{}\"}}", le),
+            )
+        };
+        // Nothing to read, PX, EX and MST are left out
+        let handle = ReplayTransport::new(vec![]);
+        let before = render(&handle);
+        record_command(Duration::from_millis(3002), Some(&rusb::Error::Timeout));
+        let after = render(&handle);
+
+        // Other tests only add fast commands, so nothing else lands between 3 and 10 s
+        let slow = |body: &str| bucket(body, "10") - bucket(body, "3");
+        assert_eq!(slow(&after) - slow(&before), 1.0);
+        assert!(value(&after, "loader_usb_errors_total{error=\"Timeout\"}") >= 1.0);
+    }
+}
@@ -0,0 +1,127 @@
+//! The MST motor status word, decoded. Bits are as in the motor status table in the manual.
+
+use bitflags::bitflags;
+
+bitflags! {
+    /// What MST says the motor and its inputs are doing.
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+    pub struct MotorStatus: i32 {
+        const CONSTANT_SPEED = 1 << 0;
+        const ACCELERATING = 1 << 1;
+        const DECELERATING = 1 << 2;
+        const HOME_INPUT = 1 << 3;
+        const MINUS_LIMIT_INPUT = 1 << 4;
+        const PLUS_LIMIT_INPUT = 1 << 5;
+        // Latched when a limit is hit while moving, moves are refused until CLR
+        const MINUS_LIMIT_ERROR = 1 << 6;
+        const PLUS_LIMIT_ERROR = 1 << 7;
+        const LATCH_INPUT = 1 << 8;
+        const Z_INDEX = 1 << 9;
+        // The communication time-out (TOC) ran out and the controller stopped the motor
+        const TOC_TIMEOUT = 1 << 10;
+    }
+}
+
+impl MotorStatus {
+    /// Any of these means the motor is moving, homing included.
+    pub const MOVING: MotorStatus = MotorStatus::CONSTANT_SPEED
+        .union(MotorStatus::ACCELERATING)
+        .union(MotorStatus::DECELERATING);
+
+    /// The motor was stopped by the controller and the move didn't finish.
+    pub const FAULTS: MotorStatus = MotorStatus::MINUS_LIMIT_ERROR
+        .union(MotorStatus::PLUS_LIMIT_ERROR)
+        .union(MotorStatus::TOC_TIMEOUT);
+
+    /// Keeps bits we don't have a name for, so they still show up in `bits()`.
+    pub fn from_mst(mst: i32) -> MotorStatus {
+        MotorStatus::from_bits_retain(mst)
+    }
+
+    pub fn is_moving(&self) -> bool {
+        self.intersects(MotorStatus::MOVING)
+    }
+
+    /// What went wrong, if a fault bit is set.
+    pub fn fault(&self) -> Option<&'static str> {
+        if self.contains(MotorStatus::MINUS_LIMIT_ERROR) {
+            Some("the minus limit switch was hit, CLR clears the error once the stage is off it")
+        } else if self.contains(MotorStatus::PLUS_LIMIT_ERROR) {
+            Some("the plus limit switch was hit, CLR clears the error once the stage is off it")
+        } else if self.contains(MotorStatus::TOC_TIMEOUT) {
+            Some("the controller's communication time-out (TOC) stopped the motor")
+        } else {
+            None
+        }
+    }
+
+    /// The names of the bits that are set, e.g. "CONSTANT_SPEED HOME_INPUT", or "IDLE".
+    pub fn describe(&self) -> String {
+        let mut names: Vec<String> = self
+            .iter_names()
+            .map(|(name, _)| name.to_string())
+            .collect();
+        let unknown = self.bits() & !MotorStatus::all().bits();
+        if unknown != 0 {
+            names.push(format!("UNKNOWN({:#x})", unknown));
+        }
+        match names.is_empty() {
+            true => "IDLE".to_string(),
+            false => names.join(" "),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decodes_known_words() {
+        assert_eq!(MotorStatus::from_mst(0), MotorStatus::empty());
+        // Accelerating off the home switch
+        assert_eq!(
+            MotorStatus::from_mst(10),
+            MotorStatus::ACCELERATING | MotorStatus::HOME_INPUT
+        );
+        // Sitting on the plus limit after running into it
+        assert_eq!(
+            MotorStatus::from_mst(160),
+            MotorStatus::PLUS_LIMIT_INPUT | MotorStatus::PLUS_LIMIT_ERROR
+        );
+        assert_eq!(MotorStatus::from_mst(1024), MotorStatus::TOC_TIMEOUT);
+    }
+
+    #[test]
+    fn moving_is_any_of_the_speed_bits() {
+        assert!(!MotorStatus::from_mst(0).is_moving());
+        assert!(MotorStatus::from_mst(1).is_moving());
+        assert!(MotorStatus::from_mst(2).is_moving());
+        assert!(MotorStatus::from_mst(4).is_moving());
+        // Inputs and errors alone aren't moving
+        assert!(!MotorStatus::from_mst(0b111_1111_1000).is_moving());
+    }
+
+    #[test]
+    fn reports_faults() {
+        assert_eq!(MotorStatus::from_mst(16).fault(), None); // Just on the minus limit input
+        assert!(MotorStatus::from_mst(64)
+            .fault()
+            .unwrap()
+            .starts_with("the minus limit"));
+        assert!(MotorStatus::from_mst(128)
+            .fault()
+            .unwrap()
+            .starts_with("the plus limit"));
+        assert!(MotorStatus::from_mst(1024).fault().unwrap().contains("TOC"));
+    }
+
+    #[test]
+    fn keeps_and_describes_unknown_bits() {
+        let status = MotorStatus::from_mst(0x1001);
+        assert_eq!(status.bits(), 0x1001);
+        assert!(status.is_moving());
+        assert_eq!(status.describe(), "CONSTANT_SPEED UNKNOWN(0x1000)");
+        assert_eq!(MotorStatus::from_mst(0).describe(), "IDLE");
+    }
+}
@@ -0,0 +1,145 @@
+//! How to talk to the controller over USB: which device, interface and endpoints, the
+//! control transfer that opens and closes communication, timeouts, packet size and how long
+//! the driver takes to settle. The
+//! NSC-A1 is the default, other Arcus controllers that speak the same ASCII protocol can be
+//! used with a profile file.
+
+use crate::stage_control::{
+    driver::{NSC_A1_PRODUCT_ID, NSC_A1_VENDOR_ID},
+    read_file::parse_value_with,
+};
+
+use std::{fs, sync::Mutex, time::Duration};
+
+/// Everything about the USB side of a controller that isn't the command set.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct DeviceProfile {
+    pub vendor_id: u16,
+    pub product_id: u16,
+    pub interface: u8,
+    pub endpoint_in: u8,  // Bulk in, responses
+    pub endpoint_out: u8, // Bulk out, commands
+    pub control_request_type: u8,
+    pub control_request: u8,
+    pub open_value: u16,  // Control value that opens communication
+    pub close_value: u16, // And closes it
+    pub timeout: Duration,
+    // Waiting for stale packets, and for a response that timed out, see driver::discard_stale
+    pub drain_timeout: Duration,
+    pub late_response_timeout: Duration,
+    pub packet_size: usize,
+    // Waited after RW, RR and EO=1 for the driver to take them
+    pub settle_time: Duration,
+}
+
+/// The NSC-A1.
+pub const NSC_A1: DeviceProfile = DeviceProfile {
+    vendor_id: NSC_A1_VENDOR_ID,
+    product_id: NSC_A1_PRODUCT_ID,
+    interface: 0,
+    endpoint_in: 0x82,
+    endpoint_out: 0x02,
+    control_request_type: 64, // Vendor, host to device
+    control_request: 2,
+    open_value: 2,
+    close_value: 4,
+    timeout: Duration::from_secs(3),
+    drain_timeout: Duration::from_millis(1),
+    late_response_timeout: Duration::from_millis(100),
+    packet_size: 64,
+    settle_time: Duration::from_secs(3),
+};
+
+impl Default for DeviceProfile {
+    fn default() -> DeviceProfile {
+        NSC_A1
+    }
+}
+
+// Global like the soft limits, every transfer in driver.rs needs it and none of them are
+// handed anything but the handle
+static DEVICE_PROFILE: Mutex<DeviceProfile> = Mutex::new(NSC_A1);
+
+/// Sets the profile every transfer uses from now on. Set it before opening the controller.
+pub fn set_device_profile(profile: DeviceProfile) {
+    *DEVICE_PROFILE.lock().unwrap() = profile;
+}
+
+/// The profile in use, NSC_A1 unless it's been set.
+pub fn get_device_profile() -> DeviceProfile {
+    *DEVICE_PROFILE.lock().unwrap()
+}
+
+// Decimal, or hex with 0x
+fn parse_number<T: TryFrom<u64>>(key: &str, value: &str) -> rusb::Result<T> {
+    parse_value_with(key, value, |value| {
+        let number = match value.strip_prefix("0x").or(value.strip_prefix("0X")) {
+            Some(hex) => u64::from_str_radix(hex, 16).ok(),
+            None => value.parse().ok(),
+        };
+        T::try_from(number?).ok()
+    })
+}
+
+// Timeouts are in ms, and 0 would be wait forever to libusb
+fn parse_timeout(key: &str, value: &str) -> rusb::Result<Duration> {
+    match parse_number(key, value)? {
+        0 => {
+            eprintln!("{} has to be at least 1 ms", key);
+            Err(rusb::Error::InvalidParam)
+        }
+        ms => Ok(Duration::from_millis(ms)),
+    }
+}
+
+/// Reads a profile file, `Key value` per line like the run file. Anything left out is the
+/// NSC-A1's. Numbers can be hex (0x82) and timeouts and the settle time are in ms.
+pub fn read_device_profile(path: &str) -> rusb::Result<DeviceProfile> {
+    let contents = fs::read_to_string(path).map_err(|e| {
+        eprintln!("Couldn't read the device profile {}: {}", path, e);
+        rusb::Error::NotFound
+    })?;
+    let mut profile = NSC_A1;
+
+    for whole_line in contents.lines() {
+        let line: Vec<&str> = whole_line.split_whitespace().collect();
+        if line.is_empty() || line[0].starts_with('#') {
+            continue;
+        }
+        if line.len() != 2 {
+            eprintln!("Couldn't understand {:?} in {}", whole_line, path);
+            return Err(rusb::Error::InvalidParam);
+        }
+        let (key, value) = (line[0], line[1]);
+
+        match key.to_ascii_lowercase().as_str() {
+            "vendorid" => profile.vendor_id = parse_number(key, value)?,
+            "productid" => profile.product_id = parse_number(key, value)?,
+            "interface" => profile.interface = parse_number(key, value)?,
+            "endpointin" => profile.endpoint_in = parse_number(key, value)?,
+            "endpointout" => profile.endpoint_out = parse_number(key, value)?,
+            "controlrequesttype" => profile.control_request_type = parse_number(key, value)?,
+            "controlrequest" => profile.control_request = parse_number(key, value)?,
+            "openvalue" => profile.open_value = parse_number(key, value)?,
+            "closevalue" => profile.close_value = parse_number(key, value)?,
+            "timeout" => profile.timeout = parse_timeout(key, value)?,
+            "draintimeout" => profile.drain_timeout = parse_timeout(key, value)?,
+            "lateresponsetimeout" => profile.late_response_timeout = parse_timeout(key, value)?,
+            "packetsize" => profile.packet_size = parse_number(key, value)?,
+            "settletime" => profile.settle_time = Duration::from_millis(parse_number(key, value)?),
+            _ => {
+                eprintln!("Couldn't understand {} in {}", key, path);
+                return Err(rusb::Error::InvalidParam);
+            }
+        }
+    }
+
+    if profile.packet_size == 0
+        || profile.endpoint_in & 0x80 == 0
+        || profile.endpoint_out & 0x80 != 0
+    {
+        eprintln!("PacketSize has to be above 0, EndpointIn an in endpoint (0x80 set) and EndpointOut an out one");
+        return Err(rusb::Error::InvalidParam);
+    }
+    Ok(profile)
+}
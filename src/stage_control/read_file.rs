@@ -0,0 +1,22 @@
+//! Reading values out of the `Key value` files: the run and calibrate files and device
+//! profiles.
+
+use std::str::FromStr;
+
+/// Parses `value` with `parse`, printing which key couldn't be understood if it fails.
+pub fn parse_value_with<T>(
+    key: &str,
+    value: &str,
+    parse: impl FnOnce(&str) -> Option<T>,
+) -> rusb::Result<T> {
+    parse(value).ok_or_else(|| {
+        eprintln!("Couldn't understand {:?} for {}", value, key);
+        rusb::Error::InvalidParam
+    })
+}
+
+/// Parses `value` as whatever `key` needs, printing which key couldn't be understood if it
+/// fails.
+pub fn parse_value<T: FromStr>(key: &str, value: &str) -> rusb::Result<T> {
+    parse_value_with(key, value, |value| value.parse().ok())
+}
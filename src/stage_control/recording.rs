@@ -0,0 +1,401 @@
+//! Recording every USB transfer to a file, and playing a recording back instead of a
+//! controller. A customer's failing session can be recorded with `--record` and replayed
+//! here with `--replay` without the hardware.
+//!
+//! One transfer per line, tab separated: time since recording started (s), `control`,
+//! `write` or `read`, the endpoint (or the control value), `ok` or the rusb error, the bytes
+//! in hex, then the printable ones as text for reading by eye. Lines starting with # are
+//! comments. The README has an example.
+//!
+//! Transfers from threads that talk to the controller alongside the session (the jog
+//! watchdog, the dashboard's sampler, the metrics server) are recorded as comments naming the
+//! thread. Their timing decides where they land, so a replay couldn't follow them, and a
+//! replay doesn't run those threads at all.
+
+use crate::stage_control::driver::Transport;
+
+use std::{
+    cell::Cell,
+    fs::File,
+    io::{BufRead, BufReader, BufWriter, Write},
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+/// What a recorded transfer was
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TransferKind {
+    Control, // Vendor control transfer, the value is what was sent
+    Write,   // Bulk out
+    Read,    // Bulk in
+}
+
+impl TransferKind {
+    fn name(&self) -> &'static str {
+        match self {
+            TransferKind::Control => "control",
+            TransferKind::Write => "write",
+            TransferKind::Read => "read",
+        }
+    }
+}
+
+/// One line of a recording.
+#[derive(Debug, Clone)]
+pub struct Transfer {
+    pub time: f64,
+    pub kind: TransferKind,
+    pub value: u16, // Endpoint for bulk transfers, the control value otherwise
+    pub error: Option<rusb::Error>,
+    pub data: Vec<u8>,
+}
+
+struct Recorder {
+    file: BufWriter<File>,
+    start: Instant,
+}
+
+// Global so driver.rs can record without every caller passing a recorder along
+static RECORDER: Mutex<Option<Recorder>> = Mutex::new(None);
+
+static REPLAYING: AtomicBool = AtomicBool::new(false);
+
+thread_local! {
+    // Set by mark_background_thread
+    static BACKGROUND_THREAD: Cell<Option<&'static str>> = const { Cell::new(None) };
+}
+
+/// Marks the calling thread as one that polls the controller alongside the session, its
+/// transfers are recorded as comments starting with `name` and a replay skips them.
+pub fn mark_background_thread(name: &'static str) {
+    BACKGROUND_THREAD.with(|thread| thread.set(Some(name)));
+}
+
+/// Set while a recording is played back instead of a controller. The background threads
+/// are refused or left off then, they'd take transfers meant for the session.
+pub fn set_replaying(replaying: bool) {
+    REPLAYING.store(replaying, Ordering::SeqCst);
+}
+
+/// Whether a recording is being played back, see set_replaying.
+pub fn is_replaying() -> bool {
+    REPLAYING.load(Ordering::SeqCst)
+}
+
+/// Records every transfer from now on to `path`, replacing any recording already going.
+pub fn start_recording(path: &str) -> std::io::Result<()> {
+    let mut file = BufWriter::new(File::create(path)?);
+    file.write_all(b"# time(s)\tkind\tendpoint/value\tresult\tdata(hex)\ttext\n")?;
+    *RECORDER.lock().unwrap() = Some(Recorder {
+        file,
+        start: Instant::now(),
+    });
+    Ok(())
+}
+
+/// Stops recording and closes the file.
+pub fn stop_recording() {
+    if let Some(mut recorder) = RECORDER.lock().unwrap().take() {
+        let _ = recorder.file.flush();
+    }
+}
+
+fn error_name(error: Option<&rusb::Error>) -> String {
+    match error {
+        None => "ok".to_string(),
+        Some(error) => format!("{:?}", error),
+    }
+}
+
+fn error_from_name(name: &str) -> Option<Option<rusb::Error>> {
+    use rusb::Error::*;
+    let error = match name {
+        "ok" => return Some(None),
+        "Io" => Io,
+        "InvalidParam" => InvalidParam,
+        "Access" => Access,
+        "NoDevice" => NoDevice,
+        "NotFound" => NotFound,
+        "Busy" => Busy,
+        "Timeout" => Timeout,
+        "Overflow" => Overflow,
+        "Pipe" => Pipe,
+        "Interrupted" => Interrupted,
+        "NoMem" => NoMem,
+        "NotSupported" => NotSupported,
+        "BadDescriptor" => BadDescriptor,
+        "Other" => Other,
+        _ => return None,
+    };
+    Some(Some(error))
+}
+
+/// Called by driver.rs after every transfer, does nothing unless recording. Flushed every
+/// line so a crash or a pulled cable still leaves the end of the session in the file.
+pub(crate) fn record_transfer(
+    kind: TransferKind,
+    value: u16,
+    error: Option<&rusb::Error>,
+    data: &[u8],
+) {
+    let mut recorder = RECORDER.lock().unwrap();
+    let recorder = match recorder.as_mut() {
+        None => return,
+        Some(recorder) => recorder,
+    };
+    let hex: String = data.iter().map(|byte| format!("{:02x}", byte)).collect();
+    let text: String = data
+        .iter()
+        .map(|&byte| match byte.is_ascii_graphic() || byte == b' ' {
+            true => byte as char,
+            false => '.',
+        })
+        .collect();
+    let line = format!(
+        "{:.6}\t{}\t{:#04x}\t{}\t{}\t{}",
+        recorder.start.elapsed().as_secs_f64(),
+        kind.name(),
+        value,
+        error_name(error),
+        hex,
+        text
+    );
+    // Transfers without data just end at the result
+    let line = line.trim_end_matches('\t');
+    let result = match BACKGROUND_THREAD.with(Cell::get) {
+        Some(thread) => writeln!(recorder.file, "# {}\t{}", thread, line),
+        None => writeln!(recorder.file, "{}", line),
+    };
+    if let Err(e) = result.and_then(|_| recorder.file.flush()) {
+        eprintln!("Failed to record a USB transfer: {}", e);
+    }
+}
+
+fn parse_transfer(line: &str) -> Option<Transfer> {
+    let fields: Vec<&str> = line.split('\t').collect();
+    let (time, kind, value, result) = match fields.as_slice() {
+        [time, kind, value, result, ..] => (time, kind, value, result),
+        _ => return None,
+    };
+    let kind = match *kind {
+        "control" => TransferKind::Control,
+        "write" => TransferKind::Write,
+        "read" => TransferKind::Read,
+        _ => return None,
+    };
+    let hex = fields.get(4).copied().unwrap_or("");
+    if hex.len() % 2 != 0 {
+        return None;
+    }
+    let data = (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).ok())
+        .collect::<Option<Vec<u8>>>()?;
+    Some(Transfer {
+        time: time.parse().ok()?,
+        kind,
+        value: u16::from_str_radix(value.trim_start_matches("0x"), 16).ok()?,
+        error: error_from_name(result)?,
+        data,
+    })
+}
+
+/// Reads a whole recording.
+pub fn read_recording(path: &str) -> std::io::Result<Vec<Transfer>> {
+    let mut transfers = Vec::new();
+    for (number, line) in BufReader::new(File::open(path)?).lines().enumerate() {
+        let line = line?;
+        if line.trim().is_empty() || line.starts_with('#') {
+            continue;
+        }
+        match parse_transfer(&line) {
+            Some(transfer) => transfers.push(transfer),
+            None => {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidData,
+                    format!("line {} isn't a transfer: {:?}", number + 1, line),
+                ))
+            }
+        }
+    }
+    Ok(transfers)
+}
+
+struct ReplayState {
+    transfers: Vec<Transfer>,
+    next: usize,
+    mismatches: usize,
+    ended: bool, // Said so already
+}
+
+/// Plays a recording back in order instead of talking to a controller. Reads get the
+/// recorded bytes or error, writes are compared with what was recorded. A write that
+/// differs (a speed the period control worked out differently, say) is reported and the
+/// replay carries on. Asking for a different kind of transfer than the recording has next
+/// means the session went another way, that's Other. The end of the recording is NoDevice,
+/// like the cable being pulled.
+pub struct ReplayTransport {
+    state: Mutex<ReplayState>,
+    late_responses: AtomicUsize,
+}
+
+impl ReplayTransport {
+    pub fn open(path: &str) -> std::io::Result<ReplayTransport> {
+        Ok(ReplayTransport::new(read_recording(path)?))
+    }
+
+    pub fn new(transfers: Vec<Transfer>) -> ReplayTransport {
+        ReplayTransport {
+            state: Mutex::new(ReplayState {
+                transfers,
+                next: 0,
+                mismatches: 0,
+                ended: false,
+            }),
+            late_responses: AtomicUsize::new(0),
+        }
+    }
+
+    /// How many transfers haven't been played yet.
+    pub fn remaining(&self) -> usize {
+        let state = self.state.lock().unwrap();
+        state.transfers.len() - state.next
+    }
+
+    /// How many writes didn't match the recording so far.
+    pub fn mismatches(&self) -> usize {
+        self.state.lock().unwrap().mismatches
+    }
+
+    // Takes the next transfer if it's the kind asked for, checking `sent` against it
+    fn next(&self, kind: TransferKind, value: u16, sent: &[u8]) -> rusb::Result<Transfer> {
+        let mut state = self.state.lock().unwrap();
+        let number = state.next;
+        let transfer = match state.transfers.get(number) {
+            Some(transfer) => transfer.clone(),
+            None => {
+                if !state.ended {
+                    eprintln!("Replay: the recording ended after {} transfers", number);
+                    state.ended = true;
+                }
+                return Err(rusb::Error::NoDevice);
+            }
+        };
+        if transfer.kind != kind {
+            eprintln!(
+                "Replay: transfer {} is a {} in the recording but this session did a {} of {:?}",
+                number + 1,
+                transfer.kind.name(),
+                kind.name(),
+                String::from_utf8_lossy(sent)
+            );
+            return Err(rusb::Error::Other);
+        }
+        state.next += 1;
+        if kind != TransferKind::Read && (transfer.value != value || transfer.data != sent) {
+            state.mismatches += 1;
+            eprintln!(
+                "Replay: transfer {} sent {:?} ({:#04x}), the recording has {:?} ({:#04x})",
+                number + 1,
+                String::from_utf8_lossy(sent),
+                value,
+                String::from_utf8_lossy(&transfer.data),
+                transfer.value
+            );
+        }
+        Ok(transfer)
+    }
+}
+
+impl Transport for ReplayTransport {
+    fn write_bulk(&self, endpoint: u8, buf: &[u8], _timeout: Duration) -> rusb::Result<usize> {
+        let transfer = self.next(TransferKind::Write, endpoint as u16, buf)?;
+        match transfer.error {
+            Some(error) => Err(error),
+            None => Ok(buf.len()),
+        }
+    }
+
+    fn read_bulk(&self, endpoint: u8, buf: &mut [u8], _timeout: Duration) -> rusb::Result<usize> {
+        let transfer = self.next(TransferKind::Read, endpoint as u16, &[])?;
+        match transfer.error {
+            Some(error) => Err(error),
+            None => {
+                let read = transfer.data.len().min(buf.len());
+                buf[..read].copy_from_slice(&transfer.data[..read]);
+                Ok(read)
+            }
+        }
+    }
+
+    fn write_control(
+        &self,
+        _request_type: u8,
+        _request: u8,
+        value: u16,
+        _index: u16,
+        _buf: &[u8],
+        _timeout: Duration,
+    ) -> rusb::Result<usize> {
+        let transfer = self.next(TransferKind::Control, value, &[])?;
+        match transfer.error {
+            Some(error) => Err(error),
+            None => Ok(0),
+        }
+    }
+
+    fn late_responses(&self) -> usize {
+        self.late_responses.load(Ordering::SeqCst)
+    }
+
+    fn set_late_responses(&self, late: usize) {
+        self.late_responses.store(late, Ordering::SeqCst);
+    }
+}
+
+// Recordings written by hand for the tests, with the NSC-A1's endpoints
+#[cfg(test)]
+pub(crate) mod script {
+    use super::{Transfer, TransferKind};
+    use crate::stage_control::profile::NSC_A1;
+
+    fn transfer(kind: TransferKind, error: Option<rusb::Error>, data: &[u8]) -> Transfer {
+        let value = match kind {
+            TransferKind::Control => NSC_A1.open_value,
+            TransferKind::Write => NSC_A1.endpoint_out as u16,
+            TransferKind::Read => NSC_A1.endpoint_in as u16,
+        };
+        Transfer {
+            time: 0.0,
+            kind,
+            value,
+            error,
+            data: data.to_vec(),
+        }
+    }
+
+    pub(crate) fn read(data: &[u8]) -> Transfer {
+        transfer(TransferKind::Read, None, data)
+    }
+
+    pub(crate) fn write(data: &[u8]) -> Transfer {
+        transfer(TransferKind::Write, None, data)
+    }
+
+    pub(crate) fn failed(kind: TransferKind, error: rusb::Error) -> Transfer {
+        transfer(kind, Some(error), &[])
+    }
+
+    /// One command as send_command_get_response does it: nothing stale, the command, the
+    /// response in one packet.
+    pub(crate) fn exchange(command: &str, response: &str) -> Vec<Transfer> {
+        vec![
+            failed(TransferKind::Read, rusb::Error::Timeout),
+            write(&[command.as_bytes(), b"\0"].concat()),
+            read(&[response.as_bytes(), b"\0"].concat()),
+        ]
+    }
+}
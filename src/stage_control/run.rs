@@ -1,21 +1,80 @@
-use crate::stage_control::commands::{
-    get_high_speed, move_cycle_get_time, move_stage, set_acceleration_time, set_deceleration_time,
-    set_encoder_position, set_high_speed, set_idle_time, set_low_speed, set_microstepping,
-    set_movement_type, set_pulse_position, turn_motor_on, wait_for_motor_idle,
-    write_driver_settings,
-};
+//! The cyclic loading test driven by `input_output_files/RunInput.txt`.
 
-use rusb::{DeviceHandle, GlobalContext};
+use crate::stage_control::{
+    abort::{
+        pause_requested, set_motor_off_on_abort, sleep_or_abort, wait_while_paused, AbortScope,
+    },
+    commands::{
+        get_encoder_position, get_high_speed, get_motor_enabled, get_pulse_position,
+        move_cycle_get_time_with, move_stage, set_acceleration_time, set_deceleration_time,
+        set_encoder_position, set_high_speed, set_idle_time, set_low_speed, set_microstepping,
+        set_movement_type, set_pulse_position, turn_motor_off, turn_motor_on, wait_for_motor_idle,
+        write_driver_settings, CyclePhase, PositionLog,
+    },
+    driver::Transport,
+    force::{read_force_setting, ForceSettings},
+    force_control::{CycleForces, CycleTargets, ForceControl, ForceControlSettings},
+    homing::{home, read_home_setting, HomeSettings},
+    io::{check_interlock, set_interlock, wait_for_input_edge, Edge, Interlock},
+    limits::{get_soft_limits, set_soft_limits, SoftLimits},
+    metrics::{record_cycle, set_encoder_units},
+    read_file::parse_value,
+    standalone::{run_program, StandaloneProtocol},
+    triggers::{parse_trigger, Trigger, Triggers},
+    units::UnitConversion,
+};
 
 use std::{
     fs::File,
-    io::{BufWriter, Read},
-    thread::sleep,
-    time::Instant,
+    io::{Read, Write},
+    time::{Duration, Instant},
 };
 
 use super::commands::{set_idle_current, set_run_current};
 
+// How far the encoder can disagree with the pulse position after the end of test move
+// before we warn about it. Lost steps show up here first.
+const ENCODER_TOLERANCE_MM: f64 = 0.05;
+
+// How long to wait for the load cell after the motor stops at a peak or valley. A sensor slower
+// than this leaves the force control targets where they are.
+const FORCE_READING_TIMEOUT: Duration = Duration::from_millis(500);
+
+/// What to do with the stage once the last load cycle is done
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum EndOfTest {
+    ReturnToStart, // Go back to the pulse position recorded before the offset move
+    Unload,        // Move to the absolute pulse position in UnloadPosition
+    Hold,          // Stay where the last cycle ended with the motor energized
+    MotorOff,      // Stay where the last cycle ended and de-energize the motor (EO=0)
+}
+
+/// Where the stage waits while a run is paused
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PauseAction {
+    Hold,          // Stay where the cycle ended, at the offset, with the motor energized
+    ReturnToStart, // Go back to the pulse position recorded before the offset move, and back on resume
+}
+
+/// How the high speed is corrected to keep cycles on time
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TimingMode {
+    PerCycle,  // Every cycle aims for Period, time lost earlier isn't made up
+    PhaseLock, // Cycle n aims to end n periods after the first started, time lost is made up
+    OpenLoop,  // HighSpeed for the whole run, no correction
+}
+
+/// Reported after every cycle of a run.
+#[derive(Debug, Clone, Copy)]
+pub struct RunProgress {
+    pub cycle: u32,
+    pub load_cycles: u32,
+    pub elapsed: f64,      // s since the first cycle started
+    pub period_error: f64, // elapsed - cycle * period, positive means running late
+    pub hspd: u32,         // High speed for the next cycle
+}
+
+/// Everything read from the run file plus the state that changes during the run.
 #[derive(Debug)]
 pub struct RunParameters {
     high_speed: u32, // Starting high speed inputed by user, doesn't change
@@ -27,22 +86,35 @@ pub struct RunParameters {
     period: f64,
     dwell_time: f64,
     factor: f64,
-    time: Option<Instant>, // This is the current cycle time, changes every cycle
     hspd: u32, // This is the current high speed and not the inputted high speed, changes every cycle
+    timing_mode: TimingMode,
     load_cycles: u32,
     offset: i32,
+    units: UnitConversion,
+    end_of_test: EndOfTest,
+    pause_action: PauseAction,
+    home: Option<HomeSettings>, // Home before the run instead of zeroing where the stage is
+    start_input: Option<u8>,    // Wait for this input to turn on before the first cycle
+    triggers: Vec<Trigger>,     // Output pulses during each cycle, one per Trigger line
+    force: ForceSettings, // Load cell logged next to position, if there's a ForcePort or ForceFile
+    force_control: Option<ForceControl>, // Cycle between forces instead of a fixed Amplitude
+    standalone_counter: u8, // Variable a standalone program counts cycles in
+    log_encoder: bool,    // EncoderResolution was given, so EX means something in mm
+    unload_position: i32,
+    start_position: i32, // Pulse position before the offset move, recorded by run
 }
 
+/// Reads a whole file and splits it into lines.
 pub fn read_file_to_vector_of_lines(file_path: &str) -> std::io::Result<Vec<String>> {
     let mut file: File = File::open(file_path)?;
     let mut whole_file: String = String::new();
     file.read_to_string(&mut whole_file)?;
     let split_vec: Vec<String> = whole_file.split("\n").map(str::to_string).collect();
-    return Ok(split_vec);
+    Ok(split_vec)
 }
 
 fn initialize_run_parameters() -> RunParameters {
-    return RunParameters {
+    RunParameters {
         high_speed: 0u32, // Starting high speed inputed by user, doesn't change
         low_speed: 0u32,
         acceleration_time: 0u32,
@@ -51,79 +123,323 @@ fn initialize_run_parameters() -> RunParameters {
         amplitude: 0i32,
         period: 0f64,
         dwell_time: 0f64,
-        time: None,
         factor: 2f64,
         hspd: 0u32, // This is the current high speed and not the inputted high speed, changes every cycle
+        timing_mode: TimingMode::PhaseLock,
         load_cycles: 0u32,
         offset: 0i32,
-    };
+        units: UnitConversion::default(),
+        end_of_test: EndOfTest::ReturnToStart,
+        pause_action: PauseAction::Hold,
+        home: None,
+        start_input: None,
+        triggers: Vec::new(),
+        force: ForceSettings::default(),
+        force_control: None,
+        log_encoder: false,
+        standalone_counter: 1,
+        unload_position: 0i32,
+        start_position: 0i32,
+    }
 }
 
+// Speed changes by Factor * 1000 pulses/s for every second the last cycle took longer than
+// the next one should. What the next one should take is where the modes differ: Period for
+// PerCycle, Period minus the time behind schedule for PhaseLock. At most half a period is made
+// up per cycle so one long stall doesn't send HSPD through the roof.
 fn adjust_speed(
-    handle: &DeviceHandle<GlobalContext>,
+    handle: &dyn Transport,
     params: &RunParameters,
-    time: f64,
+    cycle_time: f64,
+    elapsed: f64,
     cycle: u32,
 ) -> rusb::Result<u32> {
-    let error = (time - params.period * cycle as f64) * params.factor * 1000.0;
-    let new_hspd: u32 = (params.hspd as i32 + error as i32) as u32;
+    let phase_error = elapsed - params.period * cycle as f64;
+    let target = match params.timing_mode {
+        TimingMode::OpenLoop => {
+            println!(
+                "cycle={}\tmode=openloop\tcycle_time={}\tphase_error={}\thspd={}",
+                cycle, cycle_time, phase_error, params.hspd
+            );
+            return Ok(params.hspd);
+        }
+        TimingMode::PerCycle => params.period,
+        TimingMode::PhaseLock => {
+            (params.period - phase_error).clamp(params.period / 2.0, params.period * 1.5)
+        }
+    };
+
+    let error = (cycle_time - target) * params.factor * 1000.0;
+    let new_hspd = (params.hspd as f64 + error)
+        .round()
+        .clamp(params.low_speed.max(1) as f64, u32::MAX as f64) as u32;
     set_high_speed(handle, new_hspd)?;
     println!(
-        "time={}\tperiod={}\tnewhspd={}\terror={}\thspd{})",
-        time,
-        params.period * cycle as f64,
-        new_hspd,
+        "cycle={}\tmode={}\tcycle_time={}\ttarget={}\tphase_error={}\terror={}\thspd={}->{}\t(read back {})",
+        cycle,
+        match params.timing_mode {
+            TimingMode::PerCycle => "percycle",
+            TimingMode::PhaseLock => "phaselock",
+            TimingMode::OpenLoop => "openloop",
+        },
+        cycle_time,
+        target,
+        phase_error,
         error,
+        params.hspd,
+        new_hspd,
         get_high_speed(handle)?,
     );
-    return Ok(new_hspd);
+    Ok(new_hspd)
+}
+
+// What a run file sets on the controller and for the rest of the session, kept apart from
+// RunParameters so the file can be read without a controller (standalone compile)
+#[derive(Debug, Default)]
+struct RunFileSettings {
+    high_speed: Option<u32>,
+    low_speed: Option<u32>,
+    acceleration_time: Option<u32>,
+    deceleration_time: Option<u32>,
+    idle_time: Option<u32>,
+    microsteps: Option<u32>,
+    motor_off_on_abort: Option<bool>,
+    soft_limits: Option<SoftLimits>,
+    interlock: Option<Interlock>,
+    encoder_units: Option<UnitConversion>, // Only if EncoderResolution was given
 }
 
 fn set_run_parameters_from_file(
-    handle: &DeviceHandle<GlobalContext>,
+    handle: &dyn Transport,
     file_path: &str,
 ) -> rusb::Result<RunParameters> {
+    let (params, settings) = read_run_parameters(file_path)?;
+    apply_run_file_settings(handle, &settings)?;
+    Ok(params)
+}
+
+// Writes what the file set to the controller (HSPD, LSPD, ACC, DEC, DRVIT, DRVMS) and sets
+// the soft limits, interlock and MotorOffOnAbort
+fn apply_run_file_settings(handle: &dyn Transport, settings: &RunFileSettings) -> rusb::Result<()> {
+    if let Some(speed) = settings.high_speed {
+        set_high_speed(handle, speed)?;
+    }
+    if let Some(speed) = settings.low_speed {
+        set_low_speed(handle, speed)?;
+    }
+    if let Some(time) = settings.acceleration_time {
+        set_acceleration_time(handle, time)?;
+    }
+    if let Some(time) = settings.deceleration_time {
+        set_deceleration_time(handle, time)?;
+    }
+    if let Some(time) = settings.idle_time {
+        set_idle_time(handle, time)?;
+    }
+    if let Some(microsteps) = settings.microsteps {
+        set_microstepping(handle, microsteps)?;
+    }
+    if let Some(motor_off) = settings.motor_off_on_abort {
+        set_motor_off_on_abort(motor_off);
+    }
+    if let Some(limits) = settings.soft_limits {
+        set_soft_limits(Some(limits));
+    }
+    if let Some(interlock) = settings.interlock {
+        set_interlock(Some(interlock));
+    }
+    set_encoder_units(settings.encoder_units);
+    Ok(())
+}
+
+// Only reads the file, nothing is sent to the controller and nothing global changes
+fn read_run_parameters(file_path: &str) -> rusb::Result<(RunParameters, RunFileSettings)> {
     let mut params: RunParameters = initialize_run_parameters();
+    let mut settings = RunFileSettings::default();
 
-    let lines =
-        read_file_to_vector_of_lines(file_path).expect("Couldn't read inputs to run device");
+    // Anything given in mm can only be converted once every unit setting is known, and those
+    // can come anywhere in the file, so hold onto them until the whole file is read
+    let (mut amplitude_mm, mut offset_mm): (Option<f64>, Option<f64>) = (None, None);
+    let (mut high_speed_mm, mut low_speed_mm): (Option<f64>, Option<f64>) = (None, None);
+    let mut unload_position_mm: Option<f64> = None;
+    let (mut soft_limit_min, mut soft_limit_max): (Option<i32>, Option<i32>) = (None, None);
+    let (mut soft_limit_min_mm, mut soft_limit_max_mm): (Option<f64>, Option<f64>) = (None, None);
+    let mut encoder_configured = false;
+    let mut home_settings = HomeSettings::default();
+    let mut home_configured = false;
+    let mut force_control = ForceControlSettings::default();
+
+    let lines = read_file_to_vector_of_lines(file_path).map_err(|e| {
+        eprintln!("Couldn't read the run file {}: {}", file_path, e);
+        rusb::Error::NotFound
+    })?;
 
     for whole_line in lines {
         let line: Vec<&str> = whole_line.split_whitespace().collect();
 
-        if whole_line.is_empty() || line[0].starts_with("#") {
+        if line.is_empty() || line[0].starts_with("#") {
             continue;
         }
+        let key = line[0];
+        let Some(&value) = line.get(1) else {
+            eprintln!("{} needs a value", key);
+            return Err(rusb::Error::InvalidParam);
+        };
 
-        match line[0].to_ascii_lowercase().as_str() {
+        match key.to_ascii_lowercase().as_str() {
             "highspeed" => {
-                let _ = set_high_speed(handle, line[1].parse::<u32>().unwrap())?;
-                params.high_speed = line[1].parse().unwrap();
-                params.hspd = line[1].parse().unwrap();
+                let speed = parse_value(key, value)?;
+                settings.high_speed = Some(speed);
+                params.high_speed = speed;
+                params.hspd = speed;
             }
             "lowspeed" => {
-                let _ = set_low_speed(handle, line[1].parse::<u32>().unwrap())?;
-                params.low_speed = line[1].parse().unwrap();
+                let speed = parse_value(key, value)?;
+                settings.low_speed = Some(speed);
+                params.low_speed = speed;
             }
             "accelerationtime" => {
-                let _ = set_acceleration_time(handle, line[1].parse::<u32>().unwrap())?;
-                params.acceleration_time = line[1].parse().unwrap();
+                let time = parse_value(key, value)?;
+                settings.acceleration_time = Some(time);
+                params.acceleration_time = time;
             }
             "decelerationtime" => {
-                let _ = set_deceleration_time(handle, line[1].parse::<u32>().unwrap())?;
-                params.deceleration_time = line[1].parse().unwrap();
+                let time = parse_value(key, value)?;
+                settings.deceleration_time = Some(time);
+                params.deceleration_time = time;
             }
             "idletime" => {
-                let _ = set_idle_time(handle, line[1].parse::<u32>().unwrap())?;
-                params.idle_time = line[1].parse().unwrap();
+                let time = parse_value(key, value)?;
+                settings.idle_time = Some(time);
+                params.idle_time = time;
+            }
+
+            "amplitude" => params.amplitude = parse_value(key, value)?,
+            "dwelltime" => params.dwell_time = parse_value(key, value)?,
+            "factor" => params.factor = parse_value(key, value)?,
+            "period" => params.period = parse_value(key, value)?,
+            "offset" => params.offset = parse_value(key, value)?,
+            "loadcycles" => params.load_cycles = parse_value(key, value)?,
+
+            "microsteps" => {
+                let microsteps = parse_value(key, value)?;
+                settings.microsteps = Some(microsteps);
+                params.units.microsteps = microsteps;
+            }
+            "leadscrewpitch" => {
+                params.units.lead_screw_pitch = parse_value(key, value)?;
+                // Every conversion divides by it, NaN and inf included
+                if !params.units.lead_screw_pitch.is_finite()
+                    || params.units.lead_screw_pitch <= 0.0
+                {
+                    eprintln!("LeadScrewPitch must be above 0 mm, got {:?}", value);
+                    return Err(rusb::Error::InvalidParam);
+                }
+            }
+            "stepsperrev" => {
+                params.units.steps_per_rev = parse_value(key, value)?;
+                if params.units.steps_per_rev == 0 {
+                    eprintln!("StepsPerRev must be at least 1");
+                    return Err(rusb::Error::InvalidParam);
+                }
+            }
+            "encoderresolution" => {
+                params.units.encoder_resolution = parse_value(key, value)?;
+                if params.units.encoder_resolution == 0 {
+                    eprintln!("EncoderResolution must be at least 1");
+                    return Err(rusb::Error::InvalidParam);
+                }
+                encoder_configured = true;
+            }
+
+            "amplitudemm" => amplitude_mm = Some(parse_value(key, value)?),
+            "offsetmm" => offset_mm = Some(parse_value(key, value)?),
+            "highspeedmm" => high_speed_mm = Some(parse_value(key, value)?),
+            "lowspeedmm" => low_speed_mm = Some(parse_value(key, value)?),
+
+            "endoftest" => {
+                params.end_of_test = match value.to_ascii_lowercase().as_str() {
+                    "return" => EndOfTest::ReturnToStart,
+                    "unload" => EndOfTest::Unload,
+                    "hold" => EndOfTest::Hold,
+                    "off" => EndOfTest::MotorOff,
+                    _ => {
+                        eprintln!(
+                            "EndOfTest must be 'return', 'unload', 'hold' or 'off', got {:?}",
+                            value
+                        );
+                        return Err(rusb::Error::InvalidParam);
+                    }
+                }
+            }
+            "timingmode" => {
+                params.timing_mode = match value.to_ascii_lowercase().as_str() {
+                    "percycle" => TimingMode::PerCycle,
+                    "phaselock" => TimingMode::PhaseLock,
+                    "openloop" => TimingMode::OpenLoop,
+                    _ => {
+                        eprintln!(
+                            "TimingMode must be 'percycle', 'phaselock' or 'openloop', got {:?}",
+                            value
+                        );
+                        return Err(rusb::Error::InvalidParam);
+                    }
+                }
+            }
+            "pauseaction" => {
+                params.pause_action = match value.to_ascii_lowercase().as_str() {
+                    "hold" => PauseAction::Hold,
+                    "return" => PauseAction::ReturnToStart,
+                    _ => {
+                        eprintln!("PauseAction must be 'hold' or 'return', got {:?}", value);
+                        return Err(rusb::Error::InvalidParam);
+                    }
+                }
+            }
+            "motoroffonabort" => match value.to_ascii_lowercase().as_str() {
+                "true" => settings.motor_off_on_abort = Some(true),
+                "false" => settings.motor_off_on_abort = Some(false),
+                _ => {
+                    eprintln!("MotorOffOnAbort must be 'true' or 'false', got {:?}", value);
+                    return Err(rusb::Error::InvalidParam);
+                }
+            },
+            "unloadposition" => params.unload_position = parse_value(key, value)?,
+            "unloadpositionmm" => unload_position_mm = Some(parse_value(key, value)?),
+
+            "softlimitmin" => soft_limit_min = Some(parse_value(key, value)?),
+            "softlimitmax" => soft_limit_max = Some(parse_value(key, value)?),
+            "softlimitminmm" => soft_limit_min_mm = Some(parse_value(key, value)?),
+            "softlimitmaxmm" => soft_limit_max_mm = Some(parse_value(key, value)?),
+
+            "startinput" => params.start_input = Some(parse_value(key, value)?),
+            "standalonecounter" => {
+                params.standalone_counter = parse_value(key, value)?;
+                if !(1..=100).contains(&params.standalone_counter) {
+                    eprintln!(
+                        "StandaloneCounter must be 1 to 100 (V1 to V100), got {:?}",
+                        value
+                    );
+                    return Err(rusb::Error::InvalidParam);
+                }
+            }
+            "trigger" => params.triggers.push(parse_trigger(&line[1..])?),
+            "interlockinput" => {
+                settings.interlock = Some(Interlock {
+                    input: parse_value(key, value)?,
+                })
             }
 
-            "amplitude" => params.amplitude = line[1].parse().unwrap(),
-            "dwelltime" => params.dwell_time = line[1].parse().unwrap(),
-            "factor" => params.factor = line[1].parse().unwrap(),
-            "period" => params.period = line[1].parse().unwrap(),
-            "offset" => params.offset = line[1].parse().unwrap(),
-            "loadcycles" => params.load_cycles = line[1].parse().unwrap(),
+            key if read_home_setting(&mut home_settings, key, value)? => {
+                home_configured |= key == "homemethod";
+            }
+            key if force_control.read(key, value)? => {}
+            // The whole rest of the line, a ForcePattern can have spaces in it
+            key if read_force_setting(
+                &mut params.force,
+                key,
+                whole_line.trim_start()[line[0].len()..].trim(),
+            )? => {}
 
             _ => println!(
                 "Couldn't understand {:?}",
@@ -131,46 +447,512 @@ fn set_run_parameters_from_file(
             ),
         }
     }
-    return Ok(params);
+    params.home = home_configured.then_some(home_settings);
+    params.force_control = force_control.build()?;
+    if params.force_control.is_some() && params.force.source.is_none() {
+        eprintln!("Force control needs a load cell, set ForcePort or ForceFile");
+        return Err(rusb::Error::InvalidParam);
+    }
+
+    if let Some(mm) = amplitude_mm {
+        params.amplitude = params.units.mm_to_pulses(mm);
+    }
+    if let Some(mm) = offset_mm {
+        params.offset = params.units.mm_to_pulses(mm);
+    }
+    if let Some(mm) = unload_position_mm {
+        params.unload_position = params.units.mm_to_pulses(mm);
+    }
+    let soft_limit_min = soft_limit_min_mm
+        .map(|mm| params.units.mm_to_pulses(mm))
+        .or(soft_limit_min);
+    let soft_limit_max = soft_limit_max_mm
+        .map(|mm| params.units.mm_to_pulses(mm))
+        .or(soft_limit_max);
+    settings.encoder_units = encoder_configured.then_some(params.units);
+    params.log_encoder = encoder_configured;
+    if soft_limit_min.is_some() || soft_limit_max.is_some() {
+        settings.soft_limits = Some(SoftLimits {
+            min: soft_limit_min.unwrap_or(i32::MIN),
+            max: soft_limit_max.unwrap_or(i32::MAX),
+            encoder: encoder_configured.then_some(params.units),
+        });
+    }
+
+    if let Some(mm_per_s) = high_speed_mm {
+        let pulses_per_s = params.units.mm_per_s_to_pulses_per_s(mm_per_s);
+        settings.high_speed = Some(pulses_per_s);
+        params.high_speed = pulses_per_s;
+        params.hspd = pulses_per_s;
+    }
+    if let Some(mm_per_s) = low_speed_mm {
+        let pulses_per_s = params.units.mm_per_s_to_pulses_per_s(mm_per_s);
+        settings.low_speed = Some(pulses_per_s);
+        params.low_speed = pulses_per_s;
+    }
+
+    println!(
+        "Amplitude {} pulses ({} mm), offset {} pulses ({} mm), high speed {} pulses/s ({} mm/s)",
+        params.amplitude,
+        params.units.pulses_to_mm(params.amplitude),
+        params.offset,
+        params.units.pulses_to_mm(params.offset),
+        params.high_speed,
+        params.units.pulses_per_s_to_mm_per_s(params.high_speed),
+    );
+    Ok((params, settings))
 }
 
-pub fn run_prep(handle: &DeviceHandle<GlobalContext>) -> rusb::Result<RunParameters> {
+/// Sets the driver up for a run, reads `input_output_files/RunInput.txt`, turns the motor on
+/// and homes if the file says to. Without homing zero is wherever the stage is. Fails before
+/// anything moves if the interlock input is off.
+pub fn run_prep(handle: &dyn Transport) -> rusb::Result<RunParameters> {
+    run_prep_from(handle, "./input_output_files/RunInput.txt")
+}
+
+/// Same as run_prep with the run file at `path`.
+pub fn run_prep_from(handle: &dyn Transport, path: &str) -> rusb::Result<RunParameters> {
     set_microstepping(handle, 50)?;
     set_idle_current(handle, 100)?;
     set_run_current(handle, 2000)?;
     set_movement_type(handle, "inc")?;
-    set_pulse_position(handle, 0)?;
-    set_encoder_position(handle, 0)?;
-    let params = set_run_parameters_from_file(handle, "./input_output_files/RunInput.txt")?;
+    let params = set_run_parameters_from_file(handle, path)?;
     write_driver_settings(handle)?;
+    check_interlock(handle)?;
     turn_motor_on(handle)?;
-    return Ok(params);
+    match &params.home {
+        Some(settings) => home(handle, settings)?,
+        None => {
+            set_pulse_position(handle, 0)?;
+            set_encoder_position(handle, 0)?;
+        }
+    }
+    Ok(params)
 }
 
-pub fn run(handle: &DeviceHandle<GlobalContext>) -> rusb::Result<()> {
-    let mut params = run_prep(handle)?;
-    let pos_file = &mut Some(BufWriter::new(
-        File::create("./input_output_files/RunOutput.txt").unwrap(),
-    ));
+// Movement is incremental during a run, so absolute targets are turned into a move
+// from wherever the stage is now
+fn move_to_absolute_position(handle: &dyn Transport, target: i32) -> rusb::Result<()> {
+    let current = get_pulse_position(handle)?;
+    move_stage(handle, target - current)?;
+    wait_for_motor_idle(handle, &mut None)?;
+    Ok(())
+}
+
+// Reads the positions back after the end of test action so we know it actually happened.
+// A wrong pulse position is an error, the encoder only warns since its resolution
+// may not be configured.
+fn confirm_position(
+    handle: &dyn Transport,
+    params: &RunParameters,
+    target: Option<i32>, // None when the stage wasn't moved, only the encoder is checked
+) -> rusb::Result<()> {
+    let pulse_position = get_pulse_position(handle)?;
+    let encoder_position = get_encoder_position(handle)?;
+    println!(
+        "End of test position: PX={} ({} mm), EX={} ({} mm)",
+        pulse_position,
+        params.units.pulses_to_mm(pulse_position),
+        encoder_position,
+        params.units.encoder_to_mm(encoder_position),
+    );
 
+    if let Some(target) = target {
+        if pulse_position != target {
+            eprintln!(
+                "End of test move failed, expected PX={} but read PX={}",
+                target, pulse_position
+            );
+            return Err(rusb::Error::Other);
+        }
+    }
+
+    let encoder_error_mm = (params.units.encoder_to_mm(encoder_position)
+        - params.units.pulses_to_mm(pulse_position))
+    .abs();
+    if encoder_error_mm > ENCODER_TOLERANCE_MM {
+        eprintln!(
+            "WARNING: Encoder is {} mm away from the pulse position, steps may have been lost",
+            encoder_error_mm
+        );
+    }
+    Ok(())
+}
+
+fn end_of_test(handle: &dyn Transport, params: &RunParameters) -> rusb::Result<()> {
+    match params.end_of_test {
+        EndOfTest::ReturnToStart => {
+            move_to_absolute_position(handle, params.start_position)?;
+            confirm_position(handle, params, Some(params.start_position))?;
+        }
+        EndOfTest::Unload => {
+            move_to_absolute_position(handle, params.unload_position)?;
+            confirm_position(handle, params, Some(params.unload_position))?;
+        }
+        EndOfTest::Hold => confirm_position(handle, params, None)?,
+        EndOfTest::MotorOff => {
+            turn_motor_off(handle)?;
+            if get_motor_enabled(handle)? {
+                eprintln!("End of test failed, motor is still on after EO=0");
+                return Err(rusb::Error::Other);
+            }
+            confirm_position(handle, params, None)?;
+        }
+    }
+    Ok(())
+}
+
+// Catches a mistyped amplitude or offset before anything moves instead of partway through
+fn check_travel_within_limits(params: &RunParameters) -> rusb::Result<()> {
+    let limits = match get_soft_limits() {
+        None => return Ok(()),
+        Some(limits) => limits,
+    };
+
+    let top = params.start_position - params.offset;
+    let bottom = top - params.amplitude;
+    let end = match params.end_of_test {
+        EndOfTest::Unload => params.unload_position,
+        _ => params.start_position,
+    };
+    for position in [top, bottom, end] {
+        if !limits.contains(position) {
+            eprintln!(
+                "Run would move to {} which is outside the soft limits [{}, {}]",
+                position, limits.min, limits.max
+            );
+            return Err(rusb::Error::InvalidParam);
+        }
+    }
+    Ok(())
+}
+
+// Notes in the output file start with # so they're skipped as comments when it's loaded
+fn write_log_note(log: &mut PositionLog, note: &str) {
+    let line = format!("# {} at {} s\n", note, log.time.elapsed().as_secs_f64());
+    if let Err(e) = log
+        .file
+        .write_all(line.as_bytes())
+        .and_then(|_| log.file.flush())
+    {
+        eprintln!("Failed to write '{}' to the output file: {}", note, e);
+    }
+}
+
+// Ctrl-C lands here as Interrupted. Everything already logged is kept and the file says where
+// the run stopped, so a half finished test is still usable.
+fn write_abort_trailer(log: &mut PositionLog, cycle: u32) {
+    write_log_note(log, &format!("aborted at cycle {}", cycle));
+}
+
+// Holds at a cycle boundary for as long as a pause is asked for. Returns how long that took,
+// moves to the start and back included, so the speed correction can leave the pause out
+// instead of trying to make the time up.
+fn pause_between_cycles(
+    handle: &dyn Transport,
+    params: &RunParameters,
+    log: &mut PositionLog,
+    cycle: u32,
+) -> rusb::Result<Duration> {
+    if !pause_requested() {
+        return Ok(Duration::ZERO);
+    }
+
+    let paused = Instant::now();
+    let position = get_pulse_position(handle)?;
+    write_log_note(log, &format!("paused after cycle {}", cycle));
+    if params.pause_action == PauseAction::ReturnToStart {
+        println!("Returning to the start position while paused");
+        move_to_absolute_position(handle, params.start_position)?;
+    }
+
+    wait_while_paused(handle)?;
+
+    if params.pause_action == PauseAction::ReturnToStart {
+        println!("Moving back to the offset");
+        move_to_absolute_position(handle, position)?;
+        sleep_or_abort(handle, Duration::from_secs(1))?;
+    }
+    write_log_note(
+        log,
+        &format!("resumed after {} s paused", paused.elapsed().as_secs_f64()),
+    );
+    Ok(paused.elapsed())
+}
+
+// Where the next cycle should turn around to hit the forces, kept inside the soft limits.
+// Errors if the limits squash the cycle flat, there'd be no stiffness to get out of it with.
+fn next_force_targets(
+    params: &RunParameters,
+    control: &ForceControl,
+    targets: CycleTargets,
+    forces: CycleForces,
+    cycle: u32,
+) -> rusb::Result<CycleTargets> {
+    let (next, stiffness) = match control.next_targets(targets, forces) {
+        Some(next) => next,
+        None => {
+            println!(
+                "cycle={}\tforce_peak={:?}\tforce_valley={:?}\tno usable force readings, targets unchanged",
+                cycle, forces.peak, forces.valley
+            );
+            return Ok(targets);
+        }
+    };
+    let next = match get_soft_limits() {
+        None => next,
+        Some(limits) => match next.within_limits(params.start_position - params.offset, &limits) {
+            Some(clamped) => clamped,
+            None => {
+                eprintln!(
+                    "Force control wants the cycle between {} and {} pulses down, the soft limits leave no travel between them",
+                    next.valley, next.peak
+                );
+                return Err(rusb::Error::Other);
+            }
+        },
+    };
+    println!(
+        "cycle={}\tforce_peak={}\tforce_valley={}\tstiffness={}\tpeak={}->{}\tvalley={}->{}",
+        cycle,
+        forces.peak.unwrap_or(f64::NAN),
+        forces.valley.unwrap_or(f64::NAN),
+        stiffness,
+        targets.peak,
+        next.peak,
+        targets.valley,
+        next.valley
+    );
+    Ok(next)
+}
+
+// The moving part of a run. `cycle` is kept up to date so an abort can say where it stopped,
+// 0 means before the first cycle.
+fn run_cycles(
+    handle: &dyn Transport,
+    params: &mut RunParameters,
+    pos_log: &mut Option<PositionLog>,
+    cycle: &mut u32,
+    progress: &mut dyn FnMut(&RunProgress),
+) -> rusb::Result<()> {
     set_high_speed(handle, 1500)?;
     move_stage(handle, -params.offset)?;
     set_high_speed(handle, params.hspd)?;
-    wait_for_motor_idle(handle, &mut None, None)?;
-    sleep(std::time::Duration::from_secs(1));
+    wait_for_motor_idle(handle, &mut None)?;
+    sleep_or_abort(handle, Duration::from_secs(1))?;
+
+    // An external start button, or another instrument saying it's ready
+    if let Some(input) = params.start_input {
+        println!("Waiting for input {} to start", input);
+        wait_for_input_edge(handle, input, Edge::Rising, None)?;
+    }
 
-    let time = Some(Instant::now());
-    for cycle in 1..params.load_cycles + 1 {
-        move_cycle_get_time(handle, params.amplitude, pos_file, time, params.dwell_time)?;
-        adjust_speed(
+    let triggers = Triggers::new(handle, &params.triggers)?;
+    let force_control = params.force_control;
+    // Displacement control keeps these at Amplitude and 0, force control moves them every cycle
+    let mut targets = CycleTargets {
+        peak: params.amplitude,
+        valley: 0,
+    };
+    let mut last_valley = 0;
+    let mut time = Instant::now();
+    pos_log.as_mut().unwrap().time = time;
+    for current_cycle in 1..params.load_cycles + 1 {
+        // The log keeps wall clock time, only the speed correction skips over a pause
+        time += pause_between_cycles(handle, params, pos_log.as_mut().unwrap(), current_cycle - 1)?;
+        *cycle = current_cycle;
+        let mut forces = CycleForces::default();
+        let cycle_time = move_cycle_get_time_with(
             handle,
-            &params,
-            time.unwrap().elapsed().as_secs_f64(),
-            cycle,
+            targets.peak - last_valley,
+            targets.peak - targets.valley,
+            pos_log,
+            params.dwell_time,
+            &mut |phase, log| {
+                let log = log.as_mut().unwrap();
+                // The first reading after the motor stopped, so it's the force at the turnaround
+                let reading = |log: &PositionLog| {
+                    log.force
+                        .as_ref()?
+                        .reading_after(Instant::now(), FORCE_READING_TIMEOUT)
+                        .map(|sample| sample.force)
+                };
+                match (phase, force_control.is_some()) {
+                    (CyclePhase::Peak, true) => forces.peak = reading(log),
+                    (CyclePhase::Valley, true) => forces.valley = reading(log),
+                    _ => (),
+                }
+                triggers.fire(handle, phase, current_cycle, log)
+            },
         )?;
+        last_valley = targets.valley;
+        if let Some(control) = &force_control {
+            targets = next_force_targets(params, control, targets, forces, current_cycle)?;
+        }
+        let elapsed = time.elapsed().as_secs_f64();
+        params.hspd = adjust_speed(handle, params, cycle_time, elapsed, current_cycle)?;
+        let cycle_progress = RunProgress {
+            cycle: current_cycle,
+            load_cycles: params.load_cycles,
+            elapsed,
+            period_error: elapsed - params.period * current_cycle as f64,
+            hspd: params.hspd,
+        };
+        record_cycle(&cycle_progress);
+        progress(&cycle_progress);
     }
-    move_stage(handle, params.offset + 4913)?;
-    wait_for_motor_idle(handle, &mut None, None)?;
+    Ok(())
+}
+
+/// Runs a full cyclic test from `input_output_files/RunInput.txt`, logging to
+/// `input_output_files/RunOutput.txt`. Returns `Interrupted` if aborted with Ctrl-C.
+pub fn run(handle: &dyn Transport) -> rusb::Result<()> {
+    run_with_progress(handle, &mut |_| {})
+}
+
+/// Same as run, calling `progress` after every cycle.
+pub fn run_with_progress(
+    handle: &dyn Transport,
+    progress: &mut dyn FnMut(&RunProgress),
+) -> rusb::Result<()> {
+    let _abort_scope = AbortScope::enter();
+    let mut params = run_prep(handle)?;
+    params.start_position = get_pulse_position(handle)?;
+    check_travel_within_limits(&params)?;
+    let force = params.force.start()?;
+    let pos_log = &mut Some(
+        PositionLog::with_columns(
+            "./input_output_files/RunOutput.txt",
+            params.units,
+            params.log_encoder,
+            force,
+        )
+        .unwrap(),
+    );
+
+    let mut cycle = 0;
+    if let Err(e) = run_cycles(handle, &mut params, pos_log, &mut cycle, progress) {
+        if e == rusb::Error::Interrupted {
+            write_abort_trailer(pos_log.as_mut().unwrap(), cycle);
+            println!("Run aborted at cycle {}", cycle);
+        }
+        return Err(e);
+    }
+    end_of_test(handle, &params)?;
     std::thread::sleep(std::time::Duration::from_secs(1));
-    return Ok(());
+    Ok(())
+}
+
+// Things a standalone program can't do, they're left out with a warning rather than an error so
+// the same RunInput.txt works for both
+fn standalone_protocol_from(params: &RunParameters) -> StandaloneProtocol {
+    let ignored = [
+        (params.force_control.is_some(), "ForceMin/ForceMax"),
+        (!params.triggers.is_empty(), "Trigger"),
+        (params.start_input.is_some(), "StartInput"),
+        (params.force.source.is_some(), "ForcePort/ForceFile"),
+        (
+            params.timing_mode != TimingMode::OpenLoop,
+            "TimingMode (it's always openloop)",
+        ),
+    ];
+    for (_, name) in ignored.iter().filter(|(set, _)| *set) {
+        println!("WARNING: {} is ignored by standalone programs", name);
+    }
+    StandaloneProtocol {
+        offset: params.offset,
+        amplitude: params.amplitude,
+        high_speed: params.high_speed,
+        low_speed: params.low_speed,
+        acceleration_time: params.acceleration_time,
+        deceleration_time: params.deceleration_time,
+        dwell_time: params.dwell_time,
+        load_cycles: params.load_cycles,
+        counter: params.standalone_counter,
+    }
+}
+
+/// Reads `input_output_files/RunInput.txt` as a standalone program. Only the file is read,
+/// nothing is sent to the controller.
+pub fn standalone_protocol() -> rusb::Result<StandaloneProtocol> {
+    let (params, _) = read_run_parameters("./input_output_files/RunInput.txt")?;
+    Ok(standalone_protocol_from(&params))
+}
+
+/// Same as run with the stored standalone program doing the cycles. Prepares the same way
+/// (driver, homing), starts the program, logs position to `input_output_files/RunOutput.txt`
+/// while it runs and does the EndOfTest move after. The program has to match RunInput.txt,
+/// see standalone.
+pub fn run_standalone(handle: &dyn Transport) -> rusb::Result<()> {
+    let mut params = run_prep(handle)?;
+    params.start_position = get_pulse_position(handle)?;
+    check_travel_within_limits(&params)?;
+    let protocol = standalone_protocol_from(&params);
+    let mut pos_log = PositionLog::with_columns(
+        "./input_output_files/RunOutput.txt",
+        params.units,
+        params.log_encoder,
+        None,
+    )
+    .unwrap();
+
+    if let Err(e) = run_program(handle, &protocol, &mut pos_log) {
+        if e == rusb::Error::Interrupted {
+            write_log_note(&mut pos_log, "standalone program stopped");
+            println!("Standalone run aborted");
+        }
+        return Err(e);
+    }
+    // The program leaves the motor at the offset like run does
+    set_movement_type(handle, "inc")?;
+    set_high_speed(handle, params.high_speed)?;
+    end_of_test(handle, &params)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn run_file(name: &str, contents: &str) -> String {
+        let path = std::env::temp_dir().join(format!("loader_{}_{}.txt", name, std::process::id()));
+        std::fs::write(&path, contents).unwrap();
+        path.to_str().unwrap().to_string()
+    }
+
+    #[test]
+    fn reads_a_run_file() {
+        let path = run_file(
+            "run_ok",
+            "# Comment\nHighSpeed 12345\n   \r\nAmplitude -200\nStandaloneCounter 100\n",
+        );
+        let (params, settings) = read_run_parameters(&path).unwrap();
+        assert_eq!(params.hspd, 12345);
+        assert_eq!(settings.high_speed, Some(12345));
+        assert_eq!(params.amplitude, -200);
+        assert_eq!(params.standalone_counter, 100);
+    }
+
+    #[test]
+    fn bad_values_are_errors() {
+        for (name, contents) in [
+            ("run_not_a_number", "HighSpeed fast\n"),
+            ("run_no_value", "Amplitude\n"),
+            ("run_inf_pitch", "LeadScrewPitch inf\n"),
+            ("run_zero_pitch", "LeadScrewPitch 0\n"),
+            ("run_counter_0", "StandaloneCounter 0\n"),
+            ("run_counter_101", "StandaloneCounter 101\n"),
+        ] {
+            let path = run_file(name, contents);
+            assert_eq!(
+                read_run_parameters(&path).err(),
+                Some(rusb::Error::InvalidParam),
+                "{:?}",
+                contents
+            );
+        }
+        assert_eq!(
+            read_run_parameters("./no/such/RunInput.txt").err(),
+            Some(rusb::Error::NotFound)
+        );
+    }
 }
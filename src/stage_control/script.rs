@@ -0,0 +1,526 @@
+//! Script files: the command sequences you'd otherwise type into interactive mode, plus waits,
+//! checks on the replies, variables and loops.
+//!
+//! ```text
+//! # Read the driver settings, raise the idle time and write them back
+//! RR
+//! SLEEP 2s
+//! R2
+//! EXPECT 1
+//! SET idle 100
+//! DRVIT=$idle
+//! RW
+//! SLEEP 2s
+//! R4
+//! EXPECT 1
+//! EO=1
+//!
+//! # Preload
+//! SET step 500
+//! REPEAT 4 n
+//!     X-$step
+//!     WAIT_IDLE
+//!     SLEEP 500ms
+//! END
+//! ```
+//!
+//! - Any other line is sent to the controller like in interactive mode, so it is checked
+//!   against the command table and the soft limits first. LIMITS, WATCHDOG and INTERLOCK work
+//!   too.
+//! - `WAIT_IDLE` waits for the motor to stop. `SLEEP` takes `2s`, `500ms` or plain seconds.
+//! - `WAIT_INPUT input [rising|falling|any] [timeout]` waits for a digital input to change,
+//!   a timeout running out stops the script.
+//! - `EXPECT reply` stops the script unless the last reply was exactly `reply`.
+//! - `SET name value` sets `$name` (or `${name}`), names are case insensitive.
+//!   `REPEAT count [name] ... END` runs the lines in between count times, with `$name` going
+//!   from 1 to count.
+//!
+//! The whole file is checked before anything is sent. Every command and reply is printed and
+//! logged, with the time since the start, to the script's path with `.log` added on the end
+//! (`preload.txt.log`). Scripts can't run other scripts, `@file` only works in interactive
+//! mode.
+
+use crate::stage_control::{
+    abort::{check_abort, sleep_or_abort, AbortScope},
+    commands::{
+        send_interactive_command, set_interlock_from_interactive_command,
+        set_limits_from_interactive_command, set_watchdog_from_interactive_command, stop,
+        wait_for_motor_idle,
+    },
+    driver::Transport,
+    io::{parse_wait_input, wait_for_input_edge},
+    watchdog::{JogWatchdog, WatchdogSettings},
+};
+
+use rusb::Result;
+
+use std::{
+    collections::HashMap,
+    fs::File,
+    io::{BufWriter, Write},
+    time::{Duration, Instant},
+};
+
+#[derive(Debug)]
+enum Step {
+    Send(String),
+    Local(String), // LIMITS, WATCHDOG and INTERLOCK, handled like in interactive mode
+    WaitIdle,
+    WaitInput(String),
+    Sleep(String),
+    Expect(String),
+    Set(String, String),
+    Repeat {
+        count: String,
+        counter: Option<String>,
+        body: Vec<Line>,
+    },
+}
+
+#[derive(Debug)]
+struct Line {
+    number: usize,
+    step: Step,
+}
+
+fn is_variable_name(name: &str) -> bool {
+    !name.is_empty() && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+// Checked at parse time when there's no variable in it, otherwise when it's reached
+pub(crate) fn parse_duration(text: &str) -> std::result::Result<Duration, String> {
+    let (number, scale) = match text.to_ascii_lowercase() {
+        text if text.ends_with("ms") => (text.trim_end_matches("ms").to_string(), 1e-3),
+        text if text.ends_with('s') => (text.trim_end_matches('s').to_string(), 1.0),
+        text => (text, 1.0),
+    };
+    match number.trim().parse::<f64>() {
+        Ok(seconds) if seconds >= 0.0 && seconds.is_finite() => {
+            Ok(Duration::from_secs_f64(seconds * scale))
+        }
+        _ => Err(format!(
+            "SLEEP needs a time like 2s or 500ms, got '{}'",
+            text
+        )),
+    }
+}
+
+fn parse_count(text: &str) -> std::result::Result<u32, String> {
+    text.parse()
+        .map_err(|_| format!("REPEAT needs a whole number of times, got '{}'", text))
+}
+
+fn parse_step(text: &str) -> std::result::Result<Step, String> {
+    let (keyword, rest) = match text.split_once(char::is_whitespace) {
+        Some((keyword, rest)) => (keyword.to_ascii_uppercase(), rest.trim()),
+        None => (text.to_ascii_uppercase(), ""),
+    };
+    let args: Vec<&str> = rest.split_whitespace().collect();
+
+    let step = match (keyword.as_str(), args.as_slice()) {
+        ("WAIT_IDLE", []) => Step::WaitIdle,
+        ("WAIT_INPUT", [_, ..]) => Step::WaitInput(rest.to_string()),
+        ("SLEEP", [time]) => Step::Sleep(time.to_string()),
+        ("EXPECT", [_, ..]) => Step::Expect(rest.to_string()),
+        ("SET", [name, value]) | ("SET", [name, "=", value]) if is_variable_name(name) => {
+            Step::Set(name.to_ascii_uppercase(), value.to_string())
+        }
+        ("REPEAT", [count]) => Step::Repeat {
+            count: count.to_string(),
+            counter: None,
+            body: Vec::new(),
+        },
+        ("REPEAT", [count, name]) if is_variable_name(name) => Step::Repeat {
+            count: count.to_string(),
+            counter: Some(name.to_ascii_uppercase()),
+            body: Vec::new(),
+        },
+        ("LIMITS" | "WATCHDOG" | "INTERLOCK", _) => Step::Local(text.to_string()),
+        ("WAIT_IDLE" | "WAIT_INPUT" | "SLEEP" | "EXPECT" | "SET" | "REPEAT", _) => {
+            return Err(format!(
+                "Usage: WAIT_IDLE, WAIT_INPUT input [edge] [timeout], SLEEP time, EXPECT reply, SET name value or REPEAT count [name], got '{}'",
+                text
+            ))
+        }
+        ("EXIT" | "HELP", _) => return Err(format!("{} only works in interactive mode", keyword)),
+        (keyword, _) if keyword.starts_with('@') => {
+            return Err("Scripts can't run other scripts, @file only works in interactive mode".to_string())
+        }
+        _ => Step::Send(text.to_string()),
+    };
+
+    // Literal values can be checked now rather than halfway through the script
+    match &step {
+        Step::Sleep(time) if !time.contains('$') => {
+            parse_duration(time)?;
+        }
+        Step::Repeat { count, .. } if !count.contains('$') => {
+            parse_count(count)?;
+        }
+        Step::WaitInput(args) if !args.contains('$') => {
+            parse_wait_input(&args.split_whitespace().collect::<Vec<_>>())?;
+        }
+        _ => (),
+    }
+    Ok(step)
+}
+
+// Errors come back as "line N: what's wrong"
+fn parse_script(text: &str) -> std::result::Result<Vec<Line>, String> {
+    // The outermost list plus one per open REPEAT
+    let mut blocks: Vec<(usize, Step, Vec<Line>)> = Vec::new();
+    let mut lines: Vec<Line> = Vec::new();
+
+    for (i, raw) in text.lines().enumerate() {
+        let number = i + 1;
+        let text = raw.split('#').next().unwrap_or("").trim();
+        if text.is_empty() {
+            continue;
+        }
+
+        if text.eq_ignore_ascii_case("END") {
+            let (start, step, outer) = blocks
+                .pop()
+                .ok_or(format!("line {}: END without a REPEAT", number))?;
+            let step = match step {
+                Step::Repeat { count, counter, .. } => Step::Repeat {
+                    count,
+                    counter,
+                    body: std::mem::replace(&mut lines, outer),
+                },
+                step => step,
+            };
+            lines.push(Line {
+                number: start,
+                step,
+            });
+            continue;
+        }
+
+        match parse_step(text).map_err(|e| format!("line {}: {}", number, e))? {
+            step @ Step::Repeat { .. } => blocks.push((number, step, std::mem::take(&mut lines))),
+            step => lines.push(Line { number, step }),
+        }
+    }
+
+    match blocks.last() {
+        Some((start, _, _)) => Err(format!("line {}: REPEAT without an END", start)),
+        None => Ok(lines),
+    }
+}
+
+struct ScriptRun<'a> {
+    handle: &'a dyn Transport,
+    watchdog: &'a JogWatchdog,
+    variables: HashMap<String, String>,
+    last_response: Option<String>,
+    log: BufWriter<File>,
+    start: Instant,
+}
+
+impl ScriptRun<'_> {
+    // Printed and logged, the log is what's left to look at after the script has finished
+    fn log(&mut self, message: &str) {
+        println!("{}", message);
+        let elapsed = self.start.elapsed().as_secs_f64();
+        if let Err(e) = writeln!(self.log, "{:.3}\t{}", elapsed, message) {
+            eprintln!("WARNING: Failed to write the script log: {}", e);
+        }
+    }
+
+    // $name and ${name}, a name we haven't seen is an error rather than an empty string
+    fn substitute(&self, text: &str) -> std::result::Result<String, String> {
+        let mut result = String::new();
+        let mut rest = text;
+        while let Some(i) = rest.find('$') {
+            result.push_str(&rest[..i]);
+            rest = &rest[i + 1..];
+            let (name, after) = match rest.strip_prefix('{') {
+                Some(braced) => match braced.split_once('}') {
+                    Some((name, after)) => (name, after),
+                    None => return Err(format!("No closing }} in '{}'", text)),
+                },
+                None => {
+                    let end = rest
+                        .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_'))
+                        .unwrap_or(rest.len());
+                    (&rest[..end], &rest[end..])
+                }
+            };
+            match self.variables.get(&name.to_ascii_uppercase()) {
+                Some(value) => result.push_str(value),
+                None => return Err(format!("${} isn't set", name)),
+            }
+            rest = after;
+        }
+        result.push_str(rest);
+        Ok(result)
+    }
+
+    fn run_lines(&mut self, lines: &[Line]) -> Result<()> {
+        for line in lines {
+            check_abort(self.handle)?;
+            if let Err(message) = self.run_step(&line.step)? {
+                self.log(&format!("# line {}: {}", line.number, message));
+                return Err(rusb::Error::Other);
+            }
+        }
+        Ok(())
+    }
+
+    // USB errors come out as Err, a script that can't carry on as Ok(Err(reason))
+    fn run_step(&mut self, step: &Step) -> Result<std::result::Result<(), String>> {
+        let text = |text: &str| self.substitute(text);
+        match step {
+            Step::Send(command) => {
+                let command = match text(command) {
+                    Ok(command) => command.to_ascii_uppercase(),
+                    Err(e) => return Ok(Err(e)),
+                };
+                match send_interactive_command(self.handle, self.watchdog, &command)? {
+                    Some(response) => {
+                        self.log(&format!("{} --> {}", command, response));
+                        self.last_response = Some(response);
+                    }
+                    None => return Ok(Err(format!("{} wasn't sent", command))),
+                }
+            }
+            Step::Local(command) => {
+                let command = match text(command) {
+                    Ok(command) => command.to_ascii_uppercase(),
+                    Err(e) => return Ok(Err(e)),
+                };
+                self.log(&format!("# {}", command));
+                match command.split_whitespace().next() {
+                    Some("LIMITS") => set_limits_from_interactive_command(&command),
+                    Some("INTERLOCK") => set_interlock_from_interactive_command(&command),
+                    _ => set_watchdog_from_interactive_command(self.watchdog, &command),
+                }
+            }
+            Step::WaitIdle => {
+                wait_for_motor_idle(self.handle, &mut None)?;
+                self.log("# WAIT_IDLE done");
+            }
+            Step::WaitInput(args) => {
+                let args = match text(args) {
+                    Ok(args) => args,
+                    Err(e) => return Ok(Err(e)),
+                };
+                let (input, edge, timeout) =
+                    match parse_wait_input(&args.split_whitespace().collect::<Vec<_>>()) {
+                        Ok(wait) => wait,
+                        Err(e) => return Ok(Err(e)),
+                    };
+                match wait_for_input_edge(self.handle, input, edge, timeout)? {
+                    true => self.log(&format!("# WAIT_INPUT {} done", args)),
+                    false => return Ok(Err(format!("WAIT_INPUT {} timed out", args))),
+                }
+            }
+            Step::Sleep(time) => {
+                let duration = match text(time).and_then(|time| parse_duration(&time)) {
+                    Ok(duration) => duration,
+                    Err(e) => return Ok(Err(e)),
+                };
+                sleep_or_abort(self.handle, duration)?;
+            }
+            Step::Expect(expected) => {
+                let expected = match text(expected) {
+                    Ok(expected) => expected,
+                    Err(e) => return Ok(Err(e)),
+                };
+                match &self.last_response {
+                    Some(response) if *response == expected => {
+                        self.log(&format!("# EXPECT {} ok", expected))
+                    }
+                    Some(response) => {
+                        return Ok(Err(format!(
+                            "EXPECT {} failed, the reply was {}",
+                            expected, response
+                        )))
+                    }
+                    None => return Ok(Err(format!("EXPECT {} before any reply", expected))),
+                }
+            }
+            Step::Set(name, value) => match text(value) {
+                Ok(value) => {
+                    self.variables.insert(name.clone(), value);
+                }
+                Err(e) => return Ok(Err(e)),
+            },
+            Step::Repeat {
+                count,
+                counter,
+                body,
+            } => {
+                let count = match text(count).and_then(|count| parse_count(&count)) {
+                    Ok(count) => count,
+                    Err(e) => return Ok(Err(e)),
+                };
+                for i in 1..=count {
+                    if let Some(counter) = counter {
+                        self.variables.insert(counter.clone(), i.to_string());
+                    }
+                    self.run_lines(body)?;
+                }
+            }
+        }
+        Ok(Ok(()))
+    }
+}
+
+/// Runs a script file on its own, with a jog watchdog like interactive mode.
+pub fn run_script(handle: &dyn Transport, path: &str) -> Result<()> {
+    let watchdog = JogWatchdog::new(WatchdogSettings::default());
+    watchdog.watch_while(handle, || run_script_with_watchdog(handle, &watchdog, path))
+}
+
+/// Runs a script file. A script that doesn't parse, fails an EXPECT or has a command refused
+/// stops the motor and returns Other (InvalidParam if nothing was sent), Ctrl-C returns
+/// Interrupted.
+pub fn run_script_with_watchdog(
+    handle: &dyn Transport,
+    watchdog: &JogWatchdog,
+    path: &str,
+) -> Result<()> {
+    let text = match std::fs::read_to_string(path) {
+        Ok(text) => text,
+        Err(e) => {
+            eprintln!("Couldn't read script {}: {}", path, e);
+            return Err(rusb::Error::InvalidParam);
+        }
+    };
+    let lines = match parse_script(&text) {
+        Ok(lines) => lines,
+        Err(e) => {
+            eprintln!("{}: {}, nothing was sent", path, e);
+            return Err(rusb::Error::InvalidParam);
+        }
+    };
+
+    // Added rather than swapped, so foo.log doesn't log over itself and a.txt and a.nsc don't
+    // share a log
+    let log_path = format!("{}.log", path);
+    let log = match File::create(&log_path) {
+        Ok(file) => BufWriter::new(file),
+        Err(e) => {
+            eprintln!("Couldn't create the script log {}: {}", log_path, e);
+            return Err(rusb::Error::InvalidParam);
+        }
+    };
+
+    let mut script = ScriptRun {
+        handle,
+        watchdog,
+        variables: HashMap::new(),
+        last_response: None,
+        log,
+        start: Instant::now(),
+    };
+    script.log(&format!("# Running {}", path));
+
+    let result = {
+        let _scope = AbortScope::enter();
+        script.run_lines(&lines)
+    };
+
+    match &result {
+        Ok(()) => script.log("# Done"),
+        Err(rusb::Error::Interrupted) => script.log("# Aborted"),
+        Err(e) => {
+            // Whatever the script was doing shouldn't carry on without it
+            if let Err(stop_error) = stop(handle) {
+                eprintln!("Failed to stop the motor: {}", stop_error);
+            }
+            script.log(&format!("# Stopped: {}", e));
+        }
+    }
+    if let Err(e) = script.log.flush() {
+        eprintln!("WARNING: Failed to write the script log: {}", e);
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // What each line turned into, REPEAT bodies indented
+    fn outline(lines: &[Line]) -> Vec<String> {
+        let mut out = Vec::new();
+        for line in lines {
+            match &line.step {
+                Step::Repeat { count, body, .. } => {
+                    out.push(format!("{}: REPEAT {}", line.number, count));
+                    out.extend(outline(body).iter().map(|inner| format!("  {}", inner)));
+                }
+                step => out.push(format!("{}: {:?}", line.number, step)),
+            }
+        }
+        out
+    }
+
+    #[test]
+    fn skips_comments_and_blank_lines() {
+        let script = "# Preload\n\nX100 # out\n   # indented comment\nWAIT_IDLE\n";
+        assert_eq!(
+            outline(&parse_script(script).unwrap()),
+            vec!["3: Send(\"X100\")", "5: WaitIdle"]
+        );
+    }
+
+    #[test]
+    fn nests_repeats_and_keeps_line_numbers() {
+        let script =
+            "SET step 5\nREPEAT 2 n\n  REPEAT 3\n    X$step\n  END\n  sleep 1s\nEND\nlimits 0 100";
+        assert_eq!(
+            outline(&parse_script(script).unwrap()),
+            vec![
+                "1: Set(\"STEP\", \"5\")",
+                "2: REPEAT 2",
+                "  3: REPEAT 3",
+                "    4: Send(\"X$step\")",
+                "  6: Sleep(\"1s\")",
+                "8: Local(\"limits 0 100\")",
+            ]
+        );
+    }
+
+    #[test]
+    fn refuses_other_scripts() {
+        assert_eq!(
+            parse_script("X100\n@other.txt").unwrap_err(),
+            "line 2: Scripts can't run other scripts, @file only works in interactive mode"
+        );
+    }
+
+    #[test]
+    fn says_which_line_is_wrong() {
+        let error = |script: &str| parse_script(script).unwrap_err();
+        assert_eq!(error("REPEAT 2\nX1"), "line 1: REPEAT without an END");
+        assert_eq!(error("X1\nEND"), "line 2: END without a REPEAT");
+        assert_eq!(
+            error("REPEAT lots\nEND"),
+            "line 1: REPEAT needs a whole number of times, got 'lots'"
+        );
+        assert_eq!(
+            error("SLEEP soon"),
+            "line 1: SLEEP needs a time like 2s or 500ms, got 'soon'"
+        );
+        assert_eq!(
+            error("STOP\nEXIT"),
+            "line 2: EXIT only works in interactive mode"
+        );
+        assert!(error("SET 1").starts_with("line 1: Usage: "));
+        // Only checked when reached if there's a variable in it
+        assert!(parse_script("SLEEP $wait").is_ok());
+    }
+
+    #[test]
+    fn reads_durations() {
+        assert_eq!(parse_duration("2s"), Ok(Duration::from_secs(2)));
+        assert_eq!(parse_duration("500MS"), Ok(Duration::from_millis(500)));
+        assert_eq!(parse_duration("1.5"), Ok(Duration::from_millis(1500)));
+        assert!(parse_duration("-1s").is_err());
+        assert!(parse_duration("infs").is_err());
+    }
+}
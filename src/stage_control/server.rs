@@ -0,0 +1,577 @@
+//! JSON-RPC 2.0 server so other processes, or other machines in the lab, can monitor and
+//! control the loader while this process owns the USB handle.
+//!
+//! One request or response per line over TCP or a Unix socket. `subscribe` starts a stream
+//! of `status` or `position` notifications on the same connection.
+
+use crate::stage_control::{
+    abort::{is_paused, request_abort, request_pause, request_resume},
+    commands::{
+        get_encoder_position, get_high_speed, get_idle_current, get_idle_time, get_microstepping,
+        get_motor_enabled, get_motor_status, get_pulse_position, get_run_current, move_stage,
+        read_driver_settings, send_interactive_command, set_acceleration_time,
+        set_deceleration_time, set_encoder_position, set_high_speed, set_idle_current,
+        set_idle_time, set_low_speed, set_microstepping, set_movement_type, set_pulse_position,
+        set_run_current, stop, turn_motor_off, turn_motor_on, wait_for_motor_idle,
+        write_driver_settings,
+    },
+    driver::Transport,
+    run::{run_with_progress, RunProgress},
+    watchdog::{JogWatchdog, WatchdogSettings},
+};
+
+use serde_json::{json, Value};
+
+use std::{
+    io::{BufRead, BufReader, Read, Write},
+    net::TcpListener,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+/// Where serve listens if nothing else is given. Only this machine can connect.
+pub const DEFAULT_ADDRESS: &str = "127.0.0.1:7878";
+
+// Streams faster than this would mostly just fight the run for the bus
+const MIN_STREAM_INTERVAL: Duration = Duration::from_millis(20);
+const DEFAULT_STREAM_INTERVAL: Duration = Duration::from_millis(500);
+
+// Standard JSON-RPC codes, then ours
+const PARSE_ERROR: i64 = -32700;
+const INVALID_REQUEST: i64 = -32600;
+const METHOD_NOT_FOUND: i64 = -32601;
+const INVALID_PARAMS: i64 = -32602;
+const DEVICE_ERROR: i64 = -32000;
+const RUN_IN_PROGRESS: i64 = -32001;
+
+struct RpcError {
+    code: i64,
+    message: String,
+}
+
+impl RpcError {
+    fn new(code: i64, message: impl Into<String>) -> RpcError {
+        RpcError {
+            code,
+            message: message.into(),
+        }
+    }
+}
+
+impl From<rusb::Error> for RpcError {
+    fn from(e: rusb::Error) -> Self {
+        RpcError::new(DEVICE_ERROR, e.to_string())
+    }
+}
+
+type RpcResult = Result<Value, RpcError>;
+
+const DEVICE_METHODS: [&str; 17] = [
+    "run_start",
+    "send_command",
+    "move",
+    "jog",
+    "wait_idle",
+    "set_pulse_position",
+    "set_encoder_position",
+    "set_motor_enabled",
+    "get_motor_enabled",
+    "get_high_speed",
+    "set_high_speed",
+    "set_low_speed",
+    "set_acceleration_time",
+    "set_deceleration_time",
+    "set_movement_type",
+    "write_driver_settings",
+    "read_driver_settings",
+];
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum RunState {
+    Idle,
+    Running,
+    Finished,
+    Aborted,
+    Failed,
+}
+
+#[derive(Debug)]
+struct RunStatus {
+    state: RunState,
+    progress: Option<RunProgress>,
+    error: Option<String>,
+}
+
+struct Server {
+    handle: Arc<dyn Transport>,
+    // send_command_get_response already keeps single commands whole, this is held for the
+    // whole of each request so one client's multi command requests (driver settings, ...)
+    // can't be interleaved with another's
+    request_lock: Mutex<()>,
+    run_status: Mutex<RunStatus>,
+    started: Instant,
+    // Stops jogs from send_command and jog that run too long, like in interactive mode
+    watchdog: JogWatchdog,
+}
+
+struct Subscription {
+    stream: String,
+    stop: Arc<AtomicBool>,
+}
+
+type SharedWriter = Arc<Mutex<Box<dyn Write + Send>>>;
+
+fn write_line(writer: &SharedWriter, message: &Value) -> std::io::Result<()> {
+    let mut writer = writer.lock().unwrap();
+    writer.write_all(format!("{}\n", message).as_bytes())?;
+    writer.flush()
+}
+
+fn param<'a>(params: &'a Value, name: &str) -> Result<&'a Value, RpcError> {
+    params
+        .get(name)
+        .ok_or_else(|| RpcError::new(INVALID_PARAMS, format!("missing '{}'", name)))
+}
+
+fn int_param<T: TryFrom<i64>>(params: &Value, name: &str) -> Result<T, RpcError> {
+    param(params, name)?
+        .as_i64()
+        .and_then(|value| T::try_from(value).ok())
+        .ok_or_else(|| RpcError::new(INVALID_PARAMS, format!("'{}' is out of range", name)))
+}
+
+fn optional_int_param<T: TryFrom<i64>>(params: &Value, name: &str) -> Result<Option<T>, RpcError> {
+    match params.get(name) {
+        None | Some(Value::Null) => Ok(None),
+        Some(_) => int_param(params, name).map(Some),
+    }
+}
+
+fn str_param<'a>(params: &'a Value, name: &str) -> Result<&'a str, RpcError> {
+    param(params, name)?
+        .as_str()
+        .ok_or_else(|| RpcError::new(INVALID_PARAMS, format!("'{}' must be a string", name)))
+}
+
+fn bool_param(params: &Value, name: &str) -> Result<bool, RpcError> {
+    param(params, name)?
+        .as_bool()
+        .ok_or_else(|| RpcError::new(INVALID_PARAMS, format!("'{}' must be true or false", name)))
+}
+
+impl Server {
+    fn run_active(&self) -> bool {
+        self.run_status.lock().unwrap().state == RunState::Running
+    }
+
+    // Anything that moves the motor or changes its settings would fight the run
+    fn check_not_running(&self) -> Result<(), RpcError> {
+        match self.run_active() {
+            true => Err(RpcError::new(RUN_IN_PROGRESS, "a run is in progress")),
+            false => Ok(()),
+        }
+    }
+
+    fn position(&self) -> RpcResult {
+        Ok(json!({
+            "time": self.started.elapsed().as_secs_f64(),
+            "pulse": get_pulse_position(&self.handle)?,
+            "encoder": get_encoder_position(&self.handle)?,
+        }))
+    }
+
+    fn status(&self) -> RpcResult {
+        let mut status = self.position()?;
+        let motor_status = get_motor_status(&self.handle)?;
+        status["motor_status"] = json!(motor_status.bits());
+        status["motor_flags"] = json!(motor_status
+            .iter_names()
+            .map(|(name, _)| name)
+            .collect::<Vec<_>>());
+
+        let run_status = self.run_status.lock().unwrap();
+        let state = match run_status.state {
+            RunState::Idle => "idle",
+            RunState::Running if is_paused() => "paused",
+            RunState::Running => "running",
+            RunState::Finished => "finished",
+            RunState::Aborted => "aborted",
+            RunState::Failed => "failed",
+        };
+        status["run"] = json!({ "state": state, "error": run_status.error });
+        if let Some(progress) = run_status.progress {
+            status["run"]["cycle"] = json!(progress.cycle);
+            status["run"]["load_cycles"] = json!(progress.load_cycles);
+            status["run"]["elapsed"] = json!(progress.elapsed);
+            status["run"]["period_error"] = json!(progress.period_error);
+            status["run"]["hspd"] = json!(progress.hspd);
+        }
+        Ok(status)
+    }
+
+    // Checked against the command table and soft limits and watched like in interactive mode
+    fn send_interactive(&self, command: &str) -> Result<String, RpcError> {
+        match send_interactive_command(&self.handle, &self.watchdog, command)? {
+            Some(response) => Ok(response),
+            None => Err(RpcError::new(
+                INVALID_PARAMS,
+                format!("{} refused, the server's output says why", command),
+            )),
+        }
+    }
+
+    // Runs RunInput.txt (relative to the server's working directory) on its own thread
+    fn start_run(self: &Arc<Self>) -> RpcResult {
+        *self.run_status.lock().unwrap() = RunStatus {
+            state: RunState::Running,
+            progress: None,
+            error: None,
+        };
+
+        let server = Arc::clone(self);
+        thread::spawn(move || {
+            let result = run_with_progress(&server.handle, &mut |progress| {
+                server.run_status.lock().unwrap().progress = Some(*progress);
+            });
+            let mut run_status = server.run_status.lock().unwrap();
+            run_status.state = match result {
+                Ok(()) => RunState::Finished,
+                Err(rusb::Error::Interrupted) => RunState::Aborted,
+                Err(e) => {
+                    eprintln!("Run started over the server failed: {}", e);
+                    run_status.error = Some(e.to_string());
+                    RunState::Failed
+                }
+            };
+        });
+        Ok(Value::Null)
+    }
+
+    fn subscribe(
+        self: &Arc<Self>,
+        params: &Value,
+        writer: &SharedWriter,
+        subscriptions: &mut Vec<Subscription>,
+    ) -> RpcResult {
+        let stream = str_param(params, "stream")?;
+        if stream != "status" && stream != "position" {
+            return Err(RpcError::new(
+                INVALID_PARAMS,
+                "'stream' must be 'status' or 'position'",
+            ));
+        }
+        let interval = match optional_int_param::<u64>(params, "interval_ms")? {
+            None => DEFAULT_STREAM_INTERVAL,
+            Some(ms) => Duration::from_millis(ms).max(MIN_STREAM_INTERVAL),
+        };
+        unsubscribe(subscriptions, stream);
+
+        let stop = Arc::new(AtomicBool::new(false));
+        subscriptions.push(Subscription {
+            stream: stream.to_string(),
+            stop: Arc::clone(&stop),
+        });
+        let (server, writer, stream) = (Arc::clone(self), Arc::clone(writer), stream.to_string());
+        thread::spawn(move || {
+            while !stop.load(Ordering::SeqCst) {
+                let update = match stream.as_str() {
+                    "position" => server.position(),
+                    _ => server.status(),
+                }
+                .unwrap_or_else(|e| json!({ "error": e.message }));
+                let notification = json!({ "jsonrpc": "2.0", "method": stream, "params": update });
+                if write_line(&writer, &notification).is_err() {
+                    break;
+                }
+                thread::sleep(interval);
+            }
+        });
+        Ok(Value::Null)
+    }
+
+    fn call(
+        self: &Arc<Self>,
+        method: &str,
+        params: &Value,
+        writer: &SharedWriter,
+        subscriptions: &mut Vec<Subscription>,
+    ) -> RpcResult {
+        match method {
+            // Never wait on another client, stopping least of all
+            "get_status" => self.status(),
+            "get_position" => self.position(),
+            "stop" => {
+                if self.run_active() {
+                    request_abort();
+                }
+                stop(&self.handle)?;
+                Ok(Value::Null)
+            }
+            "run_pause" | "run_resume" | "run_abort" => {
+                if !self.run_active() {
+                    return Err(RpcError::new(INVALID_REQUEST, "no run in progress"));
+                }
+                match method {
+                    "run_pause" => request_pause(),
+                    "run_resume" => request_resume(),
+                    _ => request_abort(),
+                }
+                Ok(Value::Null)
+            }
+            "subscribe" => self.subscribe(params, writer, subscriptions),
+            "unsubscribe" => {
+                unsubscribe(subscriptions, str_param(params, "stream")?);
+                Ok(Value::Null)
+            }
+            _ if DEVICE_METHODS.contains(&method) => {
+                let _request = self.request_lock.lock().unwrap();
+                self.check_not_running()?;
+                self.call_device(method, params)
+            }
+            _ => Err(RpcError::new(
+                METHOD_NOT_FOUND,
+                format!("unknown method '{}'", method),
+            )),
+        }
+    }
+
+    // The methods that use the motor, one client at a time and not during a run
+    fn call_device(self: &Arc<Self>, method: &str, params: &Value) -> RpcResult {
+        let handle: &dyn Transport = &self.handle;
+        match method {
+            "run_start" => self.start_run(),
+            "send_command" => {
+                let command = str_param(params, "command")?.trim().to_ascii_uppercase();
+                Ok(json!(self.send_interactive(&command)?))
+            }
+            "move" => {
+                move_stage(handle, int_param(params, "position")?)?;
+                Ok(Value::Null)
+            }
+            "jog" => {
+                let positive = match str_param(params, "direction")? {
+                    "+" => true,
+                    "-" => false,
+                    _ => return Err(RpcError::new(INVALID_PARAMS, "'direction' must be + or -")),
+                };
+                self.send_interactive(if positive { "J+" } else { "J-" })?;
+                Ok(Value::Null)
+            }
+            "wait_idle" => {
+                wait_for_motor_idle(handle, &mut None)?;
+                Ok(Value::Null)
+            }
+            "set_pulse_position" => {
+                set_pulse_position(handle, int_param(params, "position")?)?;
+                Ok(Value::Null)
+            }
+            "set_encoder_position" => {
+                set_encoder_position(handle, int_param(params, "position")?)?;
+                Ok(Value::Null)
+            }
+            "set_motor_enabled" => {
+                match bool_param(params, "enabled")? {
+                    true => turn_motor_on(handle)?,
+                    false => turn_motor_off(handle)?,
+                }
+                Ok(Value::Null)
+            }
+            "get_motor_enabled" => Ok(json!(get_motor_enabled(handle)?)),
+            "get_high_speed" => Ok(json!(get_high_speed(handle)?)),
+            "set_high_speed" => {
+                set_high_speed(handle, int_param(params, "speed")?)?;
+                Ok(Value::Null)
+            }
+            "set_low_speed" => {
+                set_low_speed(handle, int_param(params, "speed")?)?;
+                Ok(Value::Null)
+            }
+            "set_acceleration_time" => {
+                set_acceleration_time(handle, int_param(params, "time")?)?;
+                Ok(Value::Null)
+            }
+            "set_deceleration_time" => {
+                set_deceleration_time(handle, int_param(params, "time")?)?;
+                Ok(Value::Null)
+            }
+            "set_movement_type" => {
+                set_movement_type(handle, str_param(params, "type")?)?;
+                Ok(Value::Null)
+            }
+            "write_driver_settings" => {
+                if let Some(microsteps) = optional_int_param(params, "microsteps")? {
+                    set_microstepping(handle, microsteps)?;
+                }
+                if let Some(current) = optional_int_param(params, "idle_current")? {
+                    set_idle_current(handle, current)?;
+                }
+                if let Some(current) = optional_int_param(params, "run_current")? {
+                    set_run_current(handle, current)?;
+                }
+                if let Some(time) = optional_int_param(params, "idle_time")? {
+                    set_idle_time(handle, time)?;
+                }
+                write_driver_settings(handle)?;
+                Ok(Value::Null)
+            }
+            "read_driver_settings" => {
+                read_driver_settings(handle)?;
+                Ok(json!({
+                    "microsteps": get_microstepping(handle)?,
+                    "idle_current": get_idle_current(handle)?,
+                    "run_current": get_run_current(handle)?,
+                    "idle_time": get_idle_time(handle)?,
+                }))
+            }
+            _ => unreachable!("'{}' is in DEVICE_METHODS but not handled", method),
+        }
+    }
+
+    // Returns the response to send back, None for notifications (requests without an id)
+    fn handle_line(
+        self: &Arc<Self>,
+        line: &str,
+        writer: &SharedWriter,
+        subscriptions: &mut Vec<Subscription>,
+    ) -> Option<Value> {
+        let request: Value = match serde_json::from_str(line) {
+            Ok(request) => request,
+            Err(e) => return Some(error_response(&Value::Null, PARSE_ERROR, &e.to_string())),
+        };
+        let id = request.get("id").cloned();
+        let method = match request.get("method").and_then(Value::as_str) {
+            Some(method) => method,
+            None => {
+                let id = id.unwrap_or(Value::Null);
+                return Some(error_response(&id, INVALID_REQUEST, "missing 'method'"));
+            }
+        };
+        let params = request.get("params").cloned().unwrap_or(json!({}));
+
+        let result = self.call(method, &params, writer, subscriptions);
+        let id = id?;
+        Some(match result {
+            Ok(result) => json!({ "jsonrpc": "2.0", "result": result, "id": id }),
+            Err(e) => error_response(&id, e.code, &e.message),
+        })
+    }
+}
+
+fn error_response(id: &Value, code: i64, message: &str) -> Value {
+    json!({ "jsonrpc": "2.0", "error": { "code": code, "message": message }, "id": id })
+}
+
+fn unsubscribe(subscriptions: &mut Vec<Subscription>, stream: &str) {
+    subscriptions.retain(|subscription| {
+        if subscription.stream == stream {
+            subscription.stop.store(true, Ordering::SeqCst);
+        }
+        subscription.stream != stream
+    });
+}
+
+fn serve_client(server: Arc<Server>, reader: impl Read, writer: Box<dyn Write + Send>) {
+    let writer: SharedWriter = Arc::new(Mutex::new(writer));
+    let mut subscriptions = Vec::new();
+
+    for line in BufReader::new(reader).lines() {
+        let line = match line {
+            Ok(line) => line,
+            Err(_) => break,
+        };
+        if line.trim().is_empty() {
+            continue;
+        }
+        if let Some(response) = server.handle_line(&line, &writer, &mut subscriptions) {
+            if write_line(&writer, &response).is_err() {
+                break;
+            }
+        }
+    }
+    for subscription in subscriptions {
+        subscription.stop.store(true, Ordering::SeqCst);
+    }
+}
+
+/// Serves clients until the process is killed. `address` is `host:port` for TCP or
+/// `unix:/path/to/socket` for a Unix socket. Anything that can reach a TCP address can move
+/// the stage, so only listen beyond localhost on a network you trust.
+pub fn serve(handle: Arc<dyn Transport>, address: &str) -> std::io::Result<()> {
+    let server = Arc::new(Server {
+        handle,
+        request_lock: Mutex::new(()),
+        run_status: Mutex::new(RunStatus {
+            state: RunState::Idle,
+            progress: None,
+            error: None,
+        }),
+        started: Instant::now(),
+        watchdog: JogWatchdog::new(WatchdogSettings::default()),
+    });
+
+    let watched = Arc::clone(&server);
+    watched.watchdog.watch_while(&watched.handle, move || {
+        match address.strip_prefix("unix:") {
+            Some(path) => serve_unix(server, path),
+            None => serve_tcp(server, address),
+        }
+    })
+}
+
+fn serve_tcp(server: Arc<Server>, address: &str) -> std::io::Result<()> {
+    let listener = TcpListener::bind(address)?;
+    println!("Serving JSON-RPC on {}", listener.local_addr()?);
+    for stream in listener.incoming() {
+        let stream = match stream {
+            Ok(stream) => stream,
+            Err(e) => {
+                eprintln!("Failed to accept a connection: {}", e);
+                continue;
+            }
+        };
+        let writer = stream.try_clone()?;
+        let server = Arc::clone(&server);
+        thread::spawn(move || serve_client(server, stream, Box::new(writer)));
+    }
+    Ok(())
+}
+
+#[cfg(unix)]
+fn serve_unix(server: Arc<Server>, path: &str) -> std::io::Result<()> {
+    use std::os::unix::{fs::FileTypeExt, net::UnixListener};
+
+    // A socket left behind by a server that didn't exit cleanly would stop the bind
+    if let Ok(metadata) = std::fs::symlink_metadata(path) {
+        if metadata.file_type().is_socket() {
+            std::fs::remove_file(path)?;
+        }
+    }
+
+    let listener = UnixListener::bind(path)?;
+    println!("Serving JSON-RPC on unix:{}", path);
+    for stream in listener.incoming() {
+        let stream = match stream {
+            Ok(stream) => stream,
+            Err(e) => {
+                eprintln!("Failed to accept a connection: {}", e);
+                continue;
+            }
+        };
+        let writer = stream.try_clone()?;
+        let server = Arc::clone(&server);
+        thread::spawn(move || serve_client(server, stream, Box::new(writer)));
+    }
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn serve_unix(_server: Arc<Server>, _path: &str) -> std::io::Result<()> {
+    Err(std::io::Error::new(
+        std::io::ErrorKind::Unsupported,
+        "Unix sockets aren't available on this platform, use a TCP address",
+    ))
+}
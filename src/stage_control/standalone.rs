@@ -0,0 +1,367 @@
+//! Runs compiled into an NSC-A1 standalone program, so the controller times the cycles itself
+//! instead of the host over USB. There's no speed correction, HighSpeed is used as it is, so
+//! calibrate first. The host only starts the program and watches the cycle counter.
+//!
+//! The program is written in the controller's standalone language. Downloading it to the
+//! controller isn't implemented: Arcus don't document how the Windows software downloads a
+//! program over USB, so it's saved to a file for that software to download and store, and
+//! download_program only says so. Starting, stopping and watching are plain commands: SR=1 and
+//! SR=0, SASTAT and the counter variable.
+//!
+//! Whatever is stored might not be what RunInput.txt says now, so the program starts by
+//! putting a check number worked out from the rest of it in the counter and waits. The loader
+//! only lets it go on, by zeroing the counter, if that matches the program it would compile.
+//! While it runs the soft limits and the interlock are watched like in a normal run.
+
+use crate::stage_control::{
+    abort::{abort_requested, check_abort, AbortScope},
+    commands::{check_while_moving, send_command_get_response, stop, PositionLog},
+    driver::Transport,
+};
+
+use rusb::Result;
+
+use std::{
+    fs,
+    io::Write,
+    thread::sleep,
+    time::{Duration, Instant},
+};
+
+const POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+// A program that hasn't put its check number in the counter by now isn't one of ours
+const CHECK_TIMEOUT: Duration = Duration::from_secs(2);
+
+// Same as run, the offset move doesn't need to be fast
+const OFFSET_SPEED: u32 = 1500;
+
+/// Where the compiled program goes
+pub const PROGRAM_PATH: &str = "./input_output_files/Standalone.txt";
+
+/// The part of a run a standalone program can do: trapezoidal cycles with a fixed speed.
+#[derive(Debug, Clone, Copy)]
+pub struct StandaloneProtocol {
+    pub offset: i32,
+    pub amplitude: i32,
+    pub high_speed: u32,
+    pub low_speed: u32,
+    pub acceleration_time: u32, // ms
+    pub deceleration_time: u32, // ms
+    pub dwell_time: f64,        // s
+    pub load_cycles: u32,
+    pub counter: u8, // V1 to V100, counts finished cycles
+}
+
+/// What SASTAT says the program is doing
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum StandaloneStatus {
+    Stopped,
+    Running,
+    Paused,
+    Error,
+    Unknown(i32),
+}
+
+impl StandaloneStatus {
+    fn from_sastat(sastat: i32) -> StandaloneStatus {
+        match sastat {
+            0 => StandaloneStatus::Stopped,
+            1 => StandaloneStatus::Running,
+            2 => StandaloneStatus::Paused,
+            4 => StandaloneStatus::Error,
+            other => StandaloneStatus::Unknown(other),
+        }
+    }
+}
+
+/// The program for `protocol`: the check number in the counter and a wait for the loader to
+/// zero it, then motor on, incremental moves, the offset move and load_cycles cycles with the
+/// counter going up after each one.
+pub fn compile(protocol: &StandaloneProtocol) -> String {
+    let counter = format!("V{}", protocol.counter);
+    let body = compile_body(protocol);
+    [
+        format!("{}={}", counter, program_check(&body)),
+        format!("WHILE {}>0", counter),
+        "ENDWHILE".to_string(),
+        body,
+    ]
+    .join("\n")
+}
+
+/// The check number the program for `protocol` puts in the counter, 1 to 1000000.
+pub fn expected_check(protocol: &StandaloneProtocol) -> i32 {
+    program_check(&compile_body(protocol))
+}
+
+// FNV-1a, anything that changes with the program and stays the same between builds will do
+fn program_check(body: &str) -> i32 {
+    let hash = body.bytes().fold(0x811c9dc5u32, |hash, byte| {
+        (hash ^ byte as u32).wrapping_mul(0x01000193)
+    });
+    (hash % 1_000_000) as i32 + 1
+}
+
+// Everything after the check
+fn compile_body(protocol: &StandaloneProtocol) -> String {
+    let counter = format!("V{}", protocol.counter);
+    let dwell = (protocol.dwell_time * 1000.0).round() as u32;
+    let mut lines = vec![
+        "EO=1".to_string(),
+        "INC".to_string(),
+        format!("LSPD={}", protocol.low_speed),
+        format!("ACC={}", protocol.acceleration_time),
+        format!("DEC={}", protocol.deceleration_time),
+    ];
+    if protocol.offset != 0 {
+        lines.push(format!("HSPD={}", OFFSET_SPEED));
+        lines.push(format!("X{}", -protocol.offset));
+        lines.push("WAITX".to_string());
+    }
+    lines.push(format!("HSPD={}", protocol.high_speed));
+    lines.push(format!("{}=0", counter));
+    lines.push(format!("WHILE {}<{}", counter, protocol.load_cycles));
+    for distance in [-protocol.amplitude, protocol.amplitude] {
+        lines.push(format!("X{}", distance));
+        lines.push("WAITX".to_string());
+        if dwell > 0 {
+            lines.push(format!("DELAY={}", dwell));
+        }
+    }
+    lines.push(format!("{0}={0}+1", counter));
+    lines.push("ENDWHILE".to_string());
+    lines.push("END".to_string());
+    lines.join("\n") + "\n"
+}
+
+/// Compiles `protocol` and saves it to PROGRAM_PATH for the Arcus software to download, the
+/// loader can't download it itself, see download_program.
+pub fn write_program(protocol: &StandaloneProtocol) -> std::io::Result<()> {
+    fs::write(PROGRAM_PATH, compile(protocol))?;
+    println!(
+        "Standalone program written to {}. The loader can't download programs, download and store it with the Arcus software, then 'standalone start'.",
+        PROGRAM_PATH
+    );
+    Ok(())
+}
+
+/// Would download `protocol` to the controller. Not implemented, how the Arcus software does
+/// it over USB isn't documented, so this always fails with NotSupported. Use write_program and
+/// the Arcus software instead.
+pub fn download_program(_handle: &dyn Transport, _protocol: &StandaloneProtocol) -> Result<()> {
+    eprintln!(
+        "Downloading standalone programs isn't implemented, 'standalone compile' writes {} for the Arcus software to download",
+        PROGRAM_PATH
+    );
+    Err(rusb::Error::NotSupported)
+}
+
+fn send_checked(handle: &dyn Transport, command: &str) -> Result<String> {
+    let response = send_command_get_response(handle, &[command.as_bytes(), b"\0"].concat())?;
+    if response.starts_with('?') {
+        eprintln!(
+            "Controller didn't understand {}, it answered {}",
+            command, response
+        );
+        return Err(rusb::Error::NotSupported);
+    }
+    Ok(response)
+}
+
+/// Starts the stored program, SR=1.
+pub fn start_program(handle: &dyn Transport) -> Result<()> {
+    send_checked(handle, "SR=1").map(|_| ())
+}
+
+/// Stops the program with SR=0, then the motor in case it was halfway through a move.
+pub fn stop_program(handle: &dyn Transport) -> Result<()> {
+    send_checked(handle, "SR=0")?;
+    stop(handle)
+}
+
+pub fn get_program_status(handle: &dyn Transport) -> Result<StandaloneStatus> {
+    let response = send_checked(handle, "SASTAT")?;
+    Ok(StandaloneStatus::from_sastat(
+        response.parse().unwrap_or(i32::MIN),
+    ))
+}
+
+/// Reads one of the program variables, V1 to V100.
+pub fn get_variable(handle: &dyn Transport, variable: u8) -> Result<i32> {
+    let response = send_checked(handle, &format!("V{}", variable))?;
+    response.parse().map_err(|_| {
+        eprintln!("V{} read back as {:?}", variable, response);
+        rusb::Error::Other
+    })
+}
+
+/// Sets one of the program variables, V1 to V100.
+pub fn set_variable(handle: &dyn Transport, variable: u8, value: i32) -> Result<()> {
+    send_checked(handle, &format!("V{}={}", variable, value)).map(|_| ())
+}
+
+// Waits for the program to put its check number in the counter and lets it go on if it's the
+// one `protocol` compiles to. Anything else is stopped before it moves.
+fn check_stored_program(handle: &dyn Transport, protocol: &StandaloneProtocol) -> Result<()> {
+    let expected = expected_check(protocol);
+    let start = Instant::now();
+    let check = loop {
+        // The program is waiting for us, it has to be told to stop too
+        if let Err(e) = check_abort(handle) {
+            stop_program(handle)?;
+            return Err(e);
+        }
+        match get_variable(handle, protocol.counter)? {
+            0 if start.elapsed() < CHECK_TIMEOUT => sleep(Duration::from_millis(50)),
+            check => break check,
+        }
+    };
+    if check != expected {
+        stop_program(handle)?;
+        match check {
+            0 => eprintln!("The stored program didn't check in, it was compiled by an older loader or isn't a standalone run"),
+            check => eprintln!(
+                "The stored program doesn't match RunInput.txt (V{} is {}, expected {})",
+                protocol.counter, check, expected
+            ),
+        }
+        eprintln!("Compile and download it again, nothing was run");
+        return Err(rusb::Error::Other);
+    }
+    set_variable(handle, protocol.counter, 0)
+}
+
+/// Starts the program, checks it's the one `protocol` compiles to and watches it until it
+/// stops, printing and logging the cycle counter and position to `log` every half second.
+/// Leaving the soft limits or the interlock going off stops the program and the motor and
+/// returns Other, as does a stored program that doesn't match. Ctrl-C stops the program and
+/// returns Interrupted. Errors with Other if the program stopped short of load_cycles.
+pub fn run_program(
+    handle: &dyn Transport,
+    protocol: &StandaloneProtocol,
+    log: &mut PositionLog,
+) -> Result<()> {
+    let _abort_scope = AbortScope::enter();
+    // Whatever an earlier run left in the counter mustn't pass for the check
+    set_variable(handle, protocol.counter, 0)?;
+    start_program(handle)?;
+    check_stored_program(handle, protocol)?;
+    println!("Standalone program started");
+
+    let mut last_cycle = -1;
+    loop {
+        // Also gives SASTAT time to say it's running before it's first read
+        sleep(POLL_INTERVAL);
+        // The program would carry on with the next move after a STOP or ABORT, so anything
+        // that stops the motor stops the program first
+        if abort_requested() {
+            stop_program(handle)?;
+        }
+        if let Err(e) = check_while_moving(handle, Some(log)) {
+            if e != rusb::Error::Interrupted {
+                stop_program(handle)?;
+            }
+            return Err(e);
+        }
+
+        let status = get_program_status(handle)?;
+        let cycle = get_variable(handle, protocol.counter)?;
+        if cycle != last_cycle {
+            println!(
+                "cycle={}/{}\tstatus={:?}",
+                cycle, protocol.load_cycles, status
+            );
+            last_cycle = cycle;
+        }
+        if status != StandaloneStatus::Running && status != StandaloneStatus::Paused {
+            log.file.flush().unwrap();
+            return match cycle as u32 == protocol.load_cycles {
+                true => {
+                    println!("Standalone program finished {} cycles", cycle);
+                    Ok(())
+                }
+                false => {
+                    eprintln!(
+                        "Standalone program stopped ({:?}) after {} of {} cycles",
+                        status, cycle, protocol.load_cycles
+                    );
+                    Err(rusb::Error::Other)
+                }
+            };
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn protocol() -> StandaloneProtocol {
+        StandaloneProtocol {
+            offset: 0,
+            amplitude: 2000,
+            high_speed: 5000,
+            low_speed: 100,
+            acceleration_time: 300,
+            deceleration_time: 200,
+            dwell_time: 0.0,
+            load_cycles: 50,
+            counter: 3,
+        }
+    }
+
+    #[test]
+    fn compiles_the_cycles() {
+        let protocol = protocol();
+        let expected = format!(
+            "V3={}\nWHILE V3>0\nENDWHILE\n\
+             EO=1\nINC\nLSPD=100\nACC=300\nDEC=200\nHSPD=5000\nV3=0\nWHILE V3<50\n\
+             X-2000\nWAITX\nX2000\nWAITX\nV3=V3+1\nENDWHILE\nEND\n",
+            expected_check(&protocol)
+        );
+        assert_eq!(compile(&protocol), expected);
+    }
+
+    #[test]
+    fn compiles_the_offset_move_and_dwell() {
+        let protocol = StandaloneProtocol {
+            offset: 500,
+            dwell_time: 0.25,
+            ..protocol()
+        };
+        let program = compile(&protocol);
+        // At the offset speed, before the cycle's HSPD
+        assert!(program.contains("\nHSPD=1500\nX-500\nWAITX\nHSPD=5000\n"));
+        assert!(program.contains("\nX-2000\nWAITX\nDELAY=250\nX2000\nWAITX\nDELAY=250\n"));
+    }
+
+    #[test]
+    fn the_check_changes_with_the_program() {
+        let protocol = protocol();
+        let check = expected_check(&protocol);
+        assert!((1..=1_000_000).contains(&check));
+        assert!(compile(&protocol).starts_with(&format!("V3={}\n", check)));
+        assert_eq!(expected_check(&protocol), check);
+        for changed in [
+            StandaloneProtocol {
+                amplitude: 2001,
+                ..protocol
+            },
+            StandaloneProtocol {
+                offset: -10,
+                ..protocol
+            },
+            StandaloneProtocol {
+                load_cycles: 51,
+                ..protocol
+            },
+            StandaloneProtocol {
+                high_speed: 5001,
+                ..protocol
+            },
+        ] {
+            assert_ne!(expected_check(&changed), check, "{:?}", changed);
+        }
+    }
+}
@@ -0,0 +1,136 @@
+//! Digital output pulses at points of the load cycle, so a camera or DAQ takes its data at a
+//! known place in the cycle. Every pulse is noted in the run output file.
+
+use crate::stage_control::{
+    commands::{CyclePhase, PositionLog},
+    driver::Transport,
+    io::{bit_mask, get_digital_outputs, set_digital_outputs},
+};
+
+use rusb::Result;
+
+use std::{
+    io::Write,
+    thread::sleep,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+/// One output pulsed at one point of the cycle.
+#[derive(Debug, Clone, Copy)]
+pub struct Trigger {
+    pub output: u8, // From 1, like the connector
+    pub phase: CyclePhase,
+    pub every: u32, // Only on cycles that are a multiple of this, 1 is every cycle
+    pub width: Duration, // How long the output stays on
+}
+
+/// Reads `output start|peak|valley [every] [width ms]`, what comes after `Trigger` in a run file.
+pub fn parse_trigger(args: &[&str]) -> Result<Trigger> {
+    let usage = || {
+        eprintln!(
+            "Trigger needs 'output start|peak|valley [every] [width ms]', got {:?}",
+            args.join(" ")
+        );
+        rusb::Error::InvalidParam
+    };
+    let (output, phase, rest) = match args {
+        [output, phase, rest @ ..] if rest.len() <= 2 => (output, phase, rest),
+        _ => return Err(usage()),
+    };
+    let output: u8 = output.parse().map_err(|_| usage())?;
+    bit_mask(output)?;
+    let phase = match phase.to_ascii_lowercase().as_str() {
+        "start" => CyclePhase::Start,
+        "peak" => CyclePhase::Peak,
+        "valley" => CyclePhase::Valley,
+        _ => return Err(usage()),
+    };
+    let every: u32 = match rest.first() {
+        Some(every) => every.parse().map_err(|_| usage())?,
+        None => 1,
+    };
+    let width: u64 = match rest.get(1) {
+        Some(width) => width.parse().map_err(|_| usage())?,
+        None => 10,
+    };
+    if every == 0 {
+        return Err(usage());
+    }
+    Ok(Trigger {
+        output,
+        phase,
+        every,
+        width: Duration::from_millis(width),
+    })
+}
+
+/// The triggers of a run, with the outputs the pulses go on top of.
+pub struct Triggers {
+    triggers: Vec<Trigger>,
+    outputs: u32, // DO when the run started, outputs that aren't triggers stay like this
+}
+
+impl Triggers {
+    /// Reads DO once so pulses don't have to, there's no time for it at the peak.
+    pub fn new(handle: &dyn Transport, triggers: &[Trigger]) -> Result<Triggers> {
+        let outputs = match triggers.is_empty() {
+            true => 0,
+            false => get_digital_outputs(handle)?,
+        };
+        Ok(Triggers {
+            triggers: triggers.to_vec(),
+            outputs,
+        })
+    }
+
+    /// Pulses every trigger due at `phase` of `cycle` together and notes them in `log`.
+    /// Outputs go off in order of width. The pulses hold the cycle up, keep them short.
+    pub fn fire(
+        &self,
+        handle: &dyn Transport,
+        phase: CyclePhase,
+        cycle: u32,
+        log: &mut PositionLog,
+    ) -> Result<()> {
+        let mut due: Vec<&Trigger> = self
+            .triggers
+            .iter()
+            .filter(|trigger| trigger.phase == phase && cycle.is_multiple_of(trigger.every))
+            .collect();
+        if due.is_empty() {
+            return Ok(());
+        }
+        due.sort_by_key(|trigger| trigger.width);
+
+        let mut outputs = due.iter().fold(self.outputs, |outputs, trigger| {
+            outputs | 1 << (trigger.output - 1)
+        });
+        set_digital_outputs(handle, outputs)?;
+        // Taken once DO= is answered, the outputs are on by then
+        let (on, run_time, host_time) = (
+            Instant::now(),
+            log.time.elapsed().as_secs_f64(),
+            SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs_f64(),
+        );
+        for trigger in &due {
+            sleep(trigger.width.saturating_sub(on.elapsed()));
+            outputs &= !(1 << (trigger.output - 1)) | self.outputs;
+            set_digital_outputs(handle, outputs)?;
+        }
+
+        let phase = format!("{:?}", phase).to_ascii_lowercase();
+        for trigger in &due {
+            let line = format!(
+                "# trigger output {} {} cycle {} at {} s, host time {:.6}\n",
+                trigger.output, phase, cycle, run_time, host_time
+            );
+            if let Err(e) = log.file.write_all(line.as_bytes()) {
+                eprintln!("Failed to note trigger in the output file: {}", e);
+            }
+        }
+        Ok(())
+    }
+}
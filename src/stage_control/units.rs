@@ -0,0 +1,119 @@
+//! Conversion between pulses and mm.
+
+/// The controller only knows pulses, and how far a pulse moves the stage depends on the
+/// lead screw, the motor and the microstep setting (DRVMS). This holds all of that so
+/// the run file can be written in mm and mm/s and the output can be read in mm.
+#[derive(Debug, Clone, Copy)]
+pub struct UnitConversion {
+    pub lead_screw_pitch: f64, // mm travelled per revolution of the lead screw
+    pub steps_per_rev: u32,    // Full steps per motor revolution, 200 for a 1.8 degree motor
+    pub microsteps: u32,       // Has to match DRVMS or every conversion is off
+    pub encoder_resolution: u32, // Encoder counts per motor revolution
+}
+
+impl Default for UnitConversion {
+    // These match the motor and settings the run/calibrate code has always assumed
+    // (50 microsteps). The pitch is a guess, set it in the input file for your stage.
+    fn default() -> Self {
+        UnitConversion {
+            lead_screw_pitch: 1.0,
+            steps_per_rev: 200,
+            microsteps: 50,
+            encoder_resolution: 1000,
+        }
+    }
+}
+
+impl UnitConversion {
+    /// Pulses to move 1 mm.
+    pub fn pulses_per_mm(&self) -> f64 {
+        (self.steps_per_rev * self.microsteps) as f64 / self.lead_screw_pitch
+    }
+
+    /// Encoder counts for 1 mm of travel.
+    pub fn encoder_counts_per_mm(&self) -> f64 {
+        self.encoder_resolution as f64 / self.lead_screw_pitch
+    }
+
+    /// Rounded to the nearest pulse.
+    pub fn mm_to_pulses(&self, mm: f64) -> i32 {
+        (mm * self.pulses_per_mm()).round() as i32
+    }
+
+    /// Pulses to mm.
+    pub fn pulses_to_mm(&self, pulses: i32) -> f64 {
+        pulses as f64 / self.pulses_per_mm()
+    }
+
+    /// Speeds are always positive on the controller (HSPD, LSPD), direction comes from the move
+    pub fn mm_per_s_to_pulses_per_s(&self, mm_per_s: f64) -> u32 {
+        (mm_per_s.abs() * self.pulses_per_mm()).round() as u32
+    }
+
+    /// Pulses/s to mm/s.
+    pub fn pulses_per_s_to_mm_per_s(&self, pulses_per_s: u32) -> f64 {
+        pulses_per_s as f64 / self.pulses_per_mm()
+    }
+
+    /// Encoder counts to mm.
+    pub fn encoder_to_mm(&self, counts: i32) -> f64 {
+        counts as f64 / self.encoder_counts_per_mm()
+    }
+
+    /// Where the encoder says the stage is, in pulses.
+    pub fn encoder_to_pulses(&self, counts: i32) -> i32 {
+        self.mm_to_pulses(self.encoder_to_mm(counts))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // 2 mm pitch, 200 steps and 50 microsteps: 5000 pulses/mm, 500 encoder counts/mm
+    fn stage() -> UnitConversion {
+        UnitConversion {
+            lead_screw_pitch: 2.0,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn converts_mm_and_pulses_both_ways() {
+        let units = stage();
+        assert_eq!(units.pulses_per_mm(), 5000.0);
+        assert_eq!(units.mm_to_pulses(1.5), 7500);
+        assert_eq!(units.mm_to_pulses(-1.5), -7500);
+        assert_eq!(units.pulses_to_mm(7500), 1.5);
+        assert_eq!(units.pulses_to_mm(-2500), -0.5);
+        assert_eq!(units.mm_per_s_to_pulses_per_s(0.2), 1000);
+        assert_eq!(units.pulses_per_s_to_mm_per_s(1000), 0.2);
+    }
+
+    #[test]
+    fn rounds_to_the_nearest_pulse() {
+        let units = stage();
+        assert_eq!(units.mm_to_pulses(0.00009), 0); // 0.45 pulses
+        assert_eq!(units.mm_to_pulses(0.00011), 1); // 0.55 pulses
+        assert_eq!(units.mm_to_pulses(-0.00009), 0);
+        assert_eq!(units.mm_to_pulses(-0.00011), -1);
+        // Halfway goes away from zero either way
+        assert_eq!(units.mm_to_pulses(0.0001), 1);
+        assert_eq!(units.mm_to_pulses(-0.0001), -1);
+    }
+
+    #[test]
+    fn speeds_are_positive_whichever_way_the_stage_goes() {
+        assert_eq!(stage().mm_per_s_to_pulses_per_s(-0.2), 1000);
+    }
+
+    #[test]
+    fn converts_encoder_counts() {
+        let units = stage();
+        assert_eq!(units.encoder_counts_per_mm(), 500.0);
+        assert_eq!(units.encoder_to_mm(250), 0.5);
+        assert_eq!(units.encoder_to_mm(-250), -0.5);
+        // 10 pulses per count
+        assert_eq!(units.encoder_to_pulses(-3), -30);
+    }
+}
@@ -0,0 +1,170 @@
+//! Stops jogs in interactive mode that run too long or too far.
+
+use crate::stage_control::{
+    commands::{get_pulse_position, motor_is_moving, stop},
+    driver::Transport,
+    recording::{is_replaying, mark_background_thread},
+};
+
+use rusb::Result;
+
+use std::{
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Mutex,
+    },
+    thread::sleep,
+    time::{Duration, Instant},
+};
+
+const POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+// Right after the controller accepts J+/J- MST can still read 0 before the motor starts.
+// Don't take that as the jog being over.
+const JOG_START_GRACE: Duration = Duration::from_millis(250);
+
+/// When a jog gets stopped.
+#[derive(Debug, Clone, Copy)]
+pub struct WatchdogSettings {
+    pub timeout: Duration,
+    pub max_distance: Option<u32>, // In pulses, None means only the timeout applies
+}
+
+impl Default for WatchdogSettings {
+    fn default() -> Self {
+        WatchdogSettings {
+            timeout: Duration::from_secs(10),
+            max_distance: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+struct ActiveJog {
+    command: String,
+    started: Instant,
+    start_position: i32,
+}
+
+/// Sends STOP if a jog from interactive mode runs too long or too far. Meant to live on its
+/// own thread (see watch_while) while interactive mode tells it when a jog starts.
+///
+/// The polling shares the bus with everything else through BUS_LOCK, but only while a jog
+/// is going. A command sent then waits for at most one MST (and PX with a max distance)
+/// exchange, a few ms, and nothing waits on the watchdog otherwise.
+pub struct JogWatchdog {
+    settings: Mutex<WatchdogSettings>,
+    active_jog: Mutex<Option<ActiveJog>>,
+    done: AtomicBool,
+}
+
+impl JogWatchdog {
+    /// Doesn't start watching, run watch on its own thread for that.
+    pub fn new(settings: WatchdogSettings) -> JogWatchdog {
+        JogWatchdog {
+            settings: Mutex::new(settings),
+            active_jog: Mutex::new(None),
+            done: AtomicBool::new(false),
+        }
+    }
+
+    /// The current settings.
+    pub fn settings(&self) -> WatchdogSettings {
+        *self.settings.lock().unwrap()
+    }
+
+    /// Applies to the jog in progress too.
+    pub fn set_settings(&self, settings: WatchdogSettings) {
+        *self.settings.lock().unwrap() = settings;
+    }
+
+    /// Call once the controller has accepted the jog, with PX read before sending it. The
+    /// timeout and JOG_START_GRACE count from here, so time spent queued for the bus doesn't
+    /// eat into them.
+    pub fn jog_started(&self, command: &str, start_position: i32) {
+        *self.active_jog.lock().unwrap() = Some(ActiveJog {
+            command: command.to_string(),
+            started: Instant::now(),
+            start_position,
+        });
+    }
+
+    /// Tells watch to return
+    pub fn shutdown(&self) {
+        self.done.store(true, Ordering::SeqCst);
+    }
+
+    /// Runs `f` with watch going on another thread. The watchdog is shut down when `f`
+    /// returns or panics, a panic would otherwise leave the scope waiting on watch forever.
+    /// Not while replaying, a replayed motor can't run away and the polling would take the
+    /// session's transfers.
+    pub fn watch_while<R>(&self, handle: &dyn Transport, f: impl FnOnce() -> R) -> R {
+        if is_replaying() {
+            return f();
+        }
+        std::thread::scope(|scope| {
+            scope.spawn(|| self.watch(handle));
+            let _shutdown = ShutdownOnDrop(self);
+            f()
+        })
+    }
+
+    /// Polls MST until shutdown is called. Errors are reported and polling carries on, the
+    /// watchdog giving up silently would be worse than a noisy one.
+    pub fn watch(&self, handle: &dyn Transport) {
+        mark_background_thread("watchdog");
+        while !self.done.load(Ordering::SeqCst) {
+            sleep(POLL_INTERVAL);
+            if let Err(e) = self.check_jog(handle) {
+                eprintln!("Jog watchdog failed to check the motor: {}", e);
+            }
+        }
+    }
+
+    fn check_jog(&self, handle: &dyn Transport) -> Result<()> {
+        let jog = match self.active_jog.lock().unwrap().clone() {
+            None => return Ok(()),
+            Some(jog) => jog,
+        };
+
+        if !motor_is_moving(handle)? {
+            if jog.started.elapsed() > JOG_START_GRACE {
+                *self.active_jog.lock().unwrap() = None;
+            }
+            return Ok(());
+        }
+
+        let settings = self.settings();
+        if jog.started.elapsed() > settings.timeout {
+            stop(handle)?;
+            *self.active_jog.lock().unwrap() = None;
+            eprintln!(
+                "Jog watchdog: {} ran longer than {} s, sent STOP",
+                jog.command,
+                settings.timeout.as_secs_f64()
+            );
+            return Ok(());
+        }
+
+        if let Some(max_distance) = settings.max_distance {
+            let travelled = (get_pulse_position(handle)? - jog.start_position).unsigned_abs();
+            if travelled > max_distance {
+                stop(handle)?;
+                *self.active_jog.lock().unwrap() = None;
+                eprintln!(
+                    "Jog watchdog: {} travelled {} pulses (max {}), sent STOP",
+                    jog.command, travelled, max_distance
+                );
+            }
+        }
+        Ok(())
+    }
+}
+
+struct ShutdownOnDrop<'a>(&'a JogWatchdog);
+
+impl Drop for ShutdownOnDrop<'_> {
+    fn drop(&mut self) {
+        self.0.shutdown();
+    }
+}
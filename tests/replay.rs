@@ -0,0 +1,197 @@
+// Records a session against a pretend controller, then plays the recording back through the
+// same commands and run preparation and checks it follows the recording exactly.
+
+use rust_mechanical_loader::stage_control::{
+    commands::{
+        close, get_motor_enabled, get_pulse_position, move_stage, open_replay, set_movement_type,
+        turn_motor_off, wait_for_motor_idle,
+    },
+    driver::{discard_stale, write_to_control, Transport},
+    profile::{get_device_profile, set_device_profile, DeviceProfile, NSC_A1},
+    recording::{mark_background_thread, start_recording, stop_recording},
+    run::run_prep_from,
+};
+
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Mutex,
+    },
+    time::Duration,
+};
+
+// The run file the session prepares with, its own so the operator's can change
+const RUN_INPUT: &str = "\
+HighSpeed 12345
+LowSpeed 10
+AccelerationTime 5
+DecelerationTime 5
+IdleTime 2
+LoadCycles 10
+Amplitude 1000
+Offset 0
+Period 3
+DwellTime 0
+";
+
+// Answers like an NSC-A1 whose moves finish instantly: settings are stored and read back, X
+// moves PX and EX, MST is always idle and RW always works
+#[derive(Default)]
+struct PretendController {
+    state: Mutex<ControllerState>,
+    late_responses: AtomicUsize,
+}
+
+#[derive(Default)]
+struct ControllerState {
+    values: HashMap<String, String>,
+    incremental: bool,
+    response: Option<Vec<u8>>, // Waiting to be read
+}
+
+impl ControllerState {
+    fn respond(&mut self, command: &str) -> String {
+        let values = &mut self.values;
+        let value = |values: &HashMap<String, String>, key: &str| {
+            values.get(key).cloned().unwrap_or_else(|| "0".to_string())
+        };
+        match command {
+            "ABS" | "INC" => self.incremental = command == "INC",
+            "MST" => return "0".to_string(),
+            "R4" => return "1".to_string(),
+            _ => (),
+        }
+        if let Some(Ok(target)) = command.strip_prefix('X').map(str::parse::<i32>) {
+            let position: i32 = value(values, "PX").parse().unwrap();
+            let target = match self.incremental {
+                true => position + target,
+                false => target,
+            };
+            values.insert("PX".to_string(), target.to_string());
+            values.insert("EX".to_string(), target.to_string());
+            return "OK".to_string();
+        }
+        match command.split_once('=') {
+            Some((key, new_value)) => {
+                values.insert(key.to_string(), new_value.to_string());
+                "OK".to_string()
+            }
+            None if command.chars().all(|c| c.is_ascii_uppercase()) => value(values, command),
+            None => "OK".to_string(),
+        }
+    }
+}
+
+impl Transport for PretendController {
+    fn write_bulk(&self, _endpoint: u8, buf: &[u8], _timeout: Duration) -> rusb::Result<usize> {
+        let mut state = self.state.lock().unwrap();
+        let command = String::from_utf8_lossy(buf)
+            .trim_end_matches('\0')
+            .to_string();
+        let answer = state.respond(&command);
+        state.response = Some([answer.as_bytes(), b"\0"].concat());
+        Ok(buf.len())
+    }
+
+    fn read_bulk(&self, _endpoint: u8, buf: &mut [u8], _timeout: Duration) -> rusb::Result<usize> {
+        match self.state.lock().unwrap().response.take() {
+            None => Err(rusb::Error::Timeout),
+            Some(response) => {
+                buf[..response.len()].copy_from_slice(&response);
+                Ok(response.len())
+            }
+        }
+    }
+
+    fn write_control(
+        &self,
+        _request_type: u8,
+        _request: u8,
+        _value: u16,
+        _index: u16,
+        _buf: &[u8],
+        _timeout: Duration,
+    ) -> rusb::Result<usize> {
+        Ok(0)
+    }
+
+    fn late_responses(&self) -> usize {
+        self.late_responses.load(Ordering::SeqCst)
+    }
+
+    fn set_late_responses(&self, late: usize) {
+        self.late_responses.store(late, Ordering::SeqCst);
+    }
+}
+
+// Everything after opening, returns what was read along the way
+fn session(handle: &dyn Transport, run_input: &str, background: bool) -> rusb::Result<Vec<String>> {
+    let mut seen = Vec::new();
+    run_prep_from(handle, run_input)?;
+    seen.push(get_pulse_position(handle)?.to_string());
+    // A thread polling alongside, like the jog watchdog. A replay doesn't run it
+    if background {
+        std::thread::scope(|scope| {
+            scope
+                .spawn(|| {
+                    mark_background_thread("test");
+                    get_pulse_position(handle)
+                })
+                .join()
+                .unwrap()
+        })?;
+    }
+    move_stage(handle, 1000)?;
+    wait_for_motor_idle(handle, &mut None)?;
+    seen.push(get_pulse_position(handle)?.to_string());
+    turn_motor_off(handle)?;
+    seen.push(get_motor_enabled(handle)?.to_string());
+    close(handle)?;
+    Ok(seen)
+}
+
+#[test]
+fn replays_a_recorded_run_prep() {
+    let temp_file = |name: &str| {
+        let path = std::env::temp_dir().join(format!("loader_{}_{}.txt", name, std::process::id()));
+        path.to_str().unwrap().to_string()
+    };
+    let (path, run_input) = (temp_file("replay"), temp_file("replay_run_input"));
+    let path = path.as_str();
+    std::fs::write(&run_input, RUN_INPUT).unwrap();
+    // Nothing to wait for on a pretend controller
+    set_device_profile(DeviceProfile {
+        settle_time: Duration::ZERO,
+        ..NSC_A1
+    });
+
+    start_recording(path).unwrap();
+    let controller = PretendController::default();
+    // What commands::open does once the device is found
+    write_to_control(&controller, get_device_profile().open_value).unwrap();
+    discard_stale(&controller).unwrap();
+    set_movement_type(&controller, "abs").unwrap();
+    let recorded = session(&controller, &run_input, true).unwrap();
+    stop_recording();
+
+    let recording = std::fs::read_to_string(path).unwrap();
+    assert!(recording.lines().any(|line| line.starts_with("# test\t")));
+    let high_speed = RUN_INPUT
+        .lines()
+        .find_map(|line| line.strip_prefix("HighSpeed "))
+        .unwrap();
+    assert!(recording.contains(&format!("HSPD={}", high_speed)));
+
+    let replay = open_replay(path).unwrap();
+    let replayed = session(&replay, &run_input, false).unwrap();
+    std::fs::remove_file(path).unwrap();
+    std::fs::remove_file(&run_input).unwrap();
+
+    assert_eq!(recorded, vec!["0", "1000", "false"]);
+    assert_eq!(replayed, recorded);
+    assert_eq!(replay.remaining(), 0);
+    assert_eq!(replay.mismatches(), 0);
+    // Past the end is the controller going away
+    assert_eq!(get_pulse_position(&replay), Err(rusb::Error::NoDevice));
+}